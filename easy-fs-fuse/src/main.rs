@@ -1,12 +1,19 @@
 use easy_fs::{
     BlockDevice,
     EasyFileSystem,
+    FsError,
+    Inode,
+    KEY_SZ,
+    QuotaLimit,
+    fsck,
+    resize,
 };
 use std::fs::{File, OpenOptions, read_dir};
 use std::io::{Read, Write, Seek, SeekFrom};
 use std::sync::Mutex;
 use std::sync::Arc;
-use clap::{Arg, App};
+use std::time::{SystemTime, UNIX_EPOCH};
+use clap::{Arg, App, ArgMatches, SubCommand};
 
 const BLOCK_SZ: usize = 512;
 
@@ -29,10 +36,6 @@ impl BlockDevice for BlockFile {
 }
 
 fn main() {
-    easy_fs_pack().expect("Error when packing easy-fs!");
-}
-
-fn easy_fs_pack() -> std::io::Result<()> {
     let matches = App::new("EasyFileSystem packer")
         .arg(Arg::with_name("source")
             .short("s")
@@ -46,7 +49,269 @@ fn easy_fs_pack() -> std::io::Result<()> {
             .takes_value(true)
             .help("Executable target dir(with backslash)")    
         )
+        .arg(Arg::with_name("link")
+            .short("l")
+            .long("link")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Symbolic link to create in the image, as NAME=TARGET")
+        )
+        .arg(Arg::with_name("xattrs")
+            .short("x")
+            .long("xattrs")
+            .takes_value(true)
+            .help("Manifest of extended attributes, lines of FILE NAME=VALUE")
+        )
+        .arg(Arg::with_name("extents")
+            .short("e")
+            .long("extents")
+            .help("Map the blocks of the apps through extents")
+        )
+        .arg(Arg::with_name("compress")
+            .short("c")
+            .long("compress")
+            .help("Store the apps which are ELF binaries compressed")
+        )
+        .arg(Arg::with_name("key")
+            .short("k")
+            .long("key")
+            .takes_value(true)
+            .requires("encrypt")
+            .help("File holding the 32-byte key to encrypt apps with")
+        )
+        .arg(Arg::with_name("encrypt")
+            .long("encrypt")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .requires("key")
+            .help("App to store encrypted with the key")
+        )
+        .arg(Arg::with_name("home")
+            .long("home")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Directory to create for an account, as NAME=UID; what is created in it belongs to UID")
+        )
+        .subcommand(SubCommand::with_name("fsck")
+            .about("Check an easy-fs image")
+            .arg(Arg::with_name("image")
+                .required(true)
+                .help("Path of the image")
+            )
+            .arg(Arg::with_name("repair")
+                .short("r")
+                .long("repair")
+                .help("Fix the problems which can be fixed")
+            )
+        )
+        .subcommand(SubCommand::with_name("resize")
+            .about("Grow an easy-fs image in place")
+            .arg(Arg::with_name("image")
+                .required(true)
+                .help("Path of the image")
+            )
+            .arg(Arg::with_name("blocks")
+                .required(true)
+                .help("New size of the image in blocks")
+            )
+        )
+        .subcommand(SubCommand::with_name("snapshot")
+            .about("Manage the snapshots of an easy-fs image")
+            .subcommand(SubCommand::with_name("create")
+                .about("Save the current tree as a new snapshot")
+                .arg(Arg::with_name("image").required(true).help("Path of the image"))
+                .arg(Arg::with_name("name").required(true).help("Name of the snapshot"))
+            )
+            .subcommand(SubCommand::with_name("list")
+                .about("List the snapshots")
+                .arg(Arg::with_name("image").required(true).help("Path of the image"))
+            )
+            .subcommand(SubCommand::with_name("restore")
+                .about("Return the tree to a snapshot, which is kept")
+                .arg(Arg::with_name("image").required(true).help("Path of the image"))
+                .arg(Arg::with_name("name").required(true).help("Name of the snapshot"))
+            )
+            .subcommand(SubCommand::with_name("delete")
+                .about("Drop a snapshot and free the blocks only it held")
+                .arg(Arg::with_name("image").required(true).help("Path of the image"))
+                .arg(Arg::with_name("name").required(true).help("Name of the snapshot"))
+            )
+        )
+        .subcommand(SubCommand::with_name("quota")
+            .about("Manage the per-uid quotas of an easy-fs image")
+            .subcommand(SubCommand::with_name("set")
+                .about("Set the limits of a uid, 0 for none")
+                .arg(Arg::with_name("image").required(true).help("Path of the image"))
+                .arg(Arg::with_name("uid").required(true).help("User ID"))
+                .arg(Arg::with_name("block_soft").required(true).help("Soft limit on blocks"))
+                .arg(Arg::with_name("block_hard").required(true).help("Hard limit on blocks"))
+                .arg(Arg::with_name("inode_soft").required(true).help("Soft limit on inodes"))
+                .arg(Arg::with_name("inode_hard").required(true).help("Hard limit on inodes"))
+            )
+            .subcommand(SubCommand::with_name("report")
+                .about("List usage and limits of every uid")
+                .arg(Arg::with_name("image").required(true).help("Path of the image"))
+            )
+        )
         .get_matches();
+    if let Some(matches) = matches.subcommand_matches("fsck") {
+        let code = easy_fs_check(matches).expect("Error when checking easy-fs!");
+        std::process::exit(code);
+    }
+    if let Some(matches) = matches.subcommand_matches("resize") {
+        easy_fs_resize(matches).expect("Error when resizing easy-fs!");
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("snapshot") {
+        easy_fs_snapshot(matches).expect("Error when managing snapshots of easy-fs!");
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("quota") {
+        easy_fs_quota(matches).expect("Error when managing quotas of easy-fs!");
+        return;
+    }
+    easy_fs_pack(&matches).expect("Error when packing easy-fs!");
+}
+
+/// Return the exit status: 0 if the image is clean, 1 if all problems were
+/// fixed and 4 if some are left.
+fn easy_fs_check(matches: &ArgMatches) -> std::io::Result<i32> {
+    let repair = matches.is_present("repair");
+    // writable even without repair, a pending journal transaction is
+    // completed in any case
+    let block_file = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(matches.value_of("image").unwrap())?
+    )));
+    let problems = fsck(block_file, repair);
+    let mut left = 0;
+    for problem in problems.iter() {
+        if repair && problem.repairable() {
+            println!("{} (fixed)", problem);
+        } else {
+            println!("{}", problem);
+            left += 1;
+        }
+    }
+    println!("{} problem(s) found, {} left", problems.len(), left);
+    Ok(if problems.is_empty() {
+        0
+    } else if left == 0 {
+        1
+    } else {
+        4
+    })
+}
+
+fn fs_error(err: FsError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, format!("easy-fs: {:?}", err))
+}
+
+fn easy_fs_resize(matches: &ArgMatches) -> std::io::Result<()> {
+    let blocks: u32 = matches.value_of("blocks").unwrap().parse().map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "blocks must be a number")
+    })?;
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(matches.value_of("image").unwrap())?;
+    // never cut off the end, shrinking is refused below
+    if f.metadata()?.len() < blocks as u64 * BLOCK_SZ as u64 {
+        f.set_len(blocks as u64 * BLOCK_SZ as u64)?;
+    }
+    resize(Arc::new(BlockFile(Mutex::new(f))), blocks).map_err(fs_error)
+}
+
+fn easy_fs_snapshot(matches: &ArgMatches) -> std::io::Result<()> {
+    let (command, matches) = match matches.subcommand() {
+        (command, Some(matches)) => (command, matches),
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "expected create, list, restore or delete",
+            ));
+        }
+    };
+    let block_file = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(matches.value_of("image").unwrap())?
+    )));
+    let efs = EasyFileSystem::open(block_file).map_err(fs_error)?;
+    let mut fs = efs.lock();
+    fs.set_clock(|| {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32
+    });
+    let name = matches.value_of("name").unwrap_or_default();
+    match command {
+        "create" => fs.create_snapshot(name).map_err(fs_error),
+        "restore" => fs.restore_snapshot(name).map_err(fs_error),
+        "delete" => fs.delete_snapshot(name).map_err(fs_error),
+        _ => {
+            for snapshot in fs.snapshots() {
+                println!("{}\t{}", snapshot.name, snapshot.ctime);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn easy_fs_quota(matches: &ArgMatches) -> std::io::Result<()> {
+    let (command, matches) = match matches.subcommand() {
+        (command, Some(matches)) => (command, matches),
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "expected set or report",
+            ));
+        }
+    };
+    let block_file = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(matches.value_of("image").unwrap())?
+    )));
+    let efs = EasyFileSystem::open(block_file).map_err(fs_error)?;
+    let mut fs = efs.lock();
+    fs.set_clock(|| {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32
+    });
+    if command == "set" {
+        let number = |name: &str| -> std::io::Result<u32> {
+            matches.value_of(name).unwrap().parse().map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} must be a number", name))
+            })
+        };
+        let blocks = QuotaLimit { soft: number("block_soft")?, hard: number("block_hard")? };
+        let inodes = QuotaLimit { soft: number("inode_soft")?, hard: number("inode_hard")? };
+        return fs.set_quota(number("uid")?, blocks, inodes).map_err(fs_error);
+    }
+    println!("uid\tblocks\tsoft\thard\tgrace\tinodes\tsoft\thard\tgrace");
+    for quota in fs.quotas() {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            quota.uid,
+            quota.blocks.used,
+            quota.blocks.limit.soft,
+            quota.blocks.limit.hard,
+            quota.blocks.grace,
+            quota.inodes.used,
+            quota.inodes.limit.soft,
+            quota.inodes.limit.hard,
+            quota.inodes.grace,
+        );
+    }
+    Ok(())
+}
+
+fn easy_fs_pack(matches: &ArgMatches) -> std::io::Result<()> {
     let src_path = matches.value_of("source").unwrap();
     let target_path = matches.value_of("target").unwrap();
     println!("src_path = {}\ntarget_path = {}", src_path, target_path);
@@ -64,11 +329,18 @@ fn easy_fs_pack() -> std::io::Result<()> {
         block_file.clone(),
         8192,
         1,
-    );
+    ).map_err(fs_error)?;
+    efs.lock().set_clock(|| {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32
+    });
+    let key_id = match matches.value_of("key") {
+        Some(path) => Some(efs.lock().add_key(&read_key(path)?)),
+        None => None,
+    };
+    let encrypted: Vec<&str> = matches.values_of("encrypt").into_iter().flatten().collect();
     let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
     let apps: Vec<_> = read_dir(src_path)
         .unwrap()
-        .into_iter()
         .map(|dir_entry| {
             let mut name_with_ext = dir_entry.unwrap().file_name().into_string().unwrap();
             name_with_ext.drain(name_with_ext.find('.').unwrap()..name_with_ext.len());
@@ -81,17 +353,147 @@ fn easy_fs_pack() -> std::io::Result<()> {
         let mut all_data: Vec<u8> = Vec::new();
         host_file.read_to_end(&mut all_data).unwrap();
         // create a file in easy-fs
-        let inode = root_inode.create(app.as_str()).unwrap();
+        let inode = root_inode.create(app.as_str()).map_err(fs_error)?;
+        if matches.is_present("extents") {
+            inode.use_extents().map_err(fs_error)?;
+        }
+        // encrypted data does not compress
+        if encrypted.contains(&app.as_str()) {
+            inode.use_encryption(key_id.unwrap(), &rand::random()).map_err(fs_error)?;
+        } else if matches.is_present("compress") && all_data.starts_with(b"\x7fELF") {
+            inode.use_compression().map_err(fs_error)?;
+        }
         // write data to easy-fs
-        inode.write_at(0, all_data.as_slice());
+        inode.write_at(0, all_data.as_slice()).map_err(fs_error)?;
+    }
+    // create symbolic links, e.g. several entry points of one binary
+    for link in matches.values_of("link").into_iter().flatten() {
+        let idx = link.find('=').expect("Symbolic link should be NAME=TARGET!");
+        root_inode.symlink(&link[..idx], &link[idx + 1..]).map_err(fs_error)?;
+    }
+    // home directories of the accounts sharing the image
+    for home in matches.values_of("home").into_iter().flatten() {
+        let idx = home.find('=').expect("Home directory should be NAME=UID!");
+        let uid = home[idx + 1..].parse().expect("Home directory should be NAME=UID!");
+        root_inode.mkdir(&home[..idx]).map_err(fs_error)?.chown(uid, uid).map_err(fs_error)?;
+    }
+    if let Some(manifest) = matches.value_of("xattrs") {
+        set_xattrs(&root_inode, &std::fs::read_to_string(manifest)?)?;
     }
     // list apps
-    for app in root_inode.ls() {
+    for app in root_inode.ls().map_err(fs_error)? {
         println!("{}", app);
     }
     Ok(())
 }
 
+/// Read a key file, which holds the key as it is.
+fn read_key(path: &str) -> std::io::Result<[u8; KEY_SZ]> {
+    let data = std::fs::read(path)?;
+    if data.len() != KEY_SZ {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("key file {:?} should hold {} bytes", path, KEY_SZ),
+        ));
+    }
+    let mut key = [0u8; KEY_SZ];
+    key.copy_from_slice(&data);
+    Ok(key)
+}
+
+/// Set the extended attributes listed in `manifest`, one per line as
+/// `FILE NAME=VALUE`. A value starting with "0x" is given in hex, e.g. for
+/// `security.capability`. Empty lines and those starting with '#' are
+/// skipped.
+fn set_xattrs(root_inode: &Inode, manifest: &str) -> std::io::Result<()> {
+    let invalid = |line: &str| std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("bad xattr manifest line {:?}", line),
+    );
+    for line in manifest.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let space = line.find(char::is_whitespace).ok_or_else(|| invalid(line))?;
+        let (path, xattr) = (&line[..space], line[space..].trim_start());
+        let eq = xattr.find('=').ok_or_else(|| invalid(line))?;
+        let (name, value) = (&xattr[..eq], &xattr[eq + 1..]);
+        let value = match value.strip_prefix("0x") {
+            Some(hex) if hex.len() % 2 == 0 && hex.is_ascii() => (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<Result<Vec<u8>, _>>()
+                .map_err(|_| invalid(line))?,
+            Some(_) => return Err(invalid(line)),
+            None => value.as_bytes().to_vec(),
+        };
+        let inode = root_inode.find_path(path).map_err(fs_error)?;
+        inode.set_xattr(name, &value).map_err(fs_error)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn create_image(path: &str, total_blocks: u32) -> std::io::Result<Arc<BlockFile>> {
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    f.set_len(total_blocks as u64 * BLOCK_SZ as u64)?;
+    Ok(Arc::new(BlockFile(Mutex::new(f))))
+}
+
+/// A device which loses all writes after the first `writes_left`, as if
+/// the power failed.
+#[cfg(test)]
+struct CrashDevice {
+    block_file: BlockFile,
+    writes_left: Mutex<usize>,
+}
+
+#[cfg(test)]
+impl BlockDevice for CrashDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        self.block_file.read_block(block_id, buf);
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        let mut writes_left = self.writes_left.lock().unwrap();
+        if *writes_left > 0 {
+            *writes_left -= 1;
+            self.block_file.write_block(block_id, buf);
+        }
+    }
+}
+
+/// Overwrite bytes of a block of an image of 4096 blocks behind the back
+/// of the file system, updating its checksum so only the edit is seen.
+#[cfg(test)]
+fn patch_block(block_file: &BlockFile, block_id: usize, offset: usize, bytes: &[u8]) {
+    let crc32c = |data: &[u8]| {
+        !data.iter().fold(!0u32, |mut crc, byte| {
+            crc ^= *byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+            }
+            crc
+        })
+    };
+    let mut block = [0u8; BLOCK_SZ];
+    block_file.read_block(block_id, &mut block);
+    block[offset..offset + bytes.len()].copy_from_slice(bytes);
+    block_file.write_block(block_id, &block);
+    // the table fills the last 32 blocks, a checksum for every block
+    let table_block = 4096 - 32 + block_id / (BLOCK_SZ / 4);
+    let mut table = [0u8; BLOCK_SZ];
+    block_file.read_block(table_block, &mut table);
+    let entry = block_id % (BLOCK_SZ / 4) * 4;
+    table[entry..entry + 4].copy_from_slice(&crc32c(&block).to_le_bytes());
+    block_file.write_block(table_block, &table);
+}
+
 #[test]
 fn efs_test() -> std::io::Result<()> {
     let block_file = Arc::new(BlockFile(Mutex::new({
@@ -99,51 +501,52 @@ fn efs_test() -> std::io::Result<()> {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open("target/fs.img")?;
         f.set_len(8192 * 512).unwrap();
         f
     })));
     EasyFileSystem::create(
         block_file.clone(),
-        4096,
+        8192,
         1,
-    );
-    let efs = EasyFileSystem::open(block_file.clone());
+    ).unwrap();
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
     let root_inode = EasyFileSystem::root_inode(&efs);
-    root_inode.create("filea");
-    root_inode.create("fileb");
-    for name in root_inode.ls() {
+    root_inode.create("filea").unwrap();
+    root_inode.create("fileb").unwrap();
+    for name in root_inode.ls().unwrap() {
         println!("{}", name);
     }
     let filea = root_inode.find("filea").unwrap();
     let greet_str = "Hello, world!";
-    filea.write_at(0, greet_str.as_bytes());
+    filea.write_at(0, greet_str.as_bytes()).unwrap();
     //let mut buffer = [0u8; 512];
     let mut buffer = [0u8; 233];
-    let len = filea.read_at(0, &mut buffer);
+    let len = filea.read_at(0, &mut buffer).unwrap();
     assert_eq!(
         greet_str,
         core::str::from_utf8(&buffer[..len]).unwrap(),
     );
 
     let mut random_str_test = |len: usize| {
-        filea.clear();
+        filea.clear().unwrap();
         assert_eq!(
-            filea.read_at(0, &mut buffer),
+            filea.read_at(0, &mut buffer).unwrap(),
             0,
         );
         let mut str = String::new();
         use rand;
         // random digit
         for _ in 0..len {
-            str.push(char::from('0' as u8 + rand::random::<u8>() % 10));
+            str.push(char::from(b'0' + rand::random::<u8>() % 10));
         }
-        filea.write_at(0, str.as_bytes());
+        filea.write_at(0, str.as_bytes()).unwrap();
         let mut read_buffer = [0u8; 127];
         let mut offset = 0usize;
         let mut read_str = String::new();
         loop {
-            let len = filea.read_at(offset, &mut read_buffer);
+            let len = filea.read_at(offset, &mut read_buffer).unwrap();
             if len == 0 {
                 break;
             }
@@ -165,4 +568,1365 @@ fn efs_test() -> std::io::Result<()> {
     random_str_test(2000 * BLOCK_SZ);
 
     Ok(())
-}
\ No newline at end of file
+}
+#[test]
+fn efs_multi_device_test() -> std::io::Result<()> {
+    let open_image = |path: &str| -> std::io::Result<Arc<BlockFile>> {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        f.set_len(4096 * 512).unwrap();
+        Ok(Arc::new(BlockFile(Mutex::new(f))))
+    };
+    // both images are open at the same time and use the same block IDs
+    let efs_a = EasyFileSystem::create(open_image("target/fs_a.img")?, 4096, 1).unwrap();
+    let efs_b = EasyFileSystem::create(open_image("target/fs_b.img")?, 4096, 1).unwrap();
+    let root_a = EasyFileSystem::root_inode(&efs_a);
+    let root_b = EasyFileSystem::root_inode(&efs_b);
+    root_a.create("a").unwrap().write_at(0, b"on image a").unwrap();
+    root_b.create("b").unwrap().write_at(0, b"on image b").unwrap();
+    assert_eq!(root_a.ls().unwrap(), [".", "..", "a"]);
+    assert_eq!(root_b.ls().unwrap(), [".", "..", "b"]);
+    let mut buffer = [0u8; 16];
+    let len = root_a.find("a").unwrap().read_at(0, &mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"on image a");
+    let len = root_b.find("b").unwrap().read_at(0, &mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"on image b");
+    Ok(())
+}
+
+#[test]
+fn efs_unlink_test() -> std::io::Result<()> {
+    let block_file = create_image("target/fs_unlink.img", 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let before = efs.lock().stat();
+    // sizes ending in the direct, indirect1 and indirect2 blocks
+    for (i, &len) in [3 * BLOCK_SZ, 100 * BLOCK_SZ, 300 * BLOCK_SZ].iter().enumerate() {
+        root_inode.create(&format!("file{}", i)).unwrap().write_at(0, &vec![7u8; len]).unwrap();
+    }
+    let dir_size = root_inode.stat().unwrap().size;
+    assert!(efs.lock().stat().free_blocks < before.free_blocks - 400);
+    for i in 0..3 {
+        root_inode.unlink(&format!("file{}", i)).unwrap();
+    }
+    assert_eq!(root_inode.find("file0").err(), Some(FsError::NotFound));
+    assert_eq!(root_inode.unlink("file0").err(), Some(FsError::NotFound));
+    assert_eq!(root_inode.ls().unwrap(), [".", ".."]);
+    let after = efs.lock().stat();
+    assert_eq!((after.free_blocks, after.free_inodes), (before.free_blocks, before.free_inodes));
+    // the freed directory slots are used again
+    for i in 0..3 {
+        root_inode.create(&format!("again{}", i)).unwrap();
+    }
+    assert_eq!(root_inode.stat().unwrap().size, dir_size);
+    drop(root_inode);
+    drop(efs);
+    let problems = fsck(block_file, false);
+    assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[test]
+fn efs_link_test() -> std::io::Result<()> {
+    let block_file = create_image("target/fs_link.img", 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let before = efs.lock().stat();
+    let data: Vec<u8> = (0..40 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    let a = root_inode.create("a").unwrap();
+    a.write_at(0, &data).unwrap();
+    root_inode.link("b", &a).unwrap();
+    assert_eq!(a.stat().unwrap().nlink, 2);
+    assert_eq!(root_inode.find("b").unwrap().inode_id(), a.inode_id());
+    // the data lives on through the second name
+    root_inode.unlink("a").unwrap();
+    assert_eq!(root_inode.find("a").err(), Some(FsError::NotFound));
+    let b = root_inode.find("b").unwrap();
+    assert_eq!(b.stat().unwrap().nlink, 1);
+    let mut buffer = vec![0u8; data.len()];
+    assert_eq!(b.read_at(0, &mut buffer).unwrap(), data.len());
+    assert_eq!(buffer, data);
+    // and goes with the last one
+    root_inode.unlink("b").unwrap();
+    let after = efs.lock().stat();
+    assert_eq!((after.free_blocks, after.free_inodes), (before.free_blocks, before.free_inodes));
+    drop(a);
+    drop(b);
+    drop(root_inode);
+    drop(efs);
+    let problems = fsck(block_file, false);
+    assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[test]
+fn efs_symlink_test() -> std::io::Result<()> {
+    let efs = EasyFileSystem::create(create_image("target/fs_symlink.img", 4096)?, 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let bin = root_inode.mkdir("bin").unwrap();
+    let busybox = bin.create("busybox").unwrap();
+    bin.symlink("ls", "busybox").unwrap();
+    root_inode.symlink("b", "/bin").unwrap();
+    root_inode.symlink("loop1", "loop2").unwrap();
+    root_inode.symlink("loop2", "loop1").unwrap();
+    assert_eq!(root_inode.find_path("b/ls").unwrap().inode_id(), busybox.inode_id());
+    assert!(root_inode.find_path_nofollow("b/ls").unwrap().is_symlink());
+    assert_eq!(root_inode.find_path_nofollow("/bin/ls").unwrap().readlink().unwrap(), "busybox");
+    assert_eq!(root_inode.find_path("loop1").err(), Some(FsError::Loop));
+    // a link longer than the inode holds gets a block, in the same transaction
+    let target = "x/".repeat(200);
+    root_inode.symlink("long", &target).unwrap();
+    assert_eq!(root_inode.find_path_nofollow("long").unwrap().readlink().unwrap(), target);
+    // a failed symlink leaves no entry behind
+    assert_eq!(root_inode.symlink("empty", "").err(), Some(FsError::Invalid));
+    assert_eq!(root_inode.symlink("long", "busybox").err(), Some(FsError::Exists));
+    assert_eq!(root_inode.ls().unwrap(), [".", "..", "bin", "b", "loop1", "loop2", "long"]);
+    drop(efs);
+    let efs = EasyFileSystem::open(create_image("target/fs_symlink.img", 4096)?).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.find_path_nofollow("long").unwrap().readlink().unwrap(), target);
+    Ok(())
+}
+
+#[test]
+fn efs_rename_test() -> std::io::Result<()> {
+    let block_file = create_image("target/fs_rename.img", 8192)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 8192, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let dir = root_inode.mkdir("dir").unwrap();
+    // records of 60 bytes, eight of them fill the first block after "." and ".."
+    let name = |prefix: &str, i: usize| format!("{}-{}-{:03}", prefix, "x".repeat(44), i);
+    for i in 0..8 {
+        dir.create(&name("old", i)).unwrap();
+    }
+    assert_eq!(dir.stat().unwrap().size as usize, BLOCK_SZ);
+    // the first rename turns the directory into an index, moving the old
+    // entry; later ones split leaves
+    for i in 0..200 {
+        if i >= 8 {
+            dir.create(&name("old", i)).unwrap();
+        }
+        let inode_id = dir.find(&name("old", i)).unwrap().inode_id();
+        dir.rename(&name("old", i), &dir, &name("new", i), false).unwrap();
+        assert_eq!(dir.find(&name("old", i)).err(), Some(FsError::NotFound));
+        assert_eq!(dir.find(&name("new", i)).unwrap().inode_id(), inode_id);
+    }
+    assert_eq!(dir.ls().unwrap().len(), 202);
+    for i in 0..200 {
+        assert!(dir.find(&name("new", i)).is_ok());
+    }
+    // replacing, unless told not to
+    let a = dir.create("a").unwrap();
+    dir.create("b").unwrap();
+    assert_eq!(dir.rename("a", &dir, "b", true).err(), Some(FsError::Exists));
+    assert!(dir.find("a").is_ok());
+    dir.rename("a", &root_inode, "c", true).unwrap();
+    dir.rename("b", &root_inode, "c", false).unwrap();
+    assert_eq!(dir.find("a").err(), Some(FsError::NotFound));
+    assert_ne!(root_inode.find("c").unwrap().inode_id(), a.inode_id());
+    // a directory cannot be moved below itself
+    let sub = dir.mkdir("sub").unwrap();
+    assert_eq!(root_inode.rename("dir", &sub, "x", false).err(), Some(FsError::Invalid));
+    dir.rename("sub", &root_inode, "sub", false).unwrap();
+    assert_eq!(sub.find("..").unwrap().inode_id(), root_inode.inode_id());
+    drop(efs);
+    let problems = fsck(block_file, false);
+    assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[test]
+fn efs_truncate_test() -> std::io::Result<()> {
+    let block_file = create_image("target/fs_truncate.img", 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let before = efs.lock().stat();
+    // 28 direct and 128 indirect1 blocks, past that is indirect2
+    let boundary = (28 + 128) * BLOCK_SZ;
+    let data: Vec<u8> = (0..400 * BLOCK_SZ).map(|i| (i % 253) as u8).collect();
+    // blocks taken by a file written to `len` bytes in one go
+    let used_by = |len: usize| {
+        let free_blocks = efs.lock().stat().free_blocks;
+        let file = root_inode.create("reference").unwrap();
+        file.write_at(0, &data[..len]).unwrap();
+        let used = free_blocks - efs.lock().stat().free_blocks;
+        drop(file);
+        root_inode.unlink("reference").unwrap();
+        used
+    };
+    let file = root_inode.create("file").unwrap();
+    let mut buffer = vec![0u8; data.len()];
+    // shrinking to just past, below and onto the boundary keeps only the
+    // blocks a file of that size takes
+    for &len in [boundary + BLOCK_SZ + 100, boundary - 3 * BLOCK_SZ + 7, boundary, 0].iter() {
+        file.truncate(0).unwrap();
+        file.write_at(0, &data).unwrap();
+        file.truncate(len).unwrap();
+        assert_eq!(file.stat().unwrap().size as usize, len);
+        let used = before.free_blocks - efs.lock().stat().free_blocks;
+        assert_eq!(used, used_by(len));
+        assert_eq!(file.read_at(0, &mut buffer).unwrap(), len);
+        assert_eq!(&buffer[..len], &data[..len]);
+        // growing again reads zeros past the old end
+        file.truncate(len + 2 * BLOCK_SZ).unwrap();
+        assert_eq!(file.read_at(len, &mut buffer).unwrap(), 2 * BLOCK_SZ);
+        assert!(buffer[..2 * BLOCK_SZ].iter().all(|&b| b == 0));
+    }
+    file.truncate(0).unwrap();
+    assert_eq!(efs.lock().stat().free_blocks, before.free_blocks);
+    drop(file);
+    drop(root_inode);
+    drop(efs);
+    let problems = fsck(block_file, false);
+    assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[test]
+fn efs_metadata_test() -> std::io::Result<()> {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    static NOW: AtomicU32 = AtomicU32::new(100);
+    fn clock() -> u32 {
+        NOW.load(Ordering::SeqCst)
+    }
+    let block_file = create_image("target/fs_metadata.img", 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    efs.lock().set_clock(clock);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.create("file").unwrap();
+    let stat = file.stat().unwrap();
+    assert_eq!((stat.mode, stat.nlink, stat.atime, stat.mtime, stat.ctime), (0o644, 1, 100, 100, 100));
+    NOW.store(200, Ordering::SeqCst);
+    file.write_at(0, b"abc").unwrap();
+    let stat = file.stat().unwrap();
+    assert_eq!((stat.size, stat.atime, stat.mtime), (3, 100, 200));
+    // the first read after a change updates the access time, later ones
+    // only once a day
+    let mut buffer = [0u8; 3];
+    NOW.store(300, Ordering::SeqCst);
+    file.read_at(0, &mut buffer).unwrap();
+    assert_eq!(file.stat().unwrap().atime, 300);
+    NOW.store(400, Ordering::SeqCst);
+    file.read_at(0, &mut buffer).unwrap();
+    assert_eq!(file.stat().unwrap().atime, 300);
+    NOW.store(300 + 24 * 60 * 60, Ordering::SeqCst);
+    file.read_at(0, &mut buffer).unwrap();
+    assert_eq!(file.stat().unwrap().atime, 300 + 24 * 60 * 60);
+    file.chmod(0o100600).unwrap();
+    file.chown(5, 6).unwrap();
+    let stat = file.stat().unwrap();
+    assert_eq!((stat.mode, stat.uid, stat.gid, stat.ctime), (0o600, 5, 6, 300 + 24 * 60 * 60));
+    let dir = root_inode.mkdir("dir").unwrap();
+    assert_eq!(dir.stat().unwrap().mode, 0o755);
+    assert_eq!((root_inode.stat().unwrap().nlink, dir.stat().unwrap().nlink), (3, 2));
+    drop(efs);
+    let efs = EasyFileSystem::open(block_file).unwrap();
+    let stat = EasyFileSystem::root_inode(&efs).find("file").unwrap().stat().unwrap();
+    assert_eq!((stat.uid, stat.atime), (5, 300 + 24 * 60 * 60));
+    Ok(())
+}
+
+#[test]
+fn efs_journal_test() -> std::io::Result<()> {
+    let base = "target/fs_journal_base.img";
+    let path = "target/fs_journal.img";
+    let data: Vec<u8> = (0..100 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    {
+        let efs = EasyFileSystem::create(create_image(base, 4096)?, 4096, 1).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        root_inode.create("big").unwrap().write_at(0, &data).unwrap();
+        root_inode.mkdir("dir").unwrap();
+    }
+    // unlinking frees the file over many transactions, a crash at any
+    // write leaves it either whole or gone once the image is opened again
+    let mut crash_at = 0;
+    loop {
+        std::fs::copy(base, path)?;
+        let device = Arc::new(CrashDevice {
+            block_file: BlockFile(Mutex::new(OpenOptions::new().read(true).write(true).open(path)?)),
+            writes_left: Mutex::new(crash_at),
+        });
+        {
+            let efs = EasyFileSystem::open(device.clone()).unwrap();
+            EasyFileSystem::root_inode(&efs).unlink("big").unwrap();
+        }
+        let finished = *device.writes_left.lock().unwrap() > 0;
+        let problems = fsck(create_image(path, 4096)?, false);
+        assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+        let efs = EasyFileSystem::open(create_image(path, 4096)?).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        match root_inode.find("big") {
+            Ok(big) => {
+                let mut buffer = vec![0u8; data.len()];
+                assert_eq!(big.read_at(0, &mut buffer).unwrap(), data.len());
+                assert!(buffer == data);
+            }
+            Err(err) => {
+                assert_eq!(err, FsError::NotFound);
+                // no block of the file is left allocated
+                let stat = efs.lock().stat();
+                assert_eq!(stat.blocks - stat.free_blocks, 2);
+            }
+        }
+        if finished {
+            break;
+        }
+        crash_at += 1;
+    }
+    // a failed operation leaves nothing behind, even if it had allocated
+    let efs = EasyFileSystem::open(create_image(path, 4096)?).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filler = root_inode.create("filler").unwrap();
+    while filler.write_at(filler.stat().unwrap().size as usize, &[1u8; BLOCK_SZ]).is_ok() {}
+    let stat = efs.lock().stat();
+    assert_eq!(root_inode.mkdir("full").err(), Some(FsError::NoSpace));
+    assert_eq!(efs.lock().stat(), stat);
+    drop(efs);
+    let problems = fsck(create_image(path, 4096)?, false);
+    assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[test]
+fn efs_fsck_test() -> std::io::Result<()> {
+    use easy_fs::Problem;
+    let block_file = create_image("target/fs_fsck.img", 4096)?;
+    {
+        let efs = EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        root_inode.create("a").unwrap().write_at(0, &[7u8; 1000]).unwrap();
+        let dir = root_inode.mkdir("d").unwrap();
+        dir.create("x").unwrap().write_at(0, &[1u8; 20000]).unwrap();
+    }
+    assert!(fsck(block_file, false).is_empty());
+    // a new handle, which has nothing in the block cache
+    let block_file = create_image("target/fs_fsck.img", 4096)?;
+    // inode bitmap in block 33: free inode 1, which is "a"
+    patch_block(&block_file, 33, 0, &[0b1101]);
+    // data bitmap in block 2082, the data area starts at 2083: allocate
+    // block 3083
+    patch_block(&block_file, 2082, 125, &[1]);
+    // inode 0 at the start of block 34: the link count of the root
+    patch_block(&block_file, 34, 126, &7u16.to_le_bytes());
+    let problems = fsck(block_file.clone(), true);
+    let found = |wanted: &dyn Fn(&Problem) -> bool| problems.iter().any(wanted);
+    assert!(found(&|problem| matches!(
+        problem,
+        Problem::DanglingEntry { dir: 0, name, inode_id: 1 } if name == "a"
+    )));
+    assert!(found(&|problem| matches!(
+        problem,
+        Problem::WrongLinkCount { inode_id: 0, nlink: 7, found: 3 }
+    )));
+    assert!(found(&|problem| matches!(problem, Problem::LeakedBlock(3083))));
+    assert!(problems.iter().all(|problem| problem.repairable()));
+    let problems = fsck(block_file.clone(), false);
+    assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    let efs = EasyFileSystem::open(block_file).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.ls().unwrap(), [".", "..", "d"]);
+    assert_eq!(root_inode.stat().unwrap().nlink, 3);
+    Ok(())
+}
+
+#[test]
+fn efs_cache_test() -> std::io::Result<()> {
+    use easy_fs::{block_cache_sync_all, set_block_cache_capacity};
+    let block_file = create_image("target/fs_cache.img", 8192)?;
+    // far fewer blocks than a write of a large file holds at a time
+    set_block_cache_capacity(3);
+    let efs = EasyFileSystem::create(block_file.clone(), 8192, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let dir = root_inode.mkdir("dir").unwrap();
+    let data: Vec<u8> = (0..1000 * BLOCK_SZ).map(|i| (i * 7 % 256) as u8).collect();
+    dir.create("file").unwrap().write_at(0, &data).unwrap();
+    root_inode.rename("dir", &root_inode, "moved", false).unwrap();
+    set_block_cache_capacity(1000);
+    let mut buffer = vec![0u8; data.len()];
+    let file = root_inode.find_path("/moved/file").unwrap();
+    assert_eq!(file.read_at(0, &mut buffer).unwrap(), data.len());
+    assert!(buffer == data);
+    // once synced, the image is complete while still mounted
+    file.write_at(0, b"synced").unwrap();
+    block_cache_sync_all();
+    let problems = fsck(create_image("target/fs_cache.img", 8192)?, false);
+    assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    let efs = EasyFileSystem::open(create_image("target/fs_cache.img", 8192)?).unwrap();
+    let file = EasyFileSystem::root_inode(&efs).find_path("/moved/file").unwrap();
+    assert_eq!(file.read_at(0, &mut buffer[..6]).unwrap(), 6);
+    assert_eq!(&buffer[..6], b"synced");
+    Ok(())
+}
+
+#[test]
+fn efs_errors_test() -> std::io::Result<()> {
+    let block_file = create_image("target/fs_errors.img", 4096)?;
+    assert_eq!(EasyFileSystem::create(block_file.clone(), 100, 1).err(), Some(FsError::NoSpace));
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.create("a/b").err(), Some(FsError::Invalid));
+    assert_eq!(root_inode.create("").err(), Some(FsError::Invalid));
+    assert_eq!(root_inode.create(&"x".repeat(256)).err(), Some(FsError::NameTooLong));
+    let file = root_inode.create("file").unwrap();
+    assert_eq!(root_inode.create("file").err(), Some(FsError::Exists));
+    assert_eq!(file.create("x").err(), Some(FsError::NotDir));
+    assert_eq!(root_inode.find("none").err(), Some(FsError::NotFound));
+    assert_eq!(root_inode.find_path("file/x").err(), Some(FsError::NotDir));
+    assert_eq!(root_inode.rmdir("file"), Err(FsError::NotDir));
+    let dir = root_inode.mkdir("dir").unwrap();
+    dir.create("x").unwrap();
+    assert_eq!(root_inode.unlink("dir"), Err(FsError::IsDir));
+    assert_eq!(root_inode.rmdir("dir"), Err(FsError::NotEmpty));
+    assert_eq!(root_inode.link("dir2", &dir), Err(FsError::IsDir));
+    assert_eq!(root_inode.unlink(".."), Err(FsError::Invalid));
+    assert_eq!(file.readlink().err(), Some(FsError::Invalid));
+    // filling the disk ends in a short write, then NoSpace
+    let data = vec![3u8; 4096 * BLOCK_SZ];
+    let len = file.write_at(0, &data).unwrap();
+    assert!(len > 0 && len < data.len());
+    assert_eq!(file.stat().unwrap().size as usize, len);
+    let mut offset = len;
+    let err = loop {
+        match file.write_at(offset, &data[..BLOCK_SZ]) {
+            Ok(len) => offset += len,
+            Err(err) => break err,
+        }
+    };
+    assert_eq!(err, FsError::NoSpace);
+    let mut i = 0;
+    let err = loop {
+        match dir.create(&format!("y{}", i)) {
+            Ok(_) => i += 1,
+            Err(err) => break err,
+        }
+    };
+    assert_eq!(err, FsError::NoSpace);
+    assert_eq!(root_inode.symlink("s", &"t".repeat(5000)).err(), Some(FsError::NoSpace));
+    assert_eq!(root_inode.find("s").err(), Some(FsError::NotFound));
+    // nothing was leaked, and the space can be used again
+    file.truncate(0).unwrap();
+    root_inode.symlink("s", &"t".repeat(5000)).unwrap();
+    root_inode.mkdir("damaged").unwrap().create("victim").unwrap();
+    drop(efs);
+    let problems = fsck(block_file.clone(), false);
+    assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    // a record leaving too little room behind it for the next one is
+    // damage, even where the checksum matches
+    let mut block = [0u8; BLOCK_SZ];
+    let block_id = (33..4096)
+        .find(|block_id| {
+            block_file.read_block(*block_id, &mut block);
+            block.windows(6).any(|window| window == b"victim")
+        })
+        .unwrap();
+    patch_block(&block_file, block_id, 4, &((BLOCK_SZ - 4) as u16).to_le_bytes());
+    let efs = EasyFileSystem::open(create_image("target/fs_errors.img", 4096)?).unwrap();
+    let damaged = EasyFileSystem::root_inode(&efs).find("damaged").unwrap();
+    assert_eq!(damaged.find("victim").err(), Some(FsError::Corrupt));
+    assert_eq!(damaged.ls().err(), Some(FsError::Corrupt));
+    Ok(())
+}
+
+#[test]
+fn efs_long_name_test() -> std::io::Result<()> {
+    let block_file = create_image("target/fs_long_name.img", 8192)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 8192, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let dir = root_inode.mkdir("a_directory_with_a_rather_long_name").unwrap();
+    let names: Vec<String> = (0..200)
+        .map(|i| format!("{}_{}", i, "n".repeat(1 + i * 37 % 250)))
+        .collect();
+    for name in names.iter() {
+        dir.create(name).unwrap();
+    }
+    dir.create(&"x".repeat(255)).unwrap();
+    for name in names.iter().step_by(3) {
+        dir.unlink(name).unwrap();
+    }
+    for (i, name) in names.iter().enumerate() {
+        assert_eq!(dir.find(name).is_ok(), i % 3 != 0);
+    }
+    for name in names.iter().step_by(3) {
+        dir.create(&name.replace('n', "m")).unwrap();
+    }
+    assert_eq!(dir.ls().unwrap().len(), 2 + 200 + 1);
+    let sub = dir.mkdir(&"s".repeat(200)).unwrap();
+    root_inode.rename("a_directory_with_a_rather_long_name", &root_inode, "short", false).unwrap();
+    let path = format!("/short/{}/..", "s".repeat(200));
+    assert_eq!(root_inode.find_path(&path).unwrap().inode_id(), dir.inode_id());
+    drop(sub);
+    drop(dir);
+    drop(root_inode);
+    drop(efs);
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert!(root_inode.find_path(&format!("short/{}", names[1])).is_ok());
+    assert!(root_inode.find_path(&format!("short/{}", "x".repeat(255))).is_ok());
+    drop(root_inode);
+    drop(efs);
+    let problems = fsck(block_file, false);
+    assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[test]
+fn efs_old_format_test() -> std::io::Result<()> {
+    let path = "target/fs_old_format.img";
+    let block_file = create_image(path, 8192)?;
+    EasyFileSystem::create(block_file.clone(), 8192, 1).unwrap();
+    // turn it into an image from before the feature flags: no flags, and a
+    // root of fixed 32 byte entries
+    let mut block = [0u8; BLOCK_SZ];
+    block_file.read_block(0, &mut block);
+    block[32..36].copy_from_slice(&0u32.to_le_bytes());
+    block_file.write_block(0, &block);
+    block_file.read_block(34, &mut block);
+    let data_block = u32::from_le_bytes([block[4], block[5], block[6], block[7]]);
+    block[..4].copy_from_slice(&64u32.to_le_bytes());
+    block_file.write_block(34, &block);
+    let mut block = [0u8; BLOCK_SZ];
+    block[0] = b'.';
+    block[32..34].copy_from_slice(b"..");
+    block_file.write_block(data_block as usize, &block);
+    // a new handle, which has nothing in the block cache
+    let block_file = create_image(path, 8192)?;
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.ls().unwrap(), [".", ".."]);
+    assert_eq!(root_inode.create(&"x".repeat(28)).err(), Some(FsError::NameTooLong));
+    let dir = root_inode.mkdir(&"d".repeat(27)).unwrap();
+    for i in 0..40 {
+        dir.create(&format!("f{}", i)).unwrap();
+    }
+    for i in (0..40).step_by(2) {
+        dir.unlink(&format!("f{}", i)).unwrap();
+    }
+    dir.create("again").unwrap();
+    assert_eq!(dir.stat().unwrap().size, 42 * 32);
+    root_inode.rename(&"d".repeat(27), &root_inode, "d", false).unwrap();
+    assert_eq!(root_inode.find_path("/d/again/..").err(), Some(FsError::NotDir));
+    assert_eq!(root_inode.find_path("/d/..").unwrap().inode_id(), root_inode.inode_id());
+    drop(dir);
+    drop(root_inode);
+    drop(efs);
+    let problems = fsck(block_file, false);
+    assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[test]
+fn efs_dir_index_test() -> std::io::Result<()> {
+    let block_file = create_image("target/fs_dir_index.img", 16384)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 16384, 2).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let dir = root_inode.mkdir("big").unwrap();
+    let name = |i: usize| if i % 7 == 0 {
+        format!("{}-{}", "x".repeat(200), i)
+    } else {
+        format!("file{}", i)
+    };
+    for i in 0..5000 {
+        dir.create(&name(i)).unwrap();
+    }
+    assert_eq!(dir.ls().unwrap().len(), 5000 + 2);
+    assert_eq!(dir.create(&name(1)).err(), Some(FsError::Exists));
+    for i in (0..5000).step_by(3) {
+        dir.unlink(&name(i)).unwrap();
+    }
+    // renames within the index and out of it
+    for i in (1..5000).step_by(3) {
+        let inode_id = dir.find(&name(i)).unwrap().inode_id();
+        if i % 2 == 0 {
+            dir.rename(&name(i), &dir, &format!("renamed{}", i), false).unwrap();
+            assert_eq!(dir.find(&format!("renamed{}", i)).unwrap().inode_id(), inode_id);
+        } else {
+            dir.rename(&name(i), &root_inode, &format!("moved{}", i), false).unwrap();
+            assert_eq!(root_inode.find(&format!("moved{}", i)).unwrap().inode_id(), inode_id);
+        }
+    }
+    for i in 0..5000 {
+        assert_eq!(dir.find(&name(i)).is_ok(), i % 3 == 2, "{}", name(i));
+    }
+    drop(dir);
+    drop(root_inode);
+    drop(efs);
+    let problems = fsck(block_file.clone(), false);
+    assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    let efs = EasyFileSystem::open(block_file).unwrap();
+    let dir = EasyFileSystem::root_inode(&efs).find("big").unwrap();
+    assert!(dir.find(&name(2)).is_ok());
+    assert!(dir.find("renamed4").is_ok());
+    assert_eq!(dir.find("file0").err(), Some(FsError::NotFound));
+    Ok(())
+}
+
+#[test]
+fn efs_sparse_test() -> std::io::Result<()> {
+    let block_file = create_image("target/fs_sparse.img", 8192)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 8192, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    for &extents in [false, true].iter() {
+        let file = root_inode.create("file").unwrap();
+        if extents {
+            file.use_extents().unwrap();
+        } else {
+            assert_eq!(file.truncate(20000 * BLOCK_SZ), Err(FsError::TooLarge));
+            assert_eq!(file.write_at(20000 * BLOCK_SZ, b"x"), Err(FsError::TooLarge));
+        }
+        // far beyond the capacity of the image, and all of it a hole
+        let size = 16000 * BLOCK_SZ;
+        file.truncate(size).unwrap();
+        assert_eq!(file.stat().unwrap().size as usize, size);
+        assert_eq!(file.seek_data(0), Err(FsError::OutOfRange));
+        assert_eq!(file.seek_hole(5), Ok(5));
+        let mut buffer = [7u8; 1000];
+        assert_eq!(file.read_at(size - 1000, &mut buffer).unwrap(), 1000);
+        assert!(buffer.iter().all(|byte| *byte == 0));
+        file.write_at(10 * BLOCK_SZ + 3, b"hello").unwrap();
+        file.write_at(300 * BLOCK_SZ, &[9u8; 2 * BLOCK_SZ]).unwrap();
+        file.write_at(12000 * BLOCK_SZ, b"far").unwrap();
+        assert_eq!(file.seek_data(0), Ok(10 * BLOCK_SZ));
+        assert_eq!(file.seek_data(10 * BLOCK_SZ + 100), Ok(10 * BLOCK_SZ + 100));
+        assert_eq!(file.seek_hole(10 * BLOCK_SZ), Ok(11 * BLOCK_SZ));
+        assert_eq!(file.seek_data(11 * BLOCK_SZ), Ok(300 * BLOCK_SZ));
+        assert_eq!(file.seek_hole(300 * BLOCK_SZ), Ok(302 * BLOCK_SZ));
+        assert_eq!(file.seek_data(302 * BLOCK_SZ), Ok(12000 * BLOCK_SZ));
+        assert_eq!(file.seek_data(12001 * BLOCK_SZ), Err(FsError::OutOfRange));
+        assert_eq!(file.seek_hole(size - 1), Ok(size - 1));
+        assert_eq!(file.seek_hole(size), Err(FsError::OutOfRange));
+        let mut buffer = [0u8; 8];
+        file.read_at(10 * BLOCK_SZ + 1, &mut buffer).unwrap();
+        assert_eq!(&buffer, b"\0\0hello\0");
+        // shrinking into written data and growing again leaves zeros
+        file.truncate(300 * BLOCK_SZ + 10).unwrap();
+        file.truncate(15000 * BLOCK_SZ).unwrap();
+        let mut buffer = [1u8; 20];
+        file.read_at(300 * BLOCK_SZ, &mut buffer).unwrap();
+        assert!(buffer[..10].iter().all(|byte| *byte == 9));
+        assert!(buffer[10..].iter().all(|byte| *byte == 0));
+        file.read_at(12000 * BLOCK_SZ, &mut buffer[..3]).unwrap();
+        assert_eq!(&buffer[..3], b"\0\0\0");
+        assert_eq!(file.seek_data(301 * BLOCK_SZ), Err(FsError::OutOfRange));
+        root_inode.unlink("file").unwrap();
+    }
+    // all that was written is free again, only the root holds a block
+    let stat = efs.lock().stat();
+    assert_eq!(stat.blocks - stat.free_blocks, 1);
+    drop(root_inode);
+    drop(efs);
+    let problems = fsck(block_file, false);
+    assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[test]
+fn efs_inline_test() -> std::io::Result<()> {
+    let block_file = create_image("target/fs_inline.img", 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let dir = root_inode.mkdir("dir").unwrap();
+    for i in 0..50 {
+        dir.create(&format!("f{}", i)).unwrap();
+    }
+    let free_blocks = efs.lock().stat().free_blocks;
+    // use up all data blocks
+    let filler = root_inode.create("filler").unwrap();
+    let mut offset = 0;
+    let err = loop {
+        match filler.write_at(offset, &[5u8; BLOCK_SZ]) {
+            Ok(len) => offset += len,
+            Err(err) => break err,
+        }
+    };
+    assert_eq!(err, FsError::NoSpace);
+    // small files and symlinks live in the inode and need no block
+    for i in 0..50 {
+        let file = dir.find(&format!("f{}", i)).unwrap();
+        assert_eq!(file.write_at(0, &[i as u8; 112]).unwrap(), 112);
+    }
+    root_inode.symlink("link", "dir/f7").unwrap();
+    let file = root_inode.find_path("link").unwrap();
+    let mut buffer = [0u8; 200];
+    assert_eq!(file.read_at(0, &mut buffer).unwrap(), 112);
+    assert!(buffer[..112].iter().all(|byte| *byte == 7));
+    // growing past the inode needs a block, the data stays as it was
+    assert_eq!(file.write_at(112, b"x"), Err(FsError::NoSpace));
+    assert_eq!(file.stat().unwrap().size, 112);
+    file.truncate(50).unwrap();
+    file.truncate(100).unwrap();
+    assert_eq!(file.read_at(0, &mut buffer).unwrap(), 100);
+    assert!(buffer[..50].iter().all(|byte| *byte == 7));
+    assert!(buffer[50..100].iter().all(|byte| *byte == 0));
+    // with space again, the data moves to a block
+    filler.truncate(0).unwrap();
+    file.write_at(100, &[3u8; 1000]).unwrap();
+    assert_eq!(file.read_at(0, &mut buffer).unwrap(), 200);
+    assert!(buffer[..50].iter().all(|byte| *byte == 7));
+    assert!(buffer[50..100].iter().all(|byte| *byte == 0));
+    assert!(buffer[100..].iter().all(|byte| *byte == 3));
+    // and back into the inode once it is cleared
+    file.clear().unwrap();
+    file.write_at(0, b"again").unwrap();
+    assert_eq!(efs.lock().stat().free_blocks, free_blocks);
+    drop(file);
+    drop(filler);
+    drop(dir);
+    drop(root_inode);
+    drop(efs);
+    let problems = fsck(block_file.clone(), false);
+    assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    let efs = EasyFileSystem::open(block_file).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.find_path("link").unwrap();
+    assert_eq!(file.read_at(0, &mut buffer).unwrap(), 5);
+    assert_eq!(&buffer[..5], b"again");
+    assert_eq!(root_inode.find_path("dir/f9").unwrap().read_at(0, &mut buffer).unwrap(), 112);
+    assert!(buffer[..112].iter().all(|byte| *byte == 9));
+    Ok(())
+}
+
+#[test]
+fn efs_statfs_test() -> std::io::Result<()> {
+    let block_file = create_image("target/fs_statfs.img", 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let stat = efs.lock().stat();
+    // what is left of 4096 blocks after the super block, the journal, the
+    // bitmaps, the inodes and the checksums
+    assert_eq!((stat.block_size, stat.blocks, stat.inodes), (512, 1981, 4096));
+    // the root takes an inode and a block
+    assert_eq!((stat.free_blocks, stat.free_inodes), (1980, 4095));
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode.create("file").unwrap().write_at(0, &[1u8; 10 * BLOCK_SZ]).unwrap();
+    let written = efs.lock().stat();
+    assert_eq!((written.free_blocks, written.free_inodes), (1970, 4094));
+    drop(root_inode);
+    drop(efs);
+    // and the same once mounted again
+    let efs = EasyFileSystem::open(block_file).unwrap();
+    assert_eq!(efs.lock().stat(), written);
+    EasyFileSystem::root_inode(&efs).unlink("file").unwrap();
+    assert_eq!(efs.lock().stat(), stat);
+    Ok(())
+}
+
+#[test]
+fn efs_resize_test() -> std::io::Result<()> {
+    let block_file = create_image("target/fs_resize.img", 4000)?;
+    let data: Vec<u8> = (0..600 * BLOCK_SZ).map(|i| (i % 253) as u8).collect();
+    {
+        let efs = EasyFileSystem::create(block_file.clone(), 4000, 1).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let file = root_inode.create("file").unwrap();
+        file.write_at(0, &data).unwrap();
+        file.write_at(900 * BLOCK_SZ, b"tail").unwrap();
+        let extents = root_inode.create("extents").unwrap();
+        extents.use_extents().unwrap();
+        for i in 0..100 {
+            // interleaved, so that the extents need leaves
+            extents.write_at(i * 2 * BLOCK_SZ, &data[i * BLOCK_SZ..(i + 1) * BLOCK_SZ]).unwrap();
+            file.write_at(700 * BLOCK_SZ + i * BLOCK_SZ, b"x").unwrap();
+        }
+        let dir = root_inode.mkdir("dir").unwrap();
+        for i in 0..200 {
+            dir.create(&format!("file{}", i)).unwrap().write_at(0, &data[..300]).unwrap();
+        }
+    }
+    let check = |block_file: Arc<BlockFile>| {
+        let problems = fsck(block_file.clone(), false);
+        assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+        let efs = EasyFileSystem::open(block_file).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let mut buffer = vec![0u8; data.len()];
+        let file = root_inode.find("file").unwrap();
+        assert_eq!(file.read_at(0, &mut buffer).unwrap(), data.len());
+        assert!(buffer == data);
+        file.read_at(900 * BLOCK_SZ, &mut buffer[..4]).unwrap();
+        assert_eq!(&buffer[..4], b"tail");
+        let extents = root_inode.find("extents").unwrap();
+        for i in 0..100 {
+            extents.read_at(i * 2 * BLOCK_SZ, &mut buffer[..BLOCK_SZ]).unwrap();
+            assert!(buffer[..BLOCK_SZ] == data[i * BLOCK_SZ..(i + 1) * BLOCK_SZ]);
+        }
+        let file = root_inode.find_path("dir/file123").unwrap();
+        assert_eq!(file.read_at(0, &mut buffer).unwrap(), 300);
+        let stat = efs.lock().stat();
+        stat
+    };
+    let before = check(block_file.clone());
+    // within the last bitmap block, nothing moves
+    block_file.0.lock().unwrap().set_len(4500 * BLOCK_SZ as u64)?;
+    resize(block_file.clone(), 4500).unwrap();
+    let stat = check(block_file.clone());
+    // less the blocks the checksum table grew by
+    assert_eq!(stat.blocks, before.blocks + 496);
+    assert_eq!(stat.free_blocks, before.free_blocks + 496);
+    // needs another bitmap block
+    block_file.0.lock().unwrap().set_len(12000 * BLOCK_SZ as u64)?;
+    resize(block_file.clone(), 12000).unwrap();
+    let grown = check(block_file.clone());
+    assert_eq!(grown.blocks - grown.free_blocks, stat.blocks - stat.free_blocks);
+    assert!(grown.blocks > 8000);
+    assert_eq!(resize(block_file.clone(), 11000), Err(FsError::Invalid));
+    // the new space can be used
+    {
+        let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+        let file = EasyFileSystem::root_inode(&efs).create("big").unwrap();
+        assert_eq!(file.write_at(0, &vec![1u8; 8000 * BLOCK_SZ]).unwrap(), 8000 * BLOCK_SZ);
+    }
+    check(block_file);
+    Ok(())
+}
+
+#[test]
+fn efs_checksum_test() -> std::io::Result<()> {
+    let path = "target/fs_checksum.img";
+    let block_file = create_image(path, 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let dir = root_inode.mkdir("dir").unwrap();
+    dir.create("needle").unwrap().write_at(0, &[5u8; 3000]).unwrap();
+    root_inode.create("file").unwrap().write_at(0, &[9u8; 60 * BLOCK_SZ]).unwrap();
+    let free_blocks = efs.lock().stat().free_blocks;
+    drop(dir);
+    drop(root_inode);
+    drop(efs);
+    // flip a byte of the name in the directory, behind the checksum's back;
+    // every new handle below starts with nothing in the block cache
+    let block_file = create_image(path, 4096)?;
+    let mut block = [0u8; BLOCK_SZ];
+    let (block_id, offset) = (0..4096)
+        .find_map(|block_id| {
+            block_file.read_block(block_id, &mut block);
+            block.windows(6).position(|name| name == b"needle").map(|offset| (block_id, offset))
+        })
+        .unwrap();
+    block[offset] = b'N';
+    block_file.write_block(block_id, &block);
+    let problems = fsck(block_file.clone(), false);
+    assert_eq!(problems.len(), 1);
+    assert!(matches!(problems[0], easy_fs::Problem::BadChecksum(id) if id as usize == block_id));
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let dir = root_inode.find("dir").unwrap();
+    assert_eq!(dir.find("needle").err(), Some(FsError::Corrupt));
+    assert_eq!(dir.ls().err(), Some(FsError::Corrupt));
+    assert_eq!(dir.create("other").err(), Some(FsError::Corrupt));
+    // moving a directory reads its ".."
+    assert_eq!(root_inode.rename("dir", &root_inode, "moved", false).err(), Some(FsError::Corrupt));
+    assert!(root_inode.find("dir").is_ok());
+    // what is not damaged keeps working
+    let file = root_inode.find("file").unwrap();
+    let mut buffer = vec![0u8; 60 * BLOCK_SZ];
+    assert_eq!(file.read_at(0, &mut buffer).unwrap(), buffer.len());
+    assert!(buffer.iter().all(|byte| *byte == 9));
+    file.write_at(100, b"hello").unwrap();
+    assert_eq!(efs.lock().stat().free_blocks, free_blocks);
+    // an inode type none of the known ones, inode 3 is "file": the second
+    // inode of block 35, its type after size and block pointers
+    let inode_id = file.inode_id();
+    drop(file);
+    drop(dir);
+    drop(root_inode);
+    drop(efs);
+    assert_eq!(inode_id, 3);
+    let block_file = create_image(path, 4096)?;
+    patch_block(&block_file, 35, 256 + 124, &[7]);
+    let efs = EasyFileSystem::open(block_file).unwrap();
+    let file = EasyFileSystem::root_inode(&efs).find("file").unwrap();
+    assert_eq!(file.stat().err(), Some(FsError::Corrupt));
+    assert!(!file.is_file() && !file.is_dir() && !file.is_symlink());
+    drop(file);
+    drop(efs);
+    // a damaged super block refuses the mount
+    let block_file = create_image(path, 4096)?;
+    block_file.read_block(0, &mut block);
+    block[40] ^= 0xff;
+    block_file.write_block(0, &block);
+    assert_eq!(EasyFileSystem::open(create_image(path, 4096)?).err(), Some(FsError::Corrupt));
+    Ok(())
+}
+
+#[test]
+fn efs_snapshot_test() -> std::io::Result<()> {
+    let base = "target/fs_snapshot_base.img";
+    let path = "target/fs_snapshot.img";
+    let data: Vec<u8> = (0..300 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    let free_blocks = {
+        let efs = EasyFileSystem::create(create_image(base, 4096)?, 4096, 1).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let big = root_inode.create("big").unwrap();
+        big.write_at(0, &data).unwrap();
+        let extents = root_inode.create("extents").unwrap();
+        extents.use_extents().unwrap();
+        extents.write_at(0, &data).unwrap();
+        efs.lock().create_snapshot("old").unwrap();
+        assert_eq!(efs.lock().create_snapshot("old").err(), Some(FsError::Exists));
+        // a write copies the blocks it changes rather than the whole file:
+        // three direct blocks, then one behind indirect2 and the indirect1
+        // on the way, then one of the extents
+        let free_blocks = efs.lock().stat().free_blocks;
+        big.write_at(10 * BLOCK_SZ + 100, &[1u8; 2 * BLOCK_SZ]).unwrap();
+        big.write_at(290 * BLOCK_SZ, &[1u8; BLOCK_SZ]).unwrap();
+        extents.write_at(150 * BLOCK_SZ, &[1u8; BLOCK_SZ]).unwrap();
+        assert_eq!(free_blocks - efs.lock().stat().free_blocks, 7);
+        extents.truncate(100 * BLOCK_SZ + 7).unwrap();
+        root_inode.unlink("big").unwrap();
+        // enough new inodes that restoring takes several transactions
+        for i in 0..60 {
+            root_inode.create(&format!("new{}", i)).unwrap().write_at(0, &data[..1000]).unwrap();
+        }
+        free_blocks
+    };
+    let check = |root_inode: &Inode| {
+        let mut names = root_inode.ls().unwrap();
+        names.sort();
+        assert_eq!(names, [".", "..", "big", "extents"]);
+        let mut buffer = vec![0u8; data.len()];
+        for name in ["big", "extents"].iter() {
+            let file = root_inode.find(name).unwrap();
+            assert_eq!(file.read_at(0, &mut buffer).unwrap(), data.len());
+            assert!(buffer == data);
+        }
+    };
+    // a crash at any write while restoring is completed by the next open
+    let mut crash_at = 0;
+    loop {
+        std::fs::copy(base, path)?;
+        let device = Arc::new(CrashDevice {
+            block_file: BlockFile(Mutex::new(OpenOptions::new().read(true).write(true).open(path)?)),
+            writes_left: Mutex::new(crash_at),
+        });
+        let result = EasyFileSystem::open(device.clone()).unwrap().lock().restore_snapshot("old");
+        // once writes are lost, blocks read back from the device may not
+        // match what was written, which is reported as damage
+        let finished = *device.writes_left.lock().unwrap() > 0;
+        assert!(result.is_ok() || !finished);
+        // the restore either did not start or is complete
+        let efs = EasyFileSystem::open(create_image(path, 4096)?).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        if root_inode.find("new0").is_err() {
+            check(&root_inode);
+            assert_eq!(efs.lock().stat().free_blocks, free_blocks);
+        } else {
+            assert_eq!(root_inode.find("big").err(), Some(FsError::NotFound));
+        }
+        drop(root_inode);
+        drop(efs);
+        let problems = fsck(create_image(path, 4096)?, false);
+        assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+        if finished {
+            break;
+        }
+        crash_at += 1;
+    }
+    // changes after restoring leave the snapshot as it was
+    let efs = EasyFileSystem::open(create_image(path, 4096)?).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode.find("big").unwrap().write_at(0, &[1u8; 5 * BLOCK_SZ]).unwrap();
+    root_inode.unlink("extents").unwrap();
+    drop(root_inode);
+    efs.lock().restore_snapshot("old").unwrap();
+    check(&EasyFileSystem::root_inode(&efs));
+    // the held blocks go with the snapshot, its own blocks too
+    efs.lock().delete_snapshot("old").unwrap();
+    assert_eq!(efs.lock().delete_snapshot("old").err(), Some(FsError::NotFound));
+    assert!(efs.lock().snapshots().is_empty());
+    assert!(efs.lock().stat().free_blocks > free_blocks);
+    check(&EasyFileSystem::root_inode(&efs));
+    drop(efs);
+    let problems = fsck(create_image(path, 4096)?, false);
+    assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[test]
+fn efs_xattr_test() -> std::io::Result<()> {
+    let block_file = create_image("target/fs_xattr.img", 4096)?;
+    {
+        let efs = EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let free_blocks = efs.lock().stat().free_blocks;
+        let file = root_inode.create("file").unwrap();
+        assert_eq!(file.get_xattr("user.a").err(), Some(FsError::NoAttribute));
+        assert!(file.list_xattr().unwrap().is_empty());
+        file.set_xattr("user.a", b"hello").unwrap();
+        file.set_xattr("security.capability", &[1, 0, 0, 2]).unwrap();
+        // all of them share one block
+        assert_eq!(efs.lock().stat().free_blocks, free_blocks - 1);
+        file.set_xattr("user.a", b"bye").unwrap();
+        assert_eq!(file.get_xattr("user.a").unwrap(), b"bye");
+        assert_eq!(file.list_xattr().unwrap(), ["user.a", "security.capability"]);
+        assert_eq!(file.set_xattr("", b"").err(), Some(FsError::Invalid));
+        assert_eq!(file.set_xattr(&"n".repeat(256), b"").err(), Some(FsError::NameTooLong));
+        assert_eq!(file.set_xattr("user.big", &[0u8; BLOCK_SZ]).err(), Some(FsError::NoSpace));
+        assert_eq!(file.get_xattr("user.big").err(), Some(FsError::NoAttribute));
+        // the data has nothing to do with them
+        file.write_at(0, &[1u8; 3000]).unwrap();
+        file.clear().unwrap();
+        assert_eq!(file.get_xattr("user.a").unwrap(), b"bye");
+        file.remove_xattr("user.a").unwrap();
+        assert_eq!(file.remove_xattr("user.a").err(), Some(FsError::NoAttribute));
+        root_inode.mkdir("dir").unwrap().set_xattr("user.dir", b"").unwrap();
+        root_inode.symlink("link", "file").unwrap().set_xattr("user.link", &[0xff; 300]).unwrap();
+    }
+    // they are read back from the image
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.find("file").unwrap();
+    assert_eq!(file.list_xattr().unwrap(), ["security.capability"]);
+    assert_eq!(file.get_xattr("security.capability").unwrap(), [1, 0, 0, 2]);
+    assert_eq!(root_inode.find("dir").unwrap().get_xattr("user.dir").unwrap(), b"");
+    assert_eq!(root_inode.find("link").unwrap().get_xattr("user.link").unwrap(), [0xff; 300].to_vec());
+    file.remove_xattr("security.capability").unwrap();
+    drop(file);
+    let free_blocks = efs.lock().stat().free_blocks;
+    root_inode.unlink("link").unwrap();
+    root_inode.rmdir("dir").unwrap();
+    // the attribute blocks go with their inodes, as does the block of the
+    // directory entries
+    assert_eq!(efs.lock().stat().free_blocks, free_blocks + 3);
+    // a manifest sets them when packing
+    set_xattrs(&root_inode, "# comment\n\nfile user.t=text\n  file security.capability=0x0100000200\n")?;
+    let file = root_inode.find("file").unwrap();
+    assert_eq!(file.get_xattr("user.t").unwrap(), b"text");
+    assert_eq!(file.get_xattr("security.capability").unwrap(), [1, 0, 0, 2, 0]);
+    assert!(set_xattrs(&root_inode, "file user.t=0x1").is_err());
+    assert!(set_xattrs(&root_inode, "file").is_err());
+    assert!(set_xattrs(&root_inode, "missing user.t=x").is_err());
+    drop(file);
+    drop(root_inode);
+    drop(efs);
+    let problems = fsck(block_file, false);
+    assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[test]
+fn efs_compress_test() -> std::io::Result<()> {
+    let path = "target/fs_compress.img";
+    let cluster = 8 * BLOCK_SZ;
+    let text: Vec<u8> = b"easy-fs compresses whole clusters. ".iter().cycle().take(cluster).copied().collect();
+    let mut seed = 1u32;
+    let noise: Vec<u8> = (0..cluster)
+        .map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 16) as u8
+        })
+        .collect();
+    // text compresses, noise is kept as it is, zeros are a hole, and the
+    // last cluster is shorter
+    let mut data = text.clone();
+    data.extend_from_slice(&noise);
+    data.extend(vec![0u8; cluster]);
+    data.extend_from_slice(&text[..3000]);
+    let block_file = create_image(path, 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let free_blocks = efs.lock().stat().free_blocks;
+    for name in ["indirect", "extents"].iter() {
+        let free_blocks = efs.lock().stat().free_blocks;
+        let file = root_inode.create(name).unwrap();
+        if *name == "extents" {
+            file.use_extents().unwrap();
+        }
+        file.use_compression().unwrap();
+        assert!(file.is_compressed());
+        assert_eq!(file.write_at(0, &data).unwrap(), data.len());
+        // a block for each text cluster, all eight for the noise, and the
+        // list of the cluster map with its one block
+        assert_eq!(free_blocks - efs.lock().stat().free_blocks, 1 + 8 + 1 + 2);
+        let mut buffer = vec![0u8; data.len()];
+        assert_eq!(file.read_at(0, &mut buffer).unwrap(), data.len());
+        assert!(buffer == data);
+        // clusters turning from compressed to not and back read right
+        file.write_at(0, &noise).unwrap();
+        file.write_at(cluster, &text).unwrap();
+        file.write_at(3 * cluster + 100, &noise[..2000]).unwrap();
+        // the last cluster, now mostly noise, still saves a block; its
+        // fifth block is the first behind indirect1
+        let indirect1 = if *name == "indirect" { 1 } else { 0 };
+        assert_eq!(free_blocks - efs.lock().stat().free_blocks, 8 + 1 + 5 + 2 + indirect1);
+        // and so do the ends cut off and grown back
+        file.truncate(cluster + 100).unwrap();
+        file.truncate(3 * cluster).unwrap();
+        assert_eq!(file.read_at(0, &mut buffer).unwrap(), 3 * cluster);
+        assert!(buffer[..cluster] == noise[..]);
+        assert!(buffer[cluster..cluster + 100] == text[..100]);
+        assert!(buffer[cluster + 100..3 * cluster].iter().all(|byte| *byte == 0));
+        file.write_at(0, &data).unwrap();
+        assert_eq!(file.read_at(0, &mut buffer).unwrap(), data.len());
+        assert!(buffer == data);
+    }
+    // a cluster of one block has nothing to save
+    let small = root_inode.create("small").unwrap();
+    small.use_compression().unwrap();
+    small.write_at(0, b"1").unwrap();
+    assert_eq!(small.read_at(0, &mut [0u8; 2]).unwrap(), 1);
+    // only an empty file can start
+    assert_eq!(small.use_compression(), Err(FsError::Invalid));
+    drop(small);
+    root_inode.unlink("small").unwrap();
+    drop(root_inode);
+    drop(efs);
+    let problems = fsck(block_file.clone(), false);
+    assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    // a compressed stream cut short is damage: its length is in front of
+    // it, followed by a token, a length byte and the first literals
+    let block_file = create_image(path, 4096)?;
+    let mut block = [0u8; BLOCK_SZ];
+    let block_id = (0..4096)
+        .find(|block_id| {
+            block_file.read_block(*block_id, &mut block);
+            block[6..13] == b"easy-fs"[..]
+        })
+        .unwrap();
+    let len = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+    patch_block(&block_file, block_id, 0, &(len - 1).to_le_bytes());
+    let efs = EasyFileSystem::open(block_file.clone()).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let mut buffer = vec![0u8; data.len()];
+    let damaged: Vec<_> = ["indirect", "extents"]
+        .iter()
+        .map(|name| root_inode.find(name).unwrap().read_at(0, &mut buffer).err())
+        .collect();
+    assert!(damaged.contains(&Some(FsError::Corrupt)) && damaged.contains(&None));
+    // the blocks all go, the cluster map too
+    root_inode.unlink("indirect").unwrap();
+    root_inode.unlink("extents").unwrap();
+    assert_eq!(efs.lock().stat().free_blocks, free_blocks);
+    Ok(())
+}
+
+#[test]
+fn efs_crypt_test() -> std::io::Result<()> {
+    let base = "target/fs_crypt_base.img";
+    let path = "target/fs_crypt.img";
+    let key = [7u8; KEY_SZ];
+    let text: Vec<u8> = b"easy-fs seals every block. ".iter().cycle().take(20 * BLOCK_SZ).copied().collect();
+    let block_file = create_image(base, 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    // only a registered key can be used
+    let file = root_inode.create("indirect").unwrap();
+    assert_eq!(file.use_encryption(1, &[1u8; easy_fs::SALT_SZ]), Err(FsError::NoKey));
+    let key_id = efs.lock().add_key(&key);
+    for name in ["indirect", "extents"].iter() {
+        let file = root_inode.find(name).or_else(|_| root_inode.create(name)).unwrap();
+        if *name == "extents" {
+            file.use_extents().unwrap();
+        }
+        file.use_encryption(key_id, &[1u8; easy_fs::SALT_SZ]).unwrap();
+        assert!(file.is_encrypted());
+        assert_eq!(file.use_compression(), Err(FsError::Invalid));
+        assert_eq!(file.write_at(0, &text).unwrap(), text.len());
+        // blocks written in part, past the end, and cut and grown back
+        let mut data = text.clone();
+        file.write_at(100, &text[..1000]).unwrap();
+        data[100..1100].copy_from_slice(&text[..1000]);
+        file.write_at(25 * BLOCK_SZ + 7, b"tail").unwrap();
+        data.resize(25 * BLOCK_SZ + 7, 0);
+        data.extend_from_slice(b"tail");
+        file.truncate(3 * BLOCK_SZ + 5).unwrap();
+        file.truncate(4 * BLOCK_SZ).unwrap();
+        data[3 * BLOCK_SZ + 5..4 * BLOCK_SZ].iter_mut().for_each(|byte| *byte = 0);
+        data.truncate(4 * BLOCK_SZ);
+        file.write_at(10 * BLOCK_SZ, &text[..BLOCK_SZ]).unwrap();
+        data.resize(10 * BLOCK_SZ, 0);
+        data.extend_from_slice(&text[..BLOCK_SZ]);
+        let mut buffer = vec![0u8; data.len() + 1];
+        assert_eq!(file.read_at(0, &mut buffer).unwrap(), data.len());
+        assert!(buffer[..data.len()] == data[..]);
+        // the blocks between are a hole
+        assert_eq!(file.seek_hole(0).unwrap(), 4 * BLOCK_SZ);
+        assert_eq!(file.seek_data(4 * BLOCK_SZ).unwrap(), 10 * BLOCK_SZ);
+    }
+    // no plaintext reaches the disk
+    let image = std::fs::read(base)?;
+    assert!(!image.windows(16).any(|window| window == &text[..16]));
+    // without its key, or with another one, a file cannot be read or written
+    efs.lock().add_key(&[8u8; KEY_SZ]);
+    efs.lock().remove_key(key_id).unwrap();
+    assert_eq!(efs.lock().remove_key(key_id), Err(FsError::NotFound));
+    let file = root_inode.find("indirect").unwrap();
+    let mut buffer = vec![0u8; BLOCK_SZ];
+    assert_eq!(file.read_at(0, &mut buffer), Err(FsError::NoKey));
+    assert_eq!(file.write_at(0, b"x"), Err(FsError::NoKey));
+    // but it can be removed, with all its tags
+    root_inode.unlink("extents").unwrap();
+    drop(file);
+    drop(root_inode);
+    drop(efs);
+    let problems = fsck(block_file.clone(), false);
+    assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    // keys are not kept on disk
+    let efs = EasyFileSystem::open(create_image(base, 4096)?).unwrap();
+    let file = EasyFileSystem::root_inode(&efs).find("indirect").unwrap();
+    assert_eq!(file.read_at(0, &mut buffer), Err(FsError::NoKey));
+    efs.lock().add_key(&key);
+    assert_eq!(file.read_at(0, &mut buffer).unwrap(), BLOCK_SZ);
+    drop(file);
+    drop(efs);
+    // the block rewritten at the start of the file is the one which changes
+    // in full
+    let before = std::fs::read(base)?;
+    std::fs::copy(base, path)?;
+    let rewrite = |block_file: Arc<dyn BlockDevice>| {
+        let efs = EasyFileSystem::open(block_file).unwrap();
+        efs.lock().add_key(&key);
+        let file = EasyFileSystem::root_inode(&efs).find("indirect").unwrap();
+        let _ = file.write_at(0, &text[..BLOCK_SZ]);
+    };
+    rewrite(create_image(path, 4096)?);
+    let after = std::fs::read(path)?;
+    let data_block = (33..4096)
+        .max_by_key(|block_id| {
+            let range = block_id * BLOCK_SZ..(block_id + 1) * BLOCK_SZ;
+            before[range.clone()].iter().zip(after[range].iter()).filter(|(a, b)| a != b).count()
+        })
+        .unwrap();
+    // the nonces are reserved on disk before data is sealed with them: a
+    // crash at any write, and sealing the same data again, never reuses one
+    let mut crash_at = 0;
+    loop {
+        std::fs::copy(base, path)?;
+        let device = Arc::new(CrashDevice {
+            block_file: BlockFile(Mutex::new(OpenOptions::new().read(true).write(true).open(path)?)),
+            writes_left: Mutex::new(crash_at),
+        });
+        rewrite(device.clone());
+        let finished = *device.writes_left.lock().unwrap() > 0;
+        let block_file = create_image(path, 4096)?;
+        let mut crashed = [0u8; BLOCK_SZ];
+        block_file.read_block(data_block, &mut crashed);
+        rewrite(block_file.clone());
+        let mut rewritten = [0u8; BLOCK_SZ];
+        block_file.read_block(data_block, &mut rewritten);
+        assert!(crashed != rewritten);
+        if finished {
+            break;
+        }
+        crash_at += 1;
+    }
+    // damaged ciphertext fails to open
+    let block_file = create_image(path, 4096)?;
+    block_file.read_block(data_block, &mut buffer);
+    buffer[100] ^= 1;
+    block_file.write_block(data_block, &buffer);
+    let efs = EasyFileSystem::open(block_file).unwrap();
+    efs.lock().add_key(&key);
+    let file = EasyFileSystem::root_inode(&efs).find("indirect").unwrap();
+    assert_eq!(file.read_at(0, &mut buffer), Err(FsError::Corrupt));
+    assert_eq!(file.read_at(BLOCK_SZ, &mut buffer).unwrap(), BLOCK_SZ);
+    drop(file);
+    drop(efs);
+    // the tag lists a snapshot holds are copied, and the charge of the
+    // originals goes back to the owner
+    let block_file = create_image("target/fs_crypt_snapshot.img", 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    efs.lock().set_quota(0, QuotaLimit::default(), QuotaLimit::default()).unwrap();
+    let key_id = efs.lock().add_key(&key);
+    let file = EasyFileSystem::root_inode(&efs).create("file").unwrap();
+    file.use_encryption(key_id, &[1u8; easy_fs::SALT_SZ]).unwrap();
+    file.write_at(0, &text[..4 * BLOCK_SZ]).unwrap();
+    efs.lock().create_snapshot("old").unwrap();
+    file.write_at(0, &text[BLOCK_SZ..2 * BLOCK_SZ]).unwrap();
+    let mut buffer = vec![0u8; 4 * BLOCK_SZ];
+    assert_eq!(file.read_at(0, &mut buffer).unwrap(), 4 * BLOCK_SZ);
+    assert!(buffer[..BLOCK_SZ] == text[BLOCK_SZ..2 * BLOCK_SZ]);
+    assert!(buffer[BLOCK_SZ..] == text[BLOCK_SZ..4 * BLOCK_SZ]);
+    drop(file);
+    drop(efs);
+    let problems = fsck(block_file, false);
+    assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    // images sealed with the same key use the same nonces, their salts
+    // keep the keystream apart
+    let seal = |salt: &[u8; easy_fs::SALT_SZ]| -> std::io::Result<Vec<u8>> {
+        std::fs::remove_file(path)?;
+        let efs = EasyFileSystem::create(create_image(path, 4096)?, 4096, 1).unwrap();
+        let key_id = efs.lock().add_key(&key);
+        let file = EasyFileSystem::root_inode(&efs).create("file").unwrap();
+        file.use_encryption(key_id, salt).unwrap();
+        file.write_at(0, &text[..4 * BLOCK_SZ]).unwrap();
+        drop(file);
+        drop(efs);
+        std::fs::read(path)
+    };
+    let image = seal(&[1u8; easy_fs::SALT_SZ])?;
+    // past the journal, whose blocks may come in another order
+    let data_start = 33 * BLOCK_SZ;
+    assert!(seal(&[1u8; easy_fs::SALT_SZ])?[data_start..] == image[data_start..]);
+    let other = seal(&[2u8; easy_fs::SALT_SZ])?;
+    let changed = image[data_start..]
+        .chunks(BLOCK_SZ)
+        .zip(other[data_start..].chunks(BLOCK_SZ))
+        .filter(|(a, b)| a != b)
+        .count();
+    assert!(changed >= 4, "{}", changed);
+    Ok(())
+}
+
+#[test]
+fn efs_quota_test() -> std::io::Result<()> {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    static NOW: AtomicU32 = AtomicU32::new(1000);
+    let block_file = create_image("target/fs_quota.img", 4096)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1).unwrap();
+    efs.lock().set_clock(|| NOW.load(Ordering::SeqCst));
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let home = root_inode.mkdir("home").unwrap();
+    home.chown(1000, 1000).unwrap();
+    let blocks = QuotaLimit { soft: 8, hard: 16 };
+    let inodes = QuotaLimit { soft: 0, hard: 3 };
+    efs.lock().set_quota(1000, blocks, inodes).unwrap();
+    let usage = || efs.lock().quotas().into_iter().find(|quota| quota.uid == 1000).unwrap();
+    // new inodes belong to the owner of the directory, up to the hard limit
+    let file = home.create("file").unwrap();
+    assert_eq!(file.stat().unwrap().uid, 1000);
+    home.create("other").unwrap();
+    assert_eq!(usage().inodes.used, 3);
+    assert_eq!(home.create("more").err(), Some(FsError::QuotaExceeded));
+    assert_eq!(usage().inodes.used, 3);
+    // blocks past the soft limit start the grace period, the hard limit
+    // stops the file from growing right away
+    let mut size = 0;
+    let err = loop {
+        match file.write_at(size, &[1u8; BLOCK_SZ]) {
+            Ok(len) => size += len,
+            Err(err) => break err,
+        }
+    };
+    assert_eq!(err, FsError::QuotaExceeded);
+    let quota = usage();
+    assert!(quota.blocks.used > blocks.soft && quota.blocks.used <= blocks.hard, "{:?}", quota);
+    assert_eq!(quota.blocks.grace, 1000 + easy_fs::QUOTA_GRACE);
+    assert_eq!(file.stat().unwrap().size as usize, size);
+    // root has no limits
+    root_inode.create("big").unwrap().write_at(0, &vec![2u8; 32 * BLOCK_SZ]).unwrap();
+    // below the hard limit again, growing works while the grace lasts
+    size -= 2 * BLOCK_SZ;
+    file.truncate(size).unwrap();
+    assert_eq!(file.write_at(size, &[1u8; BLOCK_SZ]).unwrap(), BLOCK_SZ);
+    size += BLOCK_SZ;
+    // and fails once it is over, but writing in place needs no new blocks
+    NOW.store(1000 + easy_fs::QUOTA_GRACE, Ordering::SeqCst);
+    assert_eq!(file.write_at(size, &[1u8; BLOCK_SZ]), Err(FsError::QuotaExceeded));
+    assert_eq!(file.write_at(0, &[3u8; BLOCK_SZ]).unwrap(), BLOCK_SZ);
+    assert_eq!(file.stat().unwrap().size as usize, size);
+    // back under the soft limit the grace period ends
+    file.truncate(BLOCK_SZ).unwrap();
+    let quota = usage();
+    assert!(quota.blocks.used <= blocks.soft, "{:?}", quota);
+    assert_eq!(quota.blocks.grace, 0);
+    assert_eq!(file.write_at(BLOCK_SZ, &[1u8; BLOCK_SZ]).unwrap(), BLOCK_SZ);
+    drop(file);
+    drop(home);
+    drop(root_inode);
+    drop(efs);
+    let problems = fsck(block_file, false);
+    assert!(problems.is_empty(), "{:?}", problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+    Ok(())
+}
@@ -0,0 +1,349 @@
+/// Bytes of a key.
+pub const KEY_SZ: usize = 32;
+pub const NONCE_SZ: usize = 12;
+pub const TAG_SZ: usize = 16;
+/// Bytes of the salt `derive_key` takes.
+pub const SALT_SZ: usize = 12;
+
+/// "expand 32-byte k"
+const SIGMA: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+const LIMB_MASK: u32 = 0x3ff_ffff;
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// Return the ChaCha20 keystream block `counter` for `key` and `nonce`.
+fn chacha20_block(key: &[u8; KEY_SZ], counter: u32, nonce: &[u8; NONCE_SZ]) -> [u8; 64] {
+    let mut initial = [0u32; 16];
+    initial[..4].copy_from_slice(&SIGMA);
+    for (word, bytes) in initial[4..12].iter_mut().zip(key.chunks(4)) {
+        *word = le32(bytes);
+    }
+    initial[12] = counter;
+    for (word, bytes) in initial[13..].iter_mut().zip(nonce.chunks(4)) {
+        *word = le32(bytes);
+    }
+    let mut state = initial;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    let mut block = [0u8; 64];
+    for ((bytes, word), initial) in block.chunks_mut(4).zip(state.iter()).zip(initial.iter()) {
+        bytes.copy_from_slice(&word.wrapping_add(*initial).to_le_bytes());
+    }
+    block
+}
+
+/// XOR `data` with the keystream from block 1 on, block 0 keys the MAC.
+fn chacha20_xor(key: &[u8; KEY_SZ], nonce: &[u8; NONCE_SZ], data: &mut [u8]) {
+    for (counter, chunk) in data.chunks_mut(64).enumerate() {
+        let block = chacha20_block(key, counter as u32 + 1, nonce);
+        chunk.iter_mut().zip(block.iter()).for_each(|(byte, key_byte)| *byte ^= key_byte);
+    }
+}
+
+/// Poly1305 with 26-bit limbs, fed whole 16-byte blocks only.
+struct Poly1305 {
+    r: [u32; 5],
+    h: [u32; 5],
+    pad: [u32; 4],
+}
+
+impl Poly1305 {
+    fn new(key: &[u8]) -> Self {
+        Self {
+            r: [
+                le32(&key[0..]) & 0x3ff_ffff,
+                (le32(&key[3..]) >> 2) & 0x3ff_ff03,
+                (le32(&key[6..]) >> 4) & 0x3ff_c0ff,
+                (le32(&key[9..]) >> 6) & 0x3f0_3fff,
+                (le32(&key[12..]) >> 8) & 0x00f_ffff,
+            ],
+            h: [0; 5],
+            pad: [le32(&key[16..]), le32(&key[20..]), le32(&key[24..]), le32(&key[28..])],
+        }
+    }
+
+    fn block(&mut self, block: &[u8]) {
+        let [r0, r1, r2, r3, r4] = self.r;
+        let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+        let h = &mut self.h;
+        h[0] += le32(&block[0..]) & LIMB_MASK;
+        h[1] += (le32(&block[3..]) >> 2) & LIMB_MASK;
+        h[2] += (le32(&block[6..]) >> 4) & LIMB_MASK;
+        h[3] += (le32(&block[9..]) >> 6) & LIMB_MASK;
+        h[4] += (le32(&block[12..]) >> 8) | 1 << 24;
+        let mul = |a: u32, b: u32| a as u64 * b as u64;
+        let d = [
+            mul(h[0], r0) + mul(h[1], s4) + mul(h[2], s3) + mul(h[3], s2) + mul(h[4], s1),
+            mul(h[0], r1) + mul(h[1], r0) + mul(h[2], s4) + mul(h[3], s3) + mul(h[4], s2),
+            mul(h[0], r2) + mul(h[1], r1) + mul(h[2], r0) + mul(h[3], s4) + mul(h[4], s3),
+            mul(h[0], r3) + mul(h[1], r2) + mul(h[2], r1) + mul(h[3], r0) + mul(h[4], s4),
+            mul(h[0], r4) + mul(h[1], r3) + mul(h[2], r2) + mul(h[3], r1) + mul(h[4], r0),
+        ];
+        let mut carry = 0;
+        for (limb, d) in h.iter_mut().zip(d.iter()) {
+            let d = d + carry;
+            *limb = d as u32 & LIMB_MASK;
+            carry = d >> 26;
+        }
+        h[0] += carry as u32 * 5;
+        h[1] += h[0] >> 26;
+        h[0] &= LIMB_MASK;
+    }
+
+    fn finish(mut self) -> [u8; TAG_SZ] {
+        let h = &mut self.h;
+        let mut carry = 0;
+        for limb in h[1..].iter_mut() {
+            *limb += carry;
+            carry = *limb >> 26;
+            *limb &= LIMB_MASK;
+        }
+        h[0] += carry * 5;
+        h[1] += h[0] >> 26;
+        h[0] &= LIMB_MASK;
+        // h - p, used unless it is negative
+        let mut g = [0u32; 5];
+        let mut carry = 5;
+        for (g, limb) in g.iter_mut().zip(h.iter()) {
+            *g = limb + carry;
+            carry = *g >> 26;
+            *g &= LIMB_MASK;
+        }
+        g[4] = g[4].wrapping_add(carry << 26).wrapping_sub(1 << 26);
+        let keep = (g[4] >> 31).wrapping_sub(1);
+        for (limb, g) in h.iter_mut().zip(g.iter()) {
+            *limb = (*limb & !keep) | (g & keep);
+        }
+        let words = [
+            h[0] | h[1] << 26,
+            h[1] >> 6 | h[2] << 20,
+            h[2] >> 12 | h[3] << 14,
+            h[3] >> 18 | h[4] << 8,
+        ];
+        let mut tag = [0u8; TAG_SZ];
+        let mut sum = 0u64;
+        for ((bytes, word), pad) in tag.chunks_mut(4).zip(words.iter()).zip(self.pad.iter()) {
+            sum = *word as u64 + *pad as u64 + (sum >> 32);
+            bytes.copy_from_slice(&(sum as u32).to_le_bytes());
+        }
+        tag
+    }
+}
+
+/// The tag of `aad` and `ciphertext`, each padded to whole blocks.
+fn mac(key: &[u8; KEY_SZ], nonce: &[u8; NONCE_SZ], aad: &[u8], ciphertext: &[u8]) -> [u8; TAG_SZ] {
+    let mut poly = Poly1305::new(&chacha20_block(key, 0, nonce)[..32]);
+    for chunk in aad.chunks(16).chain(ciphertext.chunks(16)) {
+        let mut block = [0u8; 16];
+        block[..chunk.len()].copy_from_slice(chunk);
+        poly.block(&block);
+    }
+    let mut lengths = [0u8; 16];
+    lengths[..8].copy_from_slice(&(aad.len() as u64).to_le_bytes());
+    lengths[8..].copy_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+    poly.block(&lengths);
+    poly.finish()
+}
+
+/// Encrypt `data` in place with ChaCha20-Poly1305 (RFC 8439) and return the
+/// tag, which covers `aad` as well. A nonce must never be used twice with
+/// the same key.
+pub fn seal(key: &[u8; KEY_SZ], nonce: &[u8; NONCE_SZ], aad: &[u8], data: &mut [u8]) -> [u8; TAG_SZ] {
+    chacha20_xor(key, nonce, data);
+    mac(key, nonce, aad, data)
+}
+
+/// Check `tag` and decrypt `data` in place. `data` is left as it is if the
+/// tag does not match.
+pub fn open(
+    key: &[u8; KEY_SZ],
+    nonce: &[u8; NONCE_SZ],
+    aad: &[u8],
+    data: &mut [u8],
+    tag: &[u8; TAG_SZ],
+) -> bool {
+    let expected = mac(key, nonce, aad, data);
+    // in constant time
+    if expected.iter().zip(tag.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) != 0 {
+        return false;
+    }
+    chacha20_xor(key, nonce, data);
+    true
+}
+
+/// Derive the key to seal with from `key` and a random `salt`, so that data
+/// sealed under the same key with the same nonce, which a copy of a key on
+/// several images leads to, is sealed with different keys. The keystream
+/// block 1 is taken, `key_id` takes block 0.
+pub fn derive_key(key: &[u8; KEY_SZ], salt: &[u8; SALT_SZ]) -> [u8; KEY_SZ] {
+    let mut derived = [0u8; KEY_SZ];
+    derived.copy_from_slice(&chacha20_block(key, 1, salt)[..KEY_SZ]);
+    derived
+}
+
+/// Return an identifier of `key` which does not reveal it: keystream of a
+/// nonce `seal` is never given by `EasyFileSystem`.
+pub fn key_id(key: &[u8; KEY_SZ]) -> u64 {
+    let block = chacha20_block(key, 0, &[0xff; NONCE_SZ]);
+    u64::from_le_bytes([block[0], block[1], block[2], block[3], block[4], block[5], block[6], block[7]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn array<T: Default + AsMut<[u8]>>(bytes: &[u8]) -> T {
+        let mut array = T::default();
+        array.as_mut().copy_from_slice(bytes);
+        array
+    }
+
+    const SUNSCREEN: &[u8] = b"Ladies and Gentlemen of the class of '99: If I could offer you only one \
+        tip for the future, sunscreen would be it.";
+
+    fn key_0() -> [u8; KEY_SZ] {
+        array(&(0..32).collect::<Vec<u8>>())
+    }
+
+    fn key_80() -> [u8; KEY_SZ] {
+        array(&(0x80..0xa0).collect::<Vec<u8>>())
+    }
+
+    /// RFC 8439, 2.3.2 and 2.6.2
+    #[test]
+    fn chacha20_block_test() {
+        let block = chacha20_block(&key_0(), 1, &array(&hex("000000090000004a00000000")));
+        assert_eq!(block[..], hex(
+            "10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e\
+             d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e"
+        )[..]);
+        let block = chacha20_block(&key_80(), 0, &array(&hex("000000000001020304050607")));
+        assert_eq!(block[..32], hex(
+            "8ad5a08b905f81cc815040274ab29471a833b637e3fd0da508dbb8e2fdd1a646"
+        )[..]);
+    }
+
+    /// RFC 8439, 2.4.2
+    #[test]
+    fn chacha20_xor_test() {
+        let mut data = SUNSCREEN.to_vec();
+        chacha20_xor(&key_0(), &array(&hex("000000000000004a00000000")), &mut data);
+        assert_eq!(data, hex(
+            "6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0b\
+             f91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d8\
+             07ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab7793736\
+             5af90bbf74a35be6b40b8eedf2785e42874d"
+        ));
+    }
+
+    /// RFC 8439, A.3 vectors 5 to 9, whose messages are whole blocks
+    #[test]
+    fn poly1305_test() {
+        let cases = [
+            ("02", "", "ffffffffffffffffffffffffffffffff", "03"),
+            ("02", "ffffffffffffffffffffffffffffffff", "02000000000000000000000000000000", "03"),
+            (
+                "01",
+                "",
+                "ffffffffffffffffffffffffffffffff\
+                 f0ffffffffffffffffffffffffffffff\
+                 11000000000000000000000000000000",
+                "05",
+            ),
+            (
+                "01",
+                "",
+                "ffffffffffffffffffffffffffffffff\
+                 fbfefefefefefefefefefefefefefefe\
+                 01010101010101010101010101010101",
+                "00",
+            ),
+            ("02", "", "fdffffffffffffffffffffffffffffff", "faffffffffffffffffffffffffffffff"),
+        ];
+        for (r, s, message, tag) in cases.iter() {
+            let mut key = [0u8; 32];
+            key[..1].copy_from_slice(&hex(r));
+            key[16..16 + s.len() / 2].copy_from_slice(&hex(s));
+            let mut poly = Poly1305::new(&key);
+            hex(message).chunks(16).for_each(|block| poly.block(block));
+            let mut expected = hex(tag);
+            expected.resize(TAG_SZ, 0);
+            assert_eq!(poly.finish()[..], expected[..]);
+        }
+    }
+
+    /// RFC 8439, 2.8.2
+    #[test]
+    fn aead_test() {
+        let nonce = array(&hex("070000004041424344454647"));
+        let aad = hex("50515253c0c1c2c3c4c5c6c7");
+        let mut data = SUNSCREEN.to_vec();
+        let tag = seal(&key_80(), &nonce, &aad, &mut data);
+        assert_eq!(data, hex(
+            "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6\
+             3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36\
+             92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc\
+             3ff4def08e4b7a9de576d26586cec64b6116"
+        ));
+        assert_eq!(tag[..], hex("1ae10b594f09e26a7e902ecbd0600691")[..]);
+        assert!(open(&key_80(), &nonce, &aad, &mut data, &tag));
+        assert_eq!(data, SUNSCREEN);
+    }
+
+    #[test]
+    fn tamper_test() {
+        let nonce = [3u8; NONCE_SZ];
+        let mut data = SUNSCREEN.to_vec();
+        let tag = seal(&key_0(), &nonce, b"aad", &mut data);
+        let sealed = data.clone();
+        // another key, nonce or additional data, changed data or tag
+        assert!(!open(&key_80(), &nonce, b"aad", &mut data, &tag));
+        assert!(!open(&key_0(), &[4u8; NONCE_SZ], b"aad", &mut data, &tag));
+        assert!(!open(&key_0(), &nonce, b"aaa", &mut data, &tag));
+        let mut bad_tag = tag;
+        bad_tag[15] ^= 1;
+        assert!(!open(&key_0(), &nonce, b"aad", &mut data, &bad_tag));
+        data[0] ^= 1;
+        assert!(!open(&key_0(), &nonce, b"aad", &mut data, &tag));
+        data[0] ^= 1;
+        assert_eq!(data, sealed);
+        assert!(open(&key_0(), &nonce, b"aad", &mut data, &tag));
+        assert_eq!(data, SUNSCREEN);
+        assert_ne!(key_id(&key_0()), key_id(&key_80()));
+    }
+
+    #[test]
+    fn derive_key_test() {
+        let salt = [1u8; SALT_SZ];
+        assert_eq!(derive_key(&key_0(), &salt), derive_key(&key_0(), &salt));
+        assert_ne!(derive_key(&key_0(), &salt), derive_key(&key_0(), &[2u8; SALT_SZ]));
+        assert_ne!(derive_key(&key_0(), &salt), derive_key(&key_80(), &salt));
+        assert_ne!(derive_key(&key_0(), &salt), key_0());
+    }
+}
@@ -1,4 +1,5 @@
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use super::{
     BlockDevice,
     BLOCK_SZ,
@@ -15,9 +16,9 @@ pub struct Bitmap {
 }
 
 /// Return (block_pos, bits64_pos, inner_pos)
-fn decomposition(mut bit: usize) -> (usize, usize, usize) {
+fn decomposition(bit: usize) -> (usize, usize, usize) {
     let block_pos = bit / BLOCK_BITS;
-    bit = bit % BLOCK_BITS;
+    let bit = bit % BLOCK_BITS;
     (block_pos, bit / 64, bit % 64)
 }
 
@@ -31,31 +32,87 @@ impl Bitmap {
 
     pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
         for block_id in 0..self.blocks {
-            let pos = get_block_cache(
-                block_id + self.start_block_id as usize,
+            let block_cache = get_block_cache(
+                block_id + self.start_block_id,
                 Arc::clone(block_device),
-            ).lock().modify(0, |bitmap_block: &mut BitmapBlock| {
-                if let Some((bits64_pos, inner_pos)) = bitmap_block
+            );
+            let mut block_cache = block_cache.lock();
+            // full blocks are only read, so that they stay out of the journal
+            let pos = block_cache.read(0, |bitmap_block: &BitmapBlock| {
+                bitmap_block
                     .iter()
                     .enumerate()
                     .find(|(_, bits64)| **bits64 != u64::MAX)
                     .map(|(bits64_pos, bits64)| {
                         (bits64_pos, bits64.trailing_ones() as usize)
-                    }) {
-                    // modify cache
-                    bitmap_block[bits64_pos] |= 1u64 << inner_pos;
-                    Some(block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos as usize)
-                } else {
-                    None
-                }
+                    })
             });
-            if pos.is_some() {
-                return pos;
+            if let Some((bits64_pos, inner_pos)) = pos {
+                block_cache.modify(0, |bitmap_block: &mut BitmapBlock| {
+                    bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+                });
+                return Some(block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos);
             }
         }
         None
     }
 
+    /// Allocate up to `len` free bits in a row, all below `limit`, and return
+    /// the first one and how many there are.
+    ///
+    /// The run starts at `goal` if that is free. Otherwise the first run of
+    /// `len` bits is taken, or the longest one if none is that long.
+    pub fn alloc_run(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        goal: Option<usize>,
+        len: usize,
+        limit: usize,
+    ) -> Option<(usize, usize)> {
+        let limit = limit.min(self.maximum());
+        let mut words: Vec<u64> = Vec::new();
+        for block_id in 0..self.blocks {
+            get_block_cache(
+                block_id + self.start_block_id,
+                Arc::clone(block_device),
+            ).lock().read(0, |bitmap_block: &BitmapBlock| {
+                words.extend_from_slice(bitmap_block);
+            });
+        }
+        let is_free = |bit: usize| words[bit / 64] & (1u64 << (bit % 64)) == 0;
+        let run_from = |start: usize| {
+            (start..limit.min(start + len)).take_while(|bit| is_free(*bit)).count()
+        };
+        let (start, found) = match goal.filter(|goal| *goal < limit && is_free(*goal)) {
+            Some(goal) => (goal, run_from(goal)),
+            None => {
+                let mut best = (0, 0);
+                let mut bit = 0;
+                while bit < limit && best.1 < len {
+                    if words[bit / 64] == u64::MAX {
+                        bit = (bit / 64 + 1) * 64;
+                    } else if !is_free(bit) {
+                        bit += 1;
+                    } else {
+                        let run = run_from(bit);
+                        if run > best.1 {
+                            best = (bit, run);
+                        }
+                        bit += run;
+                    }
+                }
+                best
+            }
+        };
+        if found == 0 {
+            return None;
+        }
+        for bit in start..start + found {
+            self.set(block_device, bit);
+        }
+        Some((start, found))
+    }
+
     pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
         let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
         get_block_cache(
@@ -67,6 +124,45 @@ impl Bitmap {
         });
     }
 
+    pub fn is_allocated(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> bool {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(
+            block_pos + self.start_block_id,
+            Arc::clone(block_device)
+        ).lock().read(0, |bitmap_block: &BitmapBlock| {
+            bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0
+        })
+    }
+
+    /// Mark a free `bit` as allocated.
+    pub fn set(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(
+            block_pos + self.start_block_id,
+            Arc::clone(block_device)
+        ).lock().modify(0, |bitmap_block: &mut BitmapBlock| {
+            assert!(bitmap_block[bits64_pos] & (1u64 << inner_pos) == 0);
+            bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+        });
+    }
+
+    /// Count the allocated bits.
+    pub fn count(&self, block_device: &Arc<dyn BlockDevice>) -> usize {
+        let mut count = 0;
+        for block_id in 0..self.blocks {
+            get_block_cache(
+                block_id + self.start_block_id,
+                Arc::clone(block_device),
+            ).lock().read(0, |bitmap_block: &BitmapBlock| {
+                count += bitmap_block
+                    .iter()
+                    .map(|bits64| bits64.count_ones() as usize)
+                    .sum::<usize>();
+            });
+        }
+        count
+    }
+
     pub fn maximum(&self) -> usize {
         self.blocks * BLOCK_BITS
     }
@@ -1,9 +1,11 @@
 use super::{
     BLOCK_SZ,
     BlockDevice,
+    checksum,
+    aead::{self, KEY_SZ, NONCE_SZ, TAG_SZ},
 };
-use alloc::collections::VecDeque;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use lazy_static::*;
 use spin::Mutex;
 
@@ -12,6 +14,10 @@ pub struct BlockCache {
     block_id: usize,
     block_device: Arc<dyn BlockDevice>,
     modified: bool,
+    /// Checked against the checksum table, see `checksum::check`.
+    verified: bool,
+    /// Failed the check, the data is replaced with zeros.
+    corrupt: bool,
 }
 
 impl BlockCache {
@@ -27,6 +33,46 @@ impl BlockCache {
             block_id,
             block_device,
             modified: false,
+            verified: false,
+            corrupt: false,
+        }
+    }
+
+    pub fn block_id(&self) -> usize {
+        self.block_id
+    }
+
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    pub fn is_verified(&self) -> bool {
+        self.verified
+    }
+
+    pub fn set_verified(&mut self) {
+        self.verified = true;
+    }
+
+    pub fn is_corrupt(&self) -> bool {
+        self.corrupt
+    }
+
+    /// Hide the damaged data. The block is never written back.
+    pub fn set_corrupt(&mut self) {
+        self.cache = [0u8; BLOCK_SZ];
+        self.verified = true;
+        self.corrupt = true;
+    }
+
+    /// Drop the changes and read the block from disk again.
+    pub fn discard(&mut self) {
+        self.modified = false;
+        if self.corrupt {
+            self.cache = [0u8; BLOCK_SZ];
+        } else {
+            self.block_device.read_block(self.block_id, &mut self.cache);
+            self.verified = false;
         }
     }
 
@@ -57,8 +103,40 @@ impl BlockCache {
         f(self.get_mut(offset))
     }
 
+    /// Return the data of a block sealed with `key` and `nonce`, see
+    /// `crypt`, or `None` if it does not match `tag`. The cache keeps the
+    /// ciphertext, which is what the disk holds.
+    pub fn read_sealed(
+        &self,
+        key: &[u8; KEY_SZ],
+        nonce: &[u8; NONCE_SZ],
+        aad: &[u8],
+        tag: &[u8; TAG_SZ],
+    ) -> Option<[u8; BLOCK_SZ]> {
+        let mut data = self.cache;
+        if aead::open(key, nonce, aad, &mut data, tag) {
+            Some(data)
+        } else {
+            None
+        }
+    }
+
+    /// Replace the block with `data` sealed with `key` and `nonce`, and
+    /// return the tag.
+    pub fn write_sealed(
+        &mut self,
+        key: &[u8; KEY_SZ],
+        nonce: &[u8; NONCE_SZ],
+        aad: &[u8],
+        data: &[u8; BLOCK_SZ],
+    ) -> [u8; TAG_SZ] {
+        self.modified = true;
+        self.cache = *data;
+        aead::seal(key, nonce, aad, &mut self.cache)
+    }
+
     pub fn sync(&mut self) {
-        if self.modified {
+        if self.modified && !self.corrupt {
             self.modified = false;
             self.block_device.write_block(self.block_id, &self.cache);
         }
@@ -71,15 +149,106 @@ impl Drop for BlockCache {
     }
 }
 
-const BLOCK_CACHE_SIZE: usize = 16;
+/// Capacity of the global cache unless set otherwise.
+///
+/// Blocks in use and blocks with changes not committed yet cannot be evicted.
+/// If all are like that, the cache grows past its capacity for a while.
+const BLOCK_CACHE_CAPACITY: usize = 32;
+
+/// Identify a device by its address, which stays unique while any cached
+/// block keeps the device alive.
+pub fn device_key(block_device: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(block_device) as *const u8 as usize
+}
+
+struct CacheEntry {
+    device: usize,
+    block_id: usize,
+    block_cache: Arc<Mutex<BlockCache>>,
+    /// Set on every hit, cleared when the CLOCK hand passes by.
+    referenced: bool,
+}
 
+/// Block cache with CLOCK eviction, shared by all devices. Entries are found
+/// through a hash table of indices into `entries`, bucketed by device and
+/// block ID.
 pub struct BlockCacheManager {
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    capacity: usize,
+    entries: Vec<CacheEntry>,
+    buckets: Vec<Vec<usize>>,
+    hand: usize,
 }
 
 impl BlockCacheManager {
-    pub fn new() -> Self {
-        Self { queue: VecDeque::new() }
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            capacity,
+            entries: Vec::with_capacity(capacity),
+            buckets: (0..capacity).map(|_| Vec::new()).collect(),
+            hand: 0,
+        }
+    }
+
+    /// Change the capacity. A smaller cache shrinks as new blocks come in.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        self.buckets = (0..self.capacity).map(|_| Vec::new()).collect();
+        for idx in 0..self.entries.len() {
+            let bucket = self.bucket(self.entries[idx].device, self.entries[idx].block_id);
+            self.buckets[bucket].push(idx);
+        }
+    }
+
+    fn bucket(&self, device: usize, block_id: usize) -> usize {
+        (block_id ^ (device >> 4)) % self.buckets.len()
+    }
+
+    fn find(&self, device: usize, block_id: usize) -> Option<usize> {
+        self.buckets[self.bucket(device, block_id)]
+            .iter()
+            .copied()
+            .find(|idx| {
+                let entry = &self.entries[*idx];
+                entry.device == device && entry.block_id == block_id
+            })
+    }
+
+    /// Choose an entry to evict, skipping recently used ones once.
+    fn victim(&mut self) -> Option<usize> {
+        for _ in 0..2 * self.entries.len() {
+            let idx = self.hand;
+            self.hand = (self.hand + 1) % self.entries.len();
+            let entry = &mut self.entries[idx];
+            // modified blocks must not reach the disk before the journal
+            if Arc::strong_count(&entry.block_cache) > 1
+                || entry.block_cache.lock().is_modified() {
+                continue;
+            }
+            if entry.referenced {
+                entry.referenced = false;
+                continue;
+            }
+            return Some(idx);
+        }
+        None
+    }
+
+    fn remove(&mut self, idx: usize) {
+        let bucket = self.bucket(self.entries[idx].device, self.entries[idx].block_id);
+        self.buckets[bucket].retain(|i| *i != idx);
+        // the last entry moves into the hole
+        let last = self.entries.len() - 1;
+        if idx != last {
+            let bucket = self.bucket(self.entries[last].device, self.entries[last].block_id);
+            for i in self.buckets[bucket].iter_mut().filter(|i| **i == last) {
+                *i = idx;
+            }
+        }
+        self.entries.swap_remove(idx);
+        if self.hand >= self.entries.len() {
+            self.hand = 0;
+        }
     }
 
     pub fn get_block_cache(
@@ -87,36 +256,59 @@ impl BlockCacheManager {
         block_id: usize,
         block_device: Arc<dyn BlockDevice>,
     ) -> Arc<Mutex<BlockCache>> {
-        if let Some(pair) = self.queue
-            .iter()
-            .find(|pair| pair.0 == block_id) {
-                Arc::clone(&pair.1)
-        } else {
-            // substitute
-            if self.queue.len() == BLOCK_CACHE_SIZE {
-                // from front to tail
-                if let Some((idx, _)) = self.queue
-                    .iter()
-                    .enumerate()
-                    .find(|(_, pair)| Arc::strong_count(&pair.1) == 1) {
-                    self.queue.drain(idx..=idx);
-                } else {
-                    panic!("Run out of BlockCache!");
-                }
+        let device = device_key(&block_device);
+        if let Some(idx) = self.find(device, block_id) {
+            let entry = &mut self.entries[idx];
+            entry.referenced = true;
+            return Arc::clone(&entry.block_cache);
+        }
+        // substitute
+        while self.entries.len() >= self.capacity {
+            match self.victim() {
+                Some(idx) => self.remove(idx),
+                None => break,
             }
-            // load block into mem and push back
-            let block_cache = Arc::new(Mutex::new(
-                BlockCache::new(block_id, Arc::clone(&block_device))
-            ));
-            self.queue.push_back((block_id, Arc::clone(&block_cache)));
-            block_cache
+        }
+        // load block into mem
+        let block_cache = Arc::new(Mutex::new(
+            BlockCache::new(block_id, Arc::clone(&block_device))
+        ));
+        let bucket = self.bucket(device, block_id);
+        self.buckets[bucket].push(self.entries.len());
+        self.entries.push(CacheEntry {
+            device,
+            block_id,
+            block_cache: Arc::clone(&block_cache),
+            referenced: false,
+        });
+        block_cache
+    }
+
+    /// Return block caches of `block_device` which hold modifications not
+    /// written back yet.
+    pub fn modified_block_caches(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<Arc<Mutex<BlockCache>>> {
+        let device = device_key(block_device);
+        self.entries
+            .iter()
+            .filter(|entry| entry.device == device && entry.block_cache.lock().is_modified())
+            .map(|entry| Arc::clone(&entry.block_cache))
+            .collect()
+    }
+
+    /// Write all modified blocks back.
+    pub fn sync_all(&self) {
+        for entry in self.entries.iter() {
+            entry.block_cache.lock().sync();
         }
     }
 }
 
 lazy_static! {
     pub static ref BLOCK_CACHE_MANAGER: Mutex<BlockCacheManager> = Mutex::new(
-        BlockCacheManager::new()
+        BlockCacheManager::new(BLOCK_CACHE_CAPACITY)
     );
 }
 
@@ -124,5 +316,25 @@ pub fn get_block_cache(
     block_id: usize,
     block_device: Arc<dyn BlockDevice>
 ) -> Arc<Mutex<BlockCache>> {
-    BLOCK_CACHE_MANAGER.lock().get_block_cache(block_id, block_device)
+    let block_cache = BLOCK_CACHE_MANAGER.lock().get_block_cache(block_id, Arc::clone(&block_device));
+    checksum::check(block_id, &block_device, &block_cache);
+    block_cache
+}
+
+pub fn modified_block_caches(
+    block_device: &Arc<dyn BlockDevice>
+) -> Vec<Arc<Mutex<BlockCache>>> {
+    BLOCK_CACHE_MANAGER.lock().modified_block_caches(block_device)
+}
+
+/// Write all modified blocks in the cache back to their devices.
+///
+/// Every filesystem operation commits its changes before it returns, so
+/// nothing of a transaction is in the cache between operations.
+pub fn block_cache_sync_all() {
+    BLOCK_CACHE_MANAGER.lock().sync_all()
+}
+
+pub fn set_block_cache_capacity(capacity: usize) {
+    BLOCK_CACHE_MANAGER.lock().set_capacity(capacity)
 }
\ No newline at end of file
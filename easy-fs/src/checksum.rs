@@ -0,0 +1,208 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::*;
+use spin::Mutex;
+use super::{
+    BlockDevice,
+    BLOCK_SZ,
+    get_block_cache,
+    block_cache::{BlockCache, device_key},
+};
+
+/// Checksums held by one block of the table.
+pub const CHECKSUMS_PER_BLOCK: usize = BLOCK_SZ / 4;
+
+type ChecksumBlock = [u32; CHECKSUMS_PER_BLOCK];
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+/// CRC-32C (Castagnoli) of `data`.
+pub fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte| {
+        CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Blocks of a table with a checksum for each of `total_blocks`.
+pub fn checksum_blocks_for(total_blocks: u32) -> u32 {
+    (total_blocks + CHECKSUMS_PER_BLOCK as u32 - 1) / CHECKSUMS_PER_BLOCK as u32
+}
+
+/// The checksum table of a mounted device. It starts at `start_block` and
+/// has an entry for every block before it, zero for blocks not checked:
+/// file data, free blocks and the journal.
+struct Table {
+    device: usize,
+    start_block: usize,
+    /// Mounts using the table.
+    users: usize,
+    /// Damaged blocks read since the last `take_errors`.
+    errors: usize,
+}
+
+lazy_static! {
+    static ref TABLES: Mutex<Vec<Table>> = Mutex::new(Vec::new());
+}
+
+/// Check blocks of `block_device` against the table at `start_block` from
+/// now on.
+pub fn register(block_device: &Arc<dyn BlockDevice>, start_block: usize) {
+    let device = device_key(block_device);
+    let mut tables = TABLES.lock();
+    match tables.iter_mut().find(|table| table.device == device) {
+        Some(table) => {
+            table.start_block = start_block;
+            table.users += 1;
+        }
+        None => tables.push(Table { device, start_block, users: 1, errors: 0 }),
+    }
+}
+
+pub fn unregister(block_device: &Arc<dyn BlockDevice>) {
+    let device = device_key(block_device);
+    let mut tables = TABLES.lock();
+    if let Some(pos) = tables.iter().position(|table| table.device == device) {
+        tables[pos].users -= 1;
+        if tables[pos].users == 0 {
+            tables.swap_remove(pos);
+        }
+    }
+}
+
+fn table_start(block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
+    let device = device_key(block_device);
+    TABLES.lock().iter().find(|table| table.device == device).map(|table| table.start_block)
+}
+
+fn raise_error(block_device: &Arc<dyn BlockDevice>) {
+    let device = device_key(block_device);
+    if let Some(table) = TABLES.lock().iter_mut().find(|table| table.device == device) {
+        table.errors += 1;
+    }
+}
+
+/// Return whether a damaged block was read since the last call.
+pub fn take_errors(block_device: &Arc<dyn BlockDevice>) -> bool {
+    let device = device_key(block_device);
+    TABLES.lock()
+        .iter_mut()
+        .find(|table| table.device == device)
+        .map_or(false, |table| core::mem::replace(&mut table.errors, 0) > 0)
+}
+
+/// Return the checksum recorded for `block_id`, zero if there is none.
+pub fn read_entry(start_block: usize, block_id: usize, block_device: &Arc<dyn BlockDevice>) -> u32 {
+    get_block_cache(start_block + block_id / CHECKSUMS_PER_BLOCK, Arc::clone(block_device))
+        .lock()
+        .read(0, |checksums: &ChecksumBlock| checksums[block_id % CHECKSUMS_PER_BLOCK])
+}
+
+/// Record `checksum` for `block_id`. The table block is only changed if the
+/// entry is different.
+pub fn write_entry(
+    start_block: usize,
+    block_id: usize,
+    checksum: u32,
+    block_device: &Arc<dyn BlockDevice>,
+) {
+    let block_cache = get_block_cache(
+        start_block + block_id / CHECKSUMS_PER_BLOCK,
+        Arc::clone(block_device),
+    );
+    let mut block_cache = block_cache.lock();
+    let pos = block_id % CHECKSUMS_PER_BLOCK;
+    if block_cache.read(0, |checksums: &ChecksumBlock| checksums[pos] != checksum) {
+        block_cache.modify(0, |checksums: &mut ChecksumBlock| checksums[pos] = checksum);
+    }
+}
+
+/// Return the blocks before the table at `start_block` which are `used` but
+/// do not match their checksums, reading them from the device itself.
+pub fn damaged_blocks(
+    start_block: usize,
+    block_device: &Arc<dyn BlockDevice>,
+    used: impl Fn(usize) -> bool,
+) -> Vec<usize> {
+    let mut data = [0u8; BLOCK_SZ];
+    (0..start_block)
+        .filter(|block_id| {
+            if !used(*block_id) {
+                return false;
+            }
+            let expected = read_entry(start_block, *block_id, block_device);
+            if expected == 0 {
+                return false;
+            }
+            block_device.read_block(*block_id, &mut data);
+            crc32c(&data) != expected
+        })
+        .collect()
+}
+
+/// Write a whole table at `start_block`, bypassing the journal.
+pub fn write_table(start_block: usize, checksums: &[u32], block_device: &Arc<dyn BlockDevice>) {
+    for (i, chunk) in checksums.chunks(CHECKSUMS_PER_BLOCK).enumerate() {
+        let block_cache = get_block_cache(start_block + i, Arc::clone(block_device));
+        let mut block_cache = block_cache.lock();
+        block_cache.modify(0, |table_block: &mut ChecksumBlock| {
+            table_block[..chunk.len()].copy_from_slice(chunk);
+            for checksum in table_block[chunk.len()..].iter_mut() {
+                *checksum = 0;
+            }
+        });
+        block_cache.sync();
+    }
+}
+
+/// Verify a block the first time it is handed out from the cache. A damaged
+/// block reads as zeros from then on, which no structure on disk takes for
+/// more than empty, and every access to it counts as an error.
+pub fn check(block_id: usize, block_device: &Arc<dyn BlockDevice>, block_cache: &Mutex<BlockCache>) {
+    let verified = {
+        let block_cache = block_cache.lock();
+        if block_cache.is_corrupt() {
+            drop(block_cache);
+            raise_error(block_device);
+            return;
+        }
+        block_cache.is_verified()
+    };
+    if verified {
+        return;
+    }
+    let start_block = match table_start(block_device) {
+        Some(start_block) => start_block,
+        None => return,
+    };
+    let mut block_cache = block_cache.lock();
+    // the table itself has no checksums, and changes not committed yet have
+    // none to compare with
+    if block_id >= start_block || block_cache.is_modified() {
+        block_cache.set_verified();
+        return;
+    }
+    let expected = read_entry(start_block, block_id, block_device);
+    if expected == 0 || block_cache.read(0, |data: &[u8; BLOCK_SZ]| crc32c(data)) == expected {
+        block_cache.set_verified();
+    } else {
+        block_cache.set_corrupt();
+        drop(block_cache);
+        raise_error(block_device);
+    }
+}
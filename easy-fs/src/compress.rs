@@ -0,0 +1,294 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use super::{
+    BlockAllocator,
+    BlockDevice,
+    DiskInode,
+    FsError,
+    Result,
+    BLOCK_SZ,
+    get_block_cache,
+    writable_block,
+    lz4,
+};
+
+/// Data blocks compressed together. A file is split into clusters of this
+/// many blocks, the last one may be shorter.
+///
+/// A cluster is stored compressed if that saves at least one block: its
+/// first blocks then hold the length of the compressed data as 4 bytes,
+/// little endian, followed by that data, and the rest are holes. Its bit in
+/// the cluster map of the file is set then. All other clusters are stored
+/// as they are, or as holes entirely if they are all zeros, which leaves
+/// their bit as it was. A cluster changes as a whole.
+pub const CLUSTER_BLOCKS: u32 = 8;
+const CLUSTER_SZ: usize = CLUSTER_BLOCKS as usize * BLOCK_SZ;
+const HEADER_SZ: usize = 4;
+
+/// Clusters one block of the cluster map has bits for.
+const CLUSTERS_PER_MAP_BLOCK: usize = BLOCK_SZ * 8;
+/// Blocks of the cluster map, listed by `DiskInode::clusters`.
+const MAP_BLOCKS: usize = BLOCK_SZ / 4;
+
+type MapBlock = [u8; BLOCK_SZ];
+type MapBlockList = [u32; MAP_BLOCKS];
+
+/// Largest size of a compressed file, whose clusters the map has bits for.
+pub const MAX_COMPRESSED_SIZE: u32 = (MAP_BLOCKS * CLUSTERS_PER_MAP_BLOCK * CLUSTER_SZ) as u32;
+
+/// Return the first data block of `cluster` and how many there are.
+pub(crate) fn cluster_blocks(disk_inode: &DiskInode, cluster: u32) -> (u32, u32) {
+    let first = cluster * CLUSTER_BLOCKS;
+    (first, (disk_inode.data_blocks() - first).min(CLUSTER_BLOCKS))
+}
+
+/// Return the bytes of the file in `cluster`.
+pub(crate) fn cluster_len(disk_inode: &DiskInode, cluster: u32) -> usize {
+    (disk_inode.size as usize - cluster as usize * CLUSTER_SZ).min(CLUSTER_SZ)
+}
+
+/// Return the block of the cluster map with the bit of `cluster`, zero if
+/// there is none yet.
+fn map_block(disk_inode: &DiskInode, cluster: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
+    if disk_inode.clusters == 0 {
+        return 0;
+    }
+    get_block_cache(disk_inode.clusters as usize, Arc::clone(block_device))
+        .lock()
+        .read(0, |map_blocks: &MapBlockList| map_blocks[cluster as usize / CLUSTERS_PER_MAP_BLOCK])
+}
+
+/// Whether `cluster` is stored compressed, if it holds data at all.
+fn is_packed(disk_inode: &DiskInode, cluster: u32, block_device: &Arc<dyn BlockDevice>) -> bool {
+    let map_block = map_block(disk_inode, cluster, block_device);
+    if map_block == 0 {
+        return false;
+    }
+    let bit = cluster as usize % CLUSTERS_PER_MAP_BLOCK;
+    get_block_cache(map_block as usize, Arc::clone(block_device))
+        .lock()
+        .read(0, |map: &MapBlock| map[bit / 8] & 1 << (bit % 8) != 0)
+}
+
+/// Record in the cluster map whether `cluster` is stored compressed, taking
+/// the blocks of the map this needs.
+fn set_packed(
+    disk_inode: &mut DiskInode,
+    cluster: u32,
+    packed: bool,
+    allocator: &mut dyn BlockAllocator,
+    block_device: &Arc<dyn BlockDevice>,
+) -> Result<()> {
+    if is_packed(disk_inode, cluster, block_device) == packed {
+        return Ok(());
+    }
+    disk_inode.clusters = writable_block(disk_inode.clusters, allocator, block_device)?;
+    let list = get_block_cache(disk_inode.clusters as usize, Arc::clone(block_device));
+    let pos = cluster as usize / CLUSTERS_PER_MAP_BLOCK;
+    let map_block = list.lock().read(0, |map_blocks: &MapBlockList| map_blocks[pos]);
+    let new_map_block = writable_block(map_block, allocator, block_device)?;
+    if new_map_block != map_block {
+        list.lock().modify(0, |map_blocks: &mut MapBlockList| map_blocks[pos] = new_map_block);
+    }
+    let bit = cluster as usize % CLUSTERS_PER_MAP_BLOCK;
+    get_block_cache(new_map_block as usize, Arc::clone(block_device))
+        .lock()
+        .modify(0, |map: &mut MapBlock| {
+            if packed {
+                map[bit / 8] |= 1 << (bit % 8);
+            } else {
+                map[bit / 8] &= !(1 << (bit % 8));
+            }
+        });
+    Ok(())
+}
+
+/// Free the cluster map of an inode which is freed.
+pub(crate) fn free_map(
+    disk_inode: &mut DiskInode,
+    allocator: &mut dyn BlockAllocator,
+    block_device: &Arc<dyn BlockDevice>,
+) {
+    if disk_inode.clusters == 0 {
+        return;
+    }
+    let map_blocks = get_block_cache(disk_inode.clusters as usize, Arc::clone(block_device))
+        .lock()
+        .read(0, |map_blocks: &MapBlockList| *map_blocks);
+    for block_id in map_blocks.iter().filter(|block_id| **block_id != 0) {
+        allocator.dealloc(*block_id);
+    }
+    allocator.dealloc(disk_inode.clusters);
+    disk_inode.clusters = 0;
+}
+
+/// Read `cluster` as it is stored, which has to lie within the file.
+fn read_cluster(
+    disk_inode: &DiskInode,
+    cluster: u32,
+    block_device: &Arc<dyn BlockDevice>,
+) -> Result<Vec<u8>> {
+    let (first, blocks) = cluster_blocks(disk_inode, cluster);
+    let offset = first as usize * BLOCK_SZ;
+    let mut data = vec![0u8; cluster_len(disk_inode, cluster)];
+    if disk_inode.seek_block(first, true, block_device) >= first + blocks {
+        return Ok(data);
+    }
+    if !is_packed(disk_inode, cluster, block_device) {
+        disk_inode.read_at(offset, &mut data, block_device);
+        return Ok(data);
+    }
+    let mut header = [0u8; HEADER_SZ];
+    disk_inode.read_at(offset, &mut header, block_device);
+    let len = u32::from_le_bytes(header) as usize;
+    if HEADER_SZ + len > (blocks - 1) as usize * BLOCK_SZ {
+        return Err(FsError::Corrupt);
+    }
+    let mut packed = vec![0u8; len];
+    disk_inode.read_at(offset + HEADER_SZ, &mut packed, block_device);
+    // a stream cut short may still end where a sequence does
+    match lz4::decompress(&packed, &mut data) {
+        Some(len) if len == data.len() => Ok(data),
+        _ => Err(FsError::Corrupt),
+    }
+}
+
+/// Store `data` as the whole of `cluster`.
+///
+/// The blocks it needs, its data blocks and those of the cluster map, are
+/// allocated before anything is written, and those it no longer needs are
+/// freed afterwards, so that the cluster still reads as before if
+/// allocating fails.
+fn write_cluster(
+    disk_inode: &mut DiskInode,
+    cluster: u32,
+    data: &[u8],
+    allocator: &mut dyn BlockAllocator,
+    block_device: &Arc<dyn BlockDevice>,
+) -> Result<()> {
+    let (first, blocks) = cluster_blocks(disk_inode, cluster);
+    let end = first + blocks;
+    if data.iter().all(|byte| *byte == 0) {
+        return disk_inode.dealloc_blocks(first, end, allocator, block_device);
+    }
+    let compressed = lz4::compress(data);
+    let packed = HEADER_SZ + compressed.len() <= (blocks - 1) as usize * BLOCK_SZ;
+    let stored = if packed {
+        let mut stored = (compressed.len() as u32).to_le_bytes().to_vec();
+        stored.extend_from_slice(&compressed);
+        stored
+    } else {
+        data.to_vec()
+    };
+    let stored_end = first + ((stored.len() + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
+    disk_inode.alloc_blocks(first, stored_end, allocator, block_device)?;
+    set_packed(disk_inode, cluster, packed, allocator, block_device)?;
+    disk_inode.write_at(first as usize * BLOCK_SZ, &stored, block_device);
+    disk_inode.dealloc_blocks(stored_end, end, allocator, block_device)
+}
+
+/// Read the data of a compressed inode, like `DiskInode::read_at`.
+pub fn read_at(
+    disk_inode: &DiskInode,
+    offset: usize,
+    buf: &mut [u8],
+    block_device: &Arc<dyn BlockDevice>,
+) -> Result<usize> {
+    let end = (offset + buf.len()).min(disk_inode.size as usize);
+    let mut pos = offset;
+    while pos < end {
+        let cluster = (pos / CLUSTER_SZ) as u32;
+        let data = read_cluster(disk_inode, cluster, block_device)?;
+        let start = cluster as usize * CLUSTER_SZ;
+        let len = (start + data.len()).min(end) - pos;
+        buf[pos - offset..pos - offset + len].copy_from_slice(&data[pos - start..pos - start + len]);
+        pos += len;
+    }
+    Ok(end.saturating_sub(offset))
+}
+
+/// Write to a compressed inode, growing it if `buf` reaches past the end.
+/// Every cluster touched is compressed anew.
+pub fn write_at(
+    disk_inode: &mut DiskInode,
+    offset: usize,
+    buf: &[u8],
+    allocator: &mut dyn BlockAllocator,
+    block_device: &Arc<dyn BlockDevice>,
+) -> Result<usize> {
+    let end = offset + buf.len();
+    if end > disk_inode.size as usize {
+        resize(disk_inode, end as u32, allocator, block_device)?;
+    }
+    let mut pos = offset;
+    while pos < end {
+        let cluster = (pos / CLUSTER_SZ) as u32;
+        let mut data = read_cluster(disk_inode, cluster, block_device)?;
+        let start = cluster as usize * CLUSTER_SZ;
+        let len = (start + data.len()).min(end) - pos;
+        data[pos - start..pos - start + len].copy_from_slice(&buf[pos - offset..pos - offset + len]);
+        write_cluster(disk_inode, cluster, &data, allocator, block_device)?;
+        pos += len;
+    }
+    Ok(buf.len())
+}
+
+/// Change the size of a compressed inode. The cluster holding the end is
+/// stored anew, as how it is stored depends on its length.
+pub fn resize(
+    disk_inode: &mut DiskInode,
+    new_size: u32,
+    allocator: &mut dyn BlockAllocator,
+    block_device: &Arc<dyn BlockDevice>,
+) -> Result<()> {
+    // the partial cluster at the end, either the old or the new one
+    let end = disk_inode.size.min(new_size) as usize;
+    let cluster = (end / CLUSTER_SZ) as u32;
+    let partial = if end % CLUSTER_SZ != 0 {
+        let mut data = read_cluster(disk_inode, cluster, block_device)?;
+        data.truncate(end % CLUSTER_SZ);
+        Some(data)
+    } else {
+        None
+    };
+    if new_size < disk_inode.size {
+        for block_id in disk_inode.decrease_size(new_size, block_device) {
+            allocator.dealloc(block_id);
+        }
+    } else {
+        disk_inode.increase_size(new_size);
+    }
+    match partial {
+        Some(mut data) => {
+            data.resize(cluster_len(disk_inode, cluster), 0);
+            write_cluster(disk_inode, cluster, &data, allocator, block_device)
+        }
+        None => Ok(()),
+    }
+}
+
+/// `DiskInode::seek_block` for a compressed inode, where a cluster with any
+/// block allocated holds data throughout.
+pub fn seek_block(
+    disk_inode: &DiskInode,
+    inner_id: u32,
+    data: bool,
+    block_device: &Arc<dyn BlockDevice>,
+) -> u32 {
+    let end = disk_inode.data_blocks();
+    let mut inner_id = inner_id;
+    while inner_id < end {
+        let (first, blocks) = cluster_blocks(disk_inode, inner_id / CLUSTER_BLOCKS);
+        let next = first + blocks;
+        if (disk_inode.seek_block(first, true, block_device) < next) == data {
+            return inner_id;
+        }
+        inner_id = if data {
+            disk_inode.seek_block(next, true, block_device)
+        } else {
+            next
+        };
+    }
+    end
+}
@@ -0,0 +1,322 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use super::{
+    EasyFileSystem,
+    SuperBlock,
+    DiskInode,
+    FsError,
+    Result,
+    BLOCK_SZ,
+    aead,
+    get_block_cache,
+    writable_block,
+};
+use aead::{KEY_SZ, NONCE_SZ, TAG_SZ};
+
+/// The tag of a data block and the nonce it was sealed with.
+///
+/// An encrypted file is sealed block by block with ChaCha20-Poly1305 under
+/// a key derived from its key and its random salt, as its blocks go through
+/// the block cache, see `BlockCache::read_sealed`. Every block written takes
+/// a fresh nonce out of those reserved by `EasyFileSystem::reserve_nonces`,
+/// and is sealed with its index in the file as additional data, so that
+/// blocks cannot be swapped unnoticed. The nonces are unique within an
+/// image only, the salt keeps files on other images sealed with the same
+/// key apart. The tags are kept in tag blocks, which the blocks listed by
+/// `DiskInode::tags` list in turn. A hole has no tag.
+///
+/// Data is written through while its tag is journaled, so a block being
+/// written when the system crashes fails to open afterwards.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TagEntry {
+    tag: [u8; TAG_SZ],
+    nonce: [u32; 2],
+}
+
+const TAGS_PER_BLOCK: usize = BLOCK_SZ / core::mem::size_of::<TagEntry>();
+const TAG_LIST_LEN: usize = BLOCK_SZ / 4;
+/// Levels of list blocks above the tag blocks.
+pub(crate) const TAG_LIST_DEPTH: u32 = 2;
+
+type TagBlock = [TagEntry; TAGS_PER_BLOCK];
+type TagBlockList = [u32; TAG_LIST_LEN];
+type DataBlock = [u8; BLOCK_SZ];
+
+/// Largest size of an encrypted file, whose tags the tag blocks hold.
+pub const MAX_ENCRYPTED_SIZE: u32 = (TAG_LIST_LEN * TAG_LIST_LEN * TAGS_PER_BLOCK * BLOCK_SZ) as u32;
+
+/// Nonces reserved at a time, see `EasyFileSystem::reserve_nonces`.
+const NONCE_BATCH: u64 = 1024;
+
+fn nonce_bytes(nonce: [u32; 2]) -> [u8; NONCE_SZ] {
+    let mut bytes = [0u8; NONCE_SZ];
+    bytes[..4].copy_from_slice(&nonce[0].to_le_bytes());
+    bytes[4..8].copy_from_slice(&nonce[1].to_le_bytes());
+    bytes
+}
+
+/// Positions of the entries leading to the tag of data block `inner_id`,
+/// in the lists and in its tag block.
+fn tag_path(inner_id: u32) -> ([usize; TAG_LIST_DEPTH as usize], usize) {
+    let index = inner_id as usize / TAGS_PER_BLOCK;
+    ([index / TAG_LIST_LEN, index % TAG_LIST_LEN], inner_id as usize % TAGS_PER_BLOCK)
+}
+
+impl EasyFileSystem {
+    /// Register `key` and return its identifier, which files encrypted with
+    /// it keep, see `Inode::use_encryption`. Keys are held in memory only,
+    /// the files of a key which is not registered cannot be read.
+    pub fn add_key(&mut self, key: &[u8; KEY_SZ]) -> u64 {
+        let key_id = aead::key_id(key);
+        if !self.has_key(key_id) {
+            self.keys.push((key_id, *key));
+        }
+        key_id
+    }
+
+    /// Forget the key `key_id`.
+    pub fn remove_key(&mut self, key_id: u64) -> Result<()> {
+        let count = self.keys.len();
+        self.keys.retain(|(id, _)| *id != key_id);
+        if self.keys.len() == count {
+            return Err(FsError::NotFound);
+        }
+        Ok(())
+    }
+
+    pub fn has_key(&self, key_id: u64) -> bool {
+        self.keys.iter().any(|(id, _)| *id == key_id)
+    }
+
+    /// Return the key `disk_inode` is sealed with, derived from its salt.
+    fn file_key(&self, disk_inode: &DiskInode) -> Result<[u8; KEY_SZ]> {
+        self.keys
+            .iter()
+            .find(|(id, _)| *id == disk_inode.key_id())
+            .map(|(_, key)| aead::derive_key(key, &disk_inode.salt()))
+            .ok_or(FsError::NoKey)
+    }
+
+    /// Make sure `count` nonces are reserved for `next_nonce`, raising
+    /// `SuperBlock::nonce_counter` by a batch in a transaction of its own if
+    /// need be. It has to be called with no changes pending.
+    ///
+    /// That way the counter is on disk before any data sealed with the
+    /// nonces, which is written through, and a crash does not lead to one
+    /// being used twice. Nonces reserved but not used are skipped. The
+    /// counter is not part of a snapshot, so restoring one does not turn it
+    /// back.
+    pub(crate) fn reserve_nonces(&mut self, count: u64) -> Result<()> {
+        if self.nonces.end - self.nonces.start >= count {
+            return Ok(());
+        }
+        let batch = count.max(NONCE_BATCH);
+        let start = get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                let [low, high] = super_block.nonce_counter;
+                let start = low as u64 | (high as u64) << 32;
+                let end = start + batch;
+                super_block.nonce_counter = [end as u32, (end >> 32) as u32];
+                start
+            });
+        self.commit()?;
+        self.nonces = start..start + batch;
+        Ok(())
+    }
+
+    /// Take a nonce out of those reserved, which was never used on this
+    /// image.
+    fn next_nonce(&mut self) -> [u32; 2] {
+        let nonce = self.nonces.next().expect("nonces must be reserved before sealing");
+        [nonce as u32, (nonce >> 32) as u32]
+    }
+
+    /// Return the tag block holding the tag of data block `inner_id`, zero
+    /// if there is none.
+    fn find_tag_block(&self, disk_inode: &DiskInode, inner_id: u32) -> u32 {
+        let (lists, _) = tag_path(inner_id);
+        let mut block_id = disk_inode.tags;
+        for pos in lists.iter() {
+            if block_id == 0 {
+                return 0;
+            }
+            block_id = get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .read(0, |list: &TagBlockList| list[*pos]);
+        }
+        block_id
+    }
+
+    /// Return the tag block of data block `inner_id`, ready to be changed in
+    /// place together with the lists leading to it.
+    fn tag_block(&mut self, disk_inode: &mut DiskInode, inner_id: u32) -> Result<u32> {
+        let (lists, _) = tag_path(inner_id);
+        let block_device = Arc::clone(&self.block_device);
+        disk_inode.tags = writable_block(disk_inode.tags, self, &block_device)?;
+        let mut list = disk_inode.tags;
+        for pos in lists.iter() {
+            let block_cache = get_block_cache(list as usize, Arc::clone(&block_device));
+            let block_id = block_cache.lock().read(0, |list: &TagBlockList| list[*pos]);
+            let new_block_id = writable_block(block_id, self, &block_device)?;
+            if new_block_id != block_id {
+                block_cache.lock().modify(0, |list: &mut TagBlockList| list[*pos] = new_block_id);
+            }
+            list = new_block_id;
+        }
+        Ok(list)
+    }
+
+    /// Free the tag blocks of an inode which is freed.
+    pub(crate) fn free_tags(&mut self, disk_inode: &mut DiskInode) {
+        if disk_inode.tags == 0 {
+            return;
+        }
+        let block_device = Arc::clone(&self.block_device);
+        let read_list = |block_id: u32| {
+            get_block_cache(block_id as usize, Arc::clone(&block_device))
+                .lock()
+                .read(0, |list: &TagBlockList| *list)
+        };
+        let lists = read_list(disk_inode.tags);
+        for list in lists.iter().filter(|block_id| **block_id != 0) {
+            for block_id in read_list(*list).iter().filter(|block_id| **block_id != 0) {
+                self.dealloc_data(*block_id);
+            }
+            self.dealloc_data(*list);
+        }
+        self.dealloc_data(disk_inode.tags);
+        disk_inode.tags = 0;
+    }
+
+    /// Read and open data block `inner_id`, which has to lie within the
+    /// file. A hole reads as zeros.
+    fn read_block(&self, disk_inode: &DiskInode, inner_id: u32, key: &[u8; KEY_SZ]) -> Result<DataBlock> {
+        let block_id = disk_inode.get_block_id(inner_id, &self.block_device);
+        if block_id == 0 {
+            return Ok([0u8; BLOCK_SZ]);
+        }
+        let tag_block = self.find_tag_block(disk_inode, inner_id);
+        if tag_block == 0 {
+            return Err(FsError::Corrupt);
+        }
+        let entry = get_block_cache(tag_block as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |tags: &TagBlock| tags[tag_path(inner_id).1]);
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read_sealed(key, &nonce_bytes(entry.nonce), &inner_id.to_le_bytes(), &entry.tag)
+            .ok_or(FsError::Corrupt)
+    }
+
+    /// Seal `data` into the allocated data block `inner_id` with a fresh
+    /// nonce and write it through, like `DiskInode::write_at` does. Its tag
+    /// goes into `tag_block`, which has to be writable, see `tag_block`.
+    fn write_block(
+        &mut self,
+        disk_inode: &DiskInode,
+        inner_id: u32,
+        tag_block: u32,
+        data: &DataBlock,
+        key: &[u8; KEY_SZ],
+    ) {
+        let nonce = self.next_nonce();
+        let block_id = disk_inode.get_block_id(inner_id, &self.block_device);
+        let block_cache = get_block_cache(block_id as usize, Arc::clone(&self.block_device));
+        let mut block_cache = block_cache.lock();
+        let tag = block_cache.write_sealed(key, &nonce_bytes(nonce), &inner_id.to_le_bytes(), data);
+        block_cache.sync();
+        drop(block_cache);
+        get_block_cache(tag_block as usize, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |tags: &mut TagBlock| tags[tag_path(inner_id).1] = TagEntry { tag, nonce });
+    }
+
+    /// Read the data of an encrypted inode, like `DiskInode::read_at`.
+    pub(crate) fn read_encrypted(
+        &self,
+        disk_inode: &DiskInode,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let key = self.file_key(disk_inode)?;
+        let end = (offset + buf.len()).min(disk_inode.size as usize);
+        let mut pos = offset;
+        while pos < end {
+            let inner_id = (pos / BLOCK_SZ) as u32;
+            let data = self.read_block(disk_inode, inner_id, &key)?;
+            let len = ((inner_id as usize + 1) * BLOCK_SZ).min(end) - pos;
+            buf[pos - offset..pos - offset + len].copy_from_slice(&data[pos % BLOCK_SZ..pos % BLOCK_SZ + len]);
+            pos += len;
+        }
+        Ok(end.saturating_sub(offset))
+    }
+
+    /// Write to an encrypted inode, growing it if `buf` reaches past the
+    /// end. Every block touched is sealed anew, taking a nonce each.
+    ///
+    /// The blocks only partly overwritten are opened, and the tag blocks
+    /// and data blocks allocated, before anything is written, so that the
+    /// file still reads as before if that fails.
+    pub(crate) fn write_encrypted(
+        &mut self,
+        disk_inode: &mut DiskInode,
+        offset: usize,
+        buf: &[u8],
+    ) -> Result<usize> {
+        let key = self.file_key(disk_inode)?;
+        let end = offset + buf.len();
+        let first = (offset / BLOCK_SZ) as u32;
+        let last = ((end + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
+        let mut partial: Vec<(u32, DataBlock)> = Vec::new();
+        for inner_id in [first, last.saturating_sub(1)].iter() {
+            let start = *inner_id as usize * BLOCK_SZ;
+            if (start < offset || start + BLOCK_SZ > end)
+                && start < disk_inode.size as usize
+                && partial.iter().all(|(id, _)| id != inner_id)
+            {
+                partial.push((*inner_id, self.read_block(disk_inode, *inner_id, &key)?));
+            }
+        }
+        let mut tag_blocks = Vec::new();
+        for inner_id in first..last {
+            tag_blocks.push(self.tag_block(disk_inode, inner_id)?);
+        }
+        if end > disk_inode.size as usize {
+            disk_inode.increase_size(end as u32);
+        }
+        let block_device = Arc::clone(&self.block_device);
+        disk_inode.alloc_blocks(first, last, self, &block_device)?;
+        for (inner_id, tag_block) in (first..last).zip(tag_blocks) {
+            let start = inner_id as usize * BLOCK_SZ;
+            let mut data = partial
+                .iter()
+                .find(|(id, _)| *id == inner_id)
+                .map_or([0u8; BLOCK_SZ], |(_, data)| *data);
+            let (from, to) = (offset.max(start), end.min(start + BLOCK_SZ));
+            data[from - start..to - start].copy_from_slice(&buf[from - offset..to - offset]);
+            self.write_block(disk_inode, inner_id, tag_block, &data, &key);
+        }
+        Ok(buf.len())
+    }
+
+    /// Shrink an encrypted inode to `new_size`. The bytes past the new end
+    /// in its last block are cleared, for which the block is sealed anew
+    /// with a nonce, so that they do not show up again if the file grows.
+    pub(crate) fn shrink_encrypted(&mut self, disk_inode: &mut DiskInode, new_size: u32) -> Result<()> {
+        let inner_id = new_size / BLOCK_SZ as u32;
+        let tail = new_size as usize % BLOCK_SZ;
+        if tail != 0 && disk_inode.get_block_id(inner_id, &self.block_device) != 0 {
+            let key = self.file_key(disk_inode)?;
+            let mut data = self.read_block(disk_inode, inner_id, &key)?;
+            data[tail..].iter_mut().for_each(|byte| *byte = 0);
+            let tag_block = self.tag_block(disk_inode, inner_id)?;
+            self.write_block(disk_inode, inner_id, tag_block, &data, &key);
+        }
+        for block_id in disk_inode.decrease_size(new_size, &self.block_device) {
+            self.dealloc_data(block_id);
+        }
+        Ok(())
+    }
+}
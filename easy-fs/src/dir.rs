@@ -0,0 +1,558 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use super::{
+    BlockDevice,
+    DiskInode,
+    DirEntry,
+    DirRecord,
+    FsError,
+    Result,
+    BLOCK_SZ,
+    DIRENT_SZ,
+    DIR_RECORD_SZ,
+    NAME_LENGTH_LIMIT,
+    LONG_NAME_LENGTH_LIMIT,
+    INODE_FLAG_DIR_INDEX,
+};
+
+/// How the entries of a directory are laid out in its data.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DirFormat {
+    /// One `DirEntry` per 32-byte slot, as on images without long names.
+    Fixed,
+    /// `DirRecord`s of varying length packed into blocks.
+    Variable,
+    /// Like `Variable`, but a directory outgrowing its first block gets a
+    /// hash index.
+    ///
+    /// Block 0 of an indexed directory is then the root of the index. It maps
+    /// the lowest name hash of each child to the child's block, either leaf
+    /// blocks of records or, once the root is full, one more level of index
+    /// blocks. All records of a leaf hash into its range, so a lookup reads
+    /// one block per level and the leaf. Index blocks start with a free
+    /// record covering the whole block, scans over all blocks skip them.
+    Hashed,
+}
+
+impl DirFormat {
+    pub fn name_limit(self) -> usize {
+        match self {
+            DirFormat::Fixed => NAME_LENGTH_LIMIT,
+            DirFormat::Variable | DirFormat::Hashed => LONG_NAME_LENGTH_LIMIT,
+        }
+    }
+}
+
+/// Grows a directory by at least the given number of bytes, returning the
+/// old size. The new space is not initialized.
+pub type Grow<'a> = dyn FnMut(&mut DiskInode, usize) -> Result<usize> + 'a;
+
+type DirBlock = [u8; BLOCK_SZ];
+
+/// Read the record at `pos` of a directory block and check that it stays
+/// inside the block.
+fn record_at(block: &DirBlock, pos: usize) -> Result<DirRecord> {
+    if pos + DIR_RECORD_SZ > BLOCK_SZ {
+        return Err(FsError::Corrupt);
+    }
+    let record = DirRecord::from_bytes(&block[pos..pos + DIR_RECORD_SZ]);
+    let rec_len = record.rec_len as usize;
+    if rec_len % 4 != 0
+        || rec_len < DirRecord::size_for(record.name_len as usize)
+        || pos + rec_len > BLOCK_SZ {
+        return Err(FsError::Corrupt);
+    }
+    Ok(record)
+}
+
+/// A block without records in use.
+fn empty_block() -> DirBlock {
+    let mut block = [0u8; BLOCK_SZ];
+    DirRecord::new(0, BLOCK_SZ, 0).write_to(&mut block);
+    block
+}
+
+/// Call `f` with the position, inode number and name of each record in use
+/// in a block, until it returns `Some`.
+fn block_scan<V>(
+    block: &DirBlock,
+    mut f: impl FnMut(usize, u32, &[u8]) -> Option<V>,
+) -> Result<Option<V>> {
+    let mut pos = 0;
+    while pos < BLOCK_SZ {
+        let record = record_at(block, pos)?;
+        if record.name_len > 0 {
+            let name_start = pos + DIR_RECORD_SZ;
+            let name = &block[name_start..name_start + record.name_len as usize];
+            if let Some(v) = f(pos, record.inode_number, name) {
+                return Ok(Some(v));
+            }
+        }
+        pos += record.rec_len as usize;
+    }
+    Ok(None)
+}
+
+/// Put a record into free space of a block. Return `false` if it does not fit.
+fn block_insert(block: &mut DirBlock, name: &[u8], inode_number: u32) -> Result<bool> {
+    let needed = DirRecord::size_for(name.len());
+    let mut pos = 0;
+    while pos < BLOCK_SZ {
+        let record = record_at(block, pos)?;
+        let rec_len = record.rec_len as usize;
+        // a free record is taken whole, a used one gives away the space
+        // after its name
+        let used = match record.name_len {
+            0 => 0,
+            name_len => DirRecord::size_for(name_len as usize),
+        };
+        if rec_len - used < needed {
+            pos += rec_len;
+            continue;
+        }
+        if used > 0 {
+            DirRecord::new(record.inode_number, used, record.name_len as usize)
+                .write_to(&mut block[pos..]);
+        }
+        let new_pos = pos + used;
+        DirRecord::new(inode_number, rec_len - used, name.len())
+            .write_to(&mut block[new_pos..]);
+        let name_start = new_pos + DIR_RECORD_SZ;
+        block[name_start..name_start + name.len()].copy_from_slice(name);
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Read block `block_no` of the directory, which has to exist.
+fn read_dir_block(
+    disk_inode: &DiskInode,
+    block_device: &Arc<dyn BlockDevice>,
+    block_no: u32,
+) -> Result<DirBlock> {
+    let block_start = block_no as usize * BLOCK_SZ;
+    if block_start + BLOCK_SZ > disk_inode.size as usize {
+        return Err(FsError::Corrupt);
+    }
+    let mut block = [0u8; BLOCK_SZ];
+    disk_inode.read_at(block_start, &mut block, block_device);
+    Ok(block)
+}
+
+fn write_dir_block(
+    disk_inode: &mut DiskInode,
+    block_device: &Arc<dyn BlockDevice>,
+    block_no: u32,
+    block: &DirBlock,
+) {
+    disk_inode.write_at(block_no as usize * BLOCK_SZ, block, block_device);
+}
+
+/// Hash of a name in the index, FNV-1a.
+fn name_hash(name: &[u8]) -> u32 {
+    name.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Where the index header starts, behind the free record.
+const INDEX_HEADER_POS: usize = DIR_RECORD_SZ;
+const INDEX_ENTRIES_POS: usize = INDEX_HEADER_POS + 8;
+/// Entries of one index block, each a hash and a block number.
+const INDEX_CAPACITY: usize = (BLOCK_SZ - INDEX_ENTRIES_POS) / 8;
+
+/// An index block. Its header holds the number of entries and, in the root,
+/// the number of index levels below it, 0 or 1.
+struct Index {
+    depth: u8,
+    /// Lowest hash and block number of each child, sorted by hash.
+    entries: Vec<(u32, u32)>,
+}
+
+impl Index {
+    fn from_block(block: &DirBlock) -> Result<Self> {
+        let count = u16::from_le_bytes([block[INDEX_HEADER_POS], block[INDEX_HEADER_POS + 1]]) as usize;
+        let depth = block[INDEX_HEADER_POS + 2];
+        if count == 0 || count > INDEX_CAPACITY || depth > 1 {
+            return Err(FsError::Corrupt);
+        }
+        let word = |pos: usize| {
+            u32::from_le_bytes([block[pos], block[pos + 1], block[pos + 2], block[pos + 3]])
+        };
+        let entries: Vec<_> = (0..count)
+            .map(|i| INDEX_ENTRIES_POS + i * 8)
+            .map(|pos| (word(pos), word(pos + 4)))
+            .collect();
+        if entries.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err(FsError::Corrupt);
+        }
+        Ok(Self { depth, entries })
+    }
+
+    fn to_block(&self) -> DirBlock {
+        let mut block = empty_block();
+        block[INDEX_HEADER_POS..INDEX_HEADER_POS + 2]
+            .copy_from_slice(&(self.entries.len() as u16).to_le_bytes());
+        block[INDEX_HEADER_POS + 2] = self.depth;
+        for (i, (hash, block_no)) in self.entries.iter().enumerate() {
+            let pos = INDEX_ENTRIES_POS + i * 8;
+            block[pos..pos + 4].copy_from_slice(&hash.to_le_bytes());
+            block[pos + 4..pos + 8].copy_from_slice(&block_no.to_le_bytes());
+        }
+        block
+    }
+
+    /// Return the position in `entries` of the child covering `hash`.
+    fn child(&self, hash: u32) -> usize {
+        self.entries.iter().rposition(|(lowest, _)| *lowest <= hash).unwrap_or(0)
+    }
+
+    fn is_full(&self) -> bool {
+        self.entries.len() == INDEX_CAPACITY
+    }
+}
+
+/// The index blocks from the root down to the leaf for a hash, each with its
+/// block number and the position of the entry that was followed.
+struct IndexPath {
+    nodes: Vec<(u32, Index, usize)>,
+    leaf: u32,
+}
+
+fn index_path(
+    disk_inode: &DiskInode,
+    block_device: &Arc<dyn BlockDevice>,
+    hash: u32,
+) -> Result<IndexPath> {
+    let root = Index::from_block(&read_dir_block(disk_inode, block_device, 0)?)?;
+    let mut levels = root.depth as usize + 1;
+    let mut nodes = Vec::new();
+    let (mut block_no, mut index) = (0, root);
+    loop {
+        let pos = index.child(hash);
+        let child = index.entries[pos].1;
+        if child == 0 {
+            return Err(FsError::Corrupt);
+        }
+        nodes.push((block_no, index, pos));
+        levels -= 1;
+        if levels == 0 {
+            return Ok(IndexPath { nodes, leaf: child });
+        }
+        block_no = child;
+        index = Index::from_block(&read_dir_block(disk_inode, block_device, child)?)?;
+        if index.depth != 0 {
+            return Err(FsError::Corrupt);
+        }
+    }
+}
+
+fn is_indexed(disk_inode: &DiskInode, format: DirFormat) -> bool {
+    format == DirFormat::Hashed && disk_inode.flags & INODE_FLAG_DIR_INDEX != 0
+}
+
+/// Append an unused block and return its number.
+fn grow_block(disk_inode: &mut DiskInode, grow: &mut Grow) -> Result<u32> {
+    Ok((grow(disk_inode, BLOCK_SZ)? / BLOCK_SZ) as u32)
+}
+
+/// Call `f` with the offset, inode number and name of each entry in turn,
+/// until it returns `Some`.
+pub fn scan<V>(
+    disk_inode: &DiskInode,
+    format: DirFormat,
+    block_device: &Arc<dyn BlockDevice>,
+    mut f: impl FnMut(usize, u32, &[u8]) -> Option<V>,
+) -> Result<Option<V>> {
+    let size = disk_inode.size as usize;
+    match format {
+        DirFormat::Fixed => {
+            let mut dirent = DirEntry::empty();
+            for offset in (0..size / DIRENT_SZ).map(|i| i * DIRENT_SZ) {
+                disk_inode.read_at(offset, dirent.as_bytes_mut(), block_device);
+                if dirent.is_empty() {
+                    continue;
+                }
+                if let Some(v) = f(offset, dirent.inode_number(), dirent.name()) {
+                    return Ok(Some(v));
+                }
+            }
+        }
+        DirFormat::Variable | DirFormat::Hashed => {
+            for block_no in 0..(size / BLOCK_SZ) as u32 {
+                let block = read_dir_block(disk_inode, block_device, block_no)?;
+                let block_start = block_no as usize * BLOCK_SZ;
+                let found = block_scan(&block, |pos, inode_number, name| {
+                    f(block_start + pos, inode_number, name)
+                })?;
+                if found.is_some() {
+                    return Ok(found);
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Return the offset and inode number of the entry called `name`.
+pub fn find(
+    disk_inode: &DiskInode,
+    format: DirFormat,
+    block_device: &Arc<dyn BlockDevice>,
+    name: &[u8],
+) -> Result<Option<(usize, u32)>> {
+    let matches = |offset: usize, inode_number: u32, entry_name: &[u8]| {
+        if entry_name == name {
+            Some((offset, inode_number))
+        } else {
+            None
+        }
+    };
+    if is_indexed(disk_inode, format) {
+        let leaf = index_path(disk_inode, block_device, name_hash(name))?.leaf;
+        let block = read_dir_block(disk_inode, block_device, leaf)?;
+        let block_start = leaf as usize * BLOCK_SZ;
+        return block_scan(&block, |pos, inode_number, entry_name| {
+            matches(block_start + pos, inode_number, entry_name)
+        });
+    }
+    scan(disk_inode, format, block_device, matches)
+}
+
+/// Write an entry into free space of the directory, growing it through
+/// `grow` if there is none. The directory stays consistent if that fails.
+pub fn insert(
+    disk_inode: &mut DiskInode,
+    format: DirFormat,
+    block_device: &Arc<dyn BlockDevice>,
+    name: &str,
+    inode_number: u32,
+    grow: &mut Grow,
+) -> Result<()> {
+    let size = disk_inode.size as usize;
+    if format == DirFormat::Fixed {
+        let mut dirent = DirEntry::empty();
+        let slot = (0..size / DIRENT_SZ).find(|i| {
+            disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), block_device);
+            dirent.is_empty()
+        });
+        let offset = match slot {
+            Some(slot) => slot * DIRENT_SZ,
+            None => grow(disk_inode, DIRENT_SZ)?,
+        };
+        disk_inode.write_at(offset, DirEntry::new(name, inode_number).as_bytes(), block_device);
+        return Ok(());
+    }
+    if is_indexed(disk_inode, format) {
+        return indexed_insert(disk_inode, block_device, name.as_bytes(), inode_number, grow);
+    }
+    for block_no in 0..(size / BLOCK_SZ) as u32 {
+        let mut block = read_dir_block(disk_inode, block_device, block_no)?;
+        if block_insert(&mut block, name.as_bytes(), inode_number)? {
+            write_dir_block(disk_inode, block_device, block_no, &block);
+            return Ok(());
+        }
+    }
+    if format == DirFormat::Hashed && size == BLOCK_SZ {
+        // the first block is full, from now on it is the root of the index
+        // and its records move to the only leaf
+        let leaf = grow_block(disk_inode, grow)?;
+        let block = read_dir_block(disk_inode, block_device, 0)?;
+        write_dir_block(disk_inode, block_device, leaf, &block);
+        let root = Index { depth: 0, entries: vec![(0, leaf)] };
+        write_dir_block(disk_inode, block_device, 0, &root.to_block());
+        disk_inode.flags |= INODE_FLAG_DIR_INDEX;
+        return indexed_insert(disk_inode, block_device, name.as_bytes(), inode_number, grow);
+    }
+    let block_no = grow_block(disk_inode, grow)?;
+    let mut block = empty_block();
+    let inserted = block_insert(&mut block, name.as_bytes(), inode_number)?;
+    assert!(inserted);
+    write_dir_block(disk_inode, block_device, block_no, &block);
+    Ok(())
+}
+
+/// Insert into the leaf `name` hashes to, splitting full blocks on the way.
+fn indexed_insert(
+    disk_inode: &mut DiskInode,
+    block_device: &Arc<dyn BlockDevice>,
+    name: &[u8],
+    inode_number: u32,
+    grow: &mut Grow,
+) -> Result<()> {
+    let hash = name_hash(name);
+    // every round either inserts or splits a block; each step writes a
+    // consistent directory, so running out of space leaves no damage
+    loop {
+        let mut path = index_path(disk_inode, block_device, hash)?;
+        let mut leaf = read_dir_block(disk_inode, block_device, path.leaf)?;
+        if block_insert(&mut leaf, name, inode_number)? {
+            write_dir_block(disk_inode, block_device, path.leaf, &leaf);
+            return Ok(());
+        }
+        let (parent_no, mut parent, pos) = path.nodes.pop().unwrap();
+        if !parent.is_full() {
+            let needed = DirRecord::size_for(name.len());
+            let (split_hash, low, high) = split_leaf(&leaf, hash, needed)?;
+            let high_no = grow_block(disk_inode, grow)?;
+            write_dir_block(disk_inode, block_device, high_no, &high);
+            write_dir_block(disk_inode, block_device, path.leaf, &low);
+            parent.entries.insert(pos + 1, (split_hash, high_no));
+            write_dir_block(disk_inode, block_device, parent_no, &parent.to_block());
+        } else if let Some((root_no, mut root, root_pos)) = path.nodes.pop() {
+            // split the full index block below the root
+            if root.is_full() {
+                return Err(FsError::NoSpace);
+            }
+            let high = Index { depth: 0, entries: parent.entries.split_off(INDEX_CAPACITY / 2) };
+            let high_no = grow_block(disk_inode, grow)?;
+            write_dir_block(disk_inode, block_device, high_no, &high.to_block());
+            write_dir_block(disk_inode, block_device, parent_no, &parent.to_block());
+            root.entries.insert(root_pos + 1, (high.entries[0].0, high_no));
+            write_dir_block(disk_inode, block_device, root_no, &root.to_block());
+        } else {
+            // the root is full, its entries move one level down
+            let child_no = grow_block(disk_inode, grow)?;
+            let child = Index { depth: 0, entries: parent.entries };
+            write_dir_block(disk_inode, block_device, child_no, &child.to_block());
+            let root = Index { depth: 1, entries: vec![(0, child_no)] };
+            write_dir_block(disk_inode, block_device, parent_no, &root.to_block());
+        }
+    }
+}
+
+/// Split a full leaf to make room for a record of `needed` bytes whose name
+/// has `hash`, balancing the bytes on both sides. Return the lowest hash of
+/// the upper part and blocks with the records below and above it.
+fn split_leaf(leaf: &DirBlock, hash: u32, needed: usize) -> Result<(u32, DirBlock, DirBlock)> {
+    let mut sizes = vec![(hash, needed)];
+    block_scan(leaf, |_, _, name| -> Option<()> {
+        sizes.push((name_hash(name), DirRecord::size_for(name.len())));
+        None
+    })?;
+    sizes.sort_unstable();
+    let total: usize = sizes.iter().map(|(_, size)| size).sum();
+    let mut below = 0;
+    let mut best: Option<(usize, u32)> = None;
+    for pair in sizes.windows(2) {
+        below += pair[0].1;
+        // records with equal hashes have to stay together
+        if pair[0].0 == pair[1].0 {
+            continue;
+        }
+        let imbalance = below.max(total - below);
+        if best.map_or(true, |(least, _)| imbalance < least) {
+            best = Some((imbalance, pair[1].0));
+        }
+    }
+    let split_hash = best.ok_or(FsError::NoSpace)?.1;
+    let (mut low, mut high) = (empty_block(), empty_block());
+    block_scan(leaf, |_, inode_number, name| -> Option<()> {
+        let half = if name_hash(name) < split_hash { &mut low } else { &mut high };
+        // either half held less than the whole block before
+        let inserted = block_insert(half, name, inode_number);
+        assert_eq!(inserted, Ok(true));
+        None
+    })?;
+    Ok((split_hash, low, high))
+}
+
+/// Free the entry at `offset`.
+pub fn remove(
+    disk_inode: &mut DiskInode,
+    format: DirFormat,
+    block_device: &Arc<dyn BlockDevice>,
+    offset: usize,
+) -> Result<()> {
+    if format == DirFormat::Fixed {
+        disk_inode.write_at(offset, DirEntry::empty().as_bytes(), block_device);
+        return Ok(());
+    }
+    let block_no = (offset / BLOCK_SZ) as u32;
+    let mut block = read_dir_block(disk_inode, block_device, block_no)?;
+    let target = offset % BLOCK_SZ;
+    let mut prev = None;
+    let mut pos = 0;
+    while pos < target {
+        prev = Some(pos);
+        pos += record_at(&block, pos)?.rec_len as usize;
+    }
+    if pos != target {
+        return Err(FsError::Corrupt);
+    }
+    let record = record_at(&block, pos)?;
+    match prev {
+        // the record before takes the space over
+        Some(prev) => {
+            let prev_record = record_at(&block, prev)?;
+            DirRecord::new(
+                prev_record.inode_number,
+                (prev_record.rec_len + record.rec_len) as usize,
+                prev_record.name_len as usize,
+            ).write_to(&mut block[prev..]);
+        }
+        None => DirRecord::new(0, record.rec_len as usize, 0).write_to(&mut block[pos..]),
+    }
+    write_dir_block(disk_inode, block_device, block_no, &block);
+    Ok(())
+}
+
+/// Make the entry at `offset` refer to `inode_number`.
+pub fn set_inode(
+    disk_inode: &mut DiskInode,
+    format: DirFormat,
+    block_device: &Arc<dyn BlockDevice>,
+    offset: usize,
+    inode_number: u32,
+) {
+    match format {
+        DirFormat::Fixed => {
+            let mut dirent = DirEntry::empty();
+            disk_inode.read_at(offset, dirent.as_bytes_mut(), block_device);
+            dirent.set_inode_number(inode_number);
+            disk_inode.write_at(offset, dirent.as_bytes(), block_device);
+        }
+        DirFormat::Variable | DirFormat::Hashed => {
+            disk_inode.write_at(offset, &inode_number.to_le_bytes(), block_device);
+        }
+    }
+}
+
+/// Return the number of entries.
+pub fn count(
+    disk_inode: &DiskInode,
+    format: DirFormat,
+    block_device: &Arc<dyn BlockDevice>,
+) -> Result<usize> {
+    let mut count = 0;
+    scan(disk_inode, format, block_device, |_, _, _| -> Option<()> {
+        count += 1;
+        None
+    })?;
+    Ok(count)
+}
+
+/// Check that every entry of an indexed directory can be found through the
+/// index. Return `false` if not.
+pub fn check_index(
+    disk_inode: &DiskInode,
+    format: DirFormat,
+    block_device: &Arc<dyn BlockDevice>,
+) -> Result<bool> {
+    if !is_indexed(disk_inode, format) {
+        return Ok(true);
+    }
+    let mut names = Vec::new();
+    scan(disk_inode, format, block_device, |offset, _, name| -> Option<()> {
+        names.push((offset, name.to_vec()));
+        None
+    })?;
+    for (offset, name) in names.iter() {
+        match find(disk_inode, format, block_device, name)? {
+            Some((found, _)) if found == *offset => {}
+            _ => return Ok(false),
+        }
+    }
+    Ok(true)
+}
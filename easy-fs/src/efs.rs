@@ -1,4 +1,6 @@
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
 use spin::Mutex;
 use super::{
     BlockDevice,
@@ -6,8 +8,21 @@ use super::{
     SuperBlock,
     DiskInode,
     DiskInodeType,
+    BlockAllocator,
+    DirFormat,
     Inode,
+    Journal,
+    FsError,
+    Result,
+    FEATURE_LONG_NAMES,
+    FEATURE_DIR_INDEX,
+    FEATURE_INLINE_DATA,
+    FEATURE_METADATA_CSUM,
     get_block_cache,
+    modified_block_caches,
+    checksum,
+    dir,
+    aead::KEY_SZ,
 };
 use crate::BLOCK_SZ;
 
@@ -15,8 +30,43 @@ pub struct EasyFileSystem {
     pub block_device: Arc<dyn BlockDevice>,
     pub inode_bitmap: Bitmap,
     pub data_bitmap: Bitmap,
+    journal: Journal,
     inode_area_start_block: u32,
     data_area_start_block: u32,
+    data_area_blocks: u32,
+    dir_format: DirFormat,
+    inline_data: bool,
+    /// Allocated inodes and data blocks, counted from the bitmaps at mount.
+    used_inodes: u32,
+    used_blocks: u32,
+    /// First block of the checksum table if the image has one.
+    checksums: Option<usize>,
+    clock: fn() -> u32,
+    /// Keys of encrypted files by identifier, see `add_key`.
+    pub(crate) keys: Vec<(u64, [u8; KEY_SZ])>,
+    /// Nonces reserved but not used yet, see `reserve_nonces`.
+    pub(crate) nonces: Range<u64>,
+    /// Uid allocations are charged to until the next commit, see `quota`.
+    pub(crate) owner: Option<u32>,
+}
+
+/// Space and inode usage as reported by `EasyFileSystem::stat`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsStat {
+    pub block_size: u32,
+    /// Blocks of the data area.
+    pub blocks: u32,
+    pub free_blocks: u32,
+    pub inodes: u32,
+    pub free_inodes: u32,
+}
+
+/// Blocks reserved for the journal by `create`.
+const JOURNAL_BLOCKS: u32 = 32;
+
+/// Timestamps stay zero until a clock is set.
+fn no_clock() -> u32 {
+    0
 }
 
 type DataBlock = [u8; BLOCK_SZ];
@@ -26,53 +76,82 @@ impl EasyFileSystem {
         block_device: Arc<dyn BlockDevice>,
         total_blocks: u32,
         inode_bitmap_blocks: u32,
-    ) -> Arc<Mutex<Self>> {
+    ) -> Result<Arc<Mutex<Self>>> {
         // calculate block size of areas & create bitmaps
-        let inode_bitmap = Bitmap::new(1, inode_bitmap_blocks as usize);
+        let inode_bitmap = Bitmap::new(1 + JOURNAL_BLOCKS as usize, inode_bitmap_blocks as usize);
         let inode_num = inode_bitmap.maximum();
         let inode_area_blocks =
             ((inode_num * core::mem::size_of::<DiskInode>() + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
         let inode_total_blocks = inode_bitmap_blocks + inode_area_blocks;
-        let data_total_blocks = total_blocks - 1 - inode_total_blocks;
+        let checksum_blocks = checksum::checksum_blocks_for(total_blocks);
+        let checksum_start = total_blocks.saturating_sub(checksum_blocks);
+        let data_total_blocks = checksum_start
+            .checked_sub(1 + JOURNAL_BLOCKS + inode_total_blocks)
+            .filter(|blocks| *blocks >= 2)
+            .ok_or(FsError::NoSpace)?;
         let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
         let data_area_blocks = data_total_blocks - data_bitmap_blocks;
         let data_bitmap = Bitmap::new(
-            (1 + inode_bitmap_blocks + inode_area_blocks) as usize,
+            (1 + JOURNAL_BLOCKS + inode_bitmap_blocks + inode_area_blocks) as usize,
             data_bitmap_blocks as usize,
         );
         let mut efs = Self {
             block_device: Arc::clone(&block_device),
             inode_bitmap,
             data_bitmap,
-            inode_area_start_block: 1 + inode_bitmap_blocks,
-            data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
+            journal: Journal::new(1, JOURNAL_BLOCKS as usize),
+            inode_area_start_block: 1 + JOURNAL_BLOCKS + inode_bitmap_blocks,
+            data_area_start_block: 1 + JOURNAL_BLOCKS + inode_total_blocks + data_bitmap_blocks,
+            data_area_blocks,
+            dir_format: DirFormat::Hashed,
+            inline_data: true,
+            used_inodes: 0,
+            used_blocks: 0,
+            checksums: Some(checksum_start as usize),
+            clock: no_clock,
+            keys: Vec::new(),
+            nonces: 0..0,
+            owner: None,
         };
-        // clear all blocks
+        // clear all blocks, which needs no journal
         for i in 0..total_blocks {
-            get_block_cache(
+            let block_cache = get_block_cache(
                 i as usize, 
                 Arc::clone(&block_device)
-            )
-            .lock()
-            .modify(0, |data_block: &mut DataBlock| {
+            );
+            let mut block_cache = block_cache.lock();
+            block_cache.modify(0, |data_block: &mut DataBlock| {
                 for byte in data_block.iter_mut() { *byte = 0; }
             });
+            block_cache.sync();
+        }
+        // the bitmaps and inodes start out as zeros, and so does the table
+        let zero_checksum = checksum::crc32c(&[0u8; BLOCK_SZ]);
+        for block_id in 1 + JOURNAL_BLOCKS..efs.data_area_start_block {
+            let block_id = block_id as usize;
+            checksum::write_entry(checksum_start as usize, block_id, zero_checksum, &block_device);
         }
+        for block_id in checksum_start..total_blocks {
+            get_block_cache(block_id as usize, Arc::clone(&block_device)).lock().sync();
+        }
+        checksum::register(&block_device, checksum_start as usize);
         // initialize SuperBlock
         get_block_cache(0, Arc::clone(&block_device))
         .lock()
         .modify(0, |super_block: &mut SuperBlock| {
             super_block.initialize(
                 total_blocks,
+                JOURNAL_BLOCKS,
                 inode_bitmap_blocks,
                 inode_area_blocks,
                 data_bitmap_blocks,
                 data_area_blocks,
             );
+            super_block.checksum_blocks = checksum_blocks;
         });
-        // write back immediately
         // create a inode for root node "/"
-        assert_eq!(efs.alloc_inode(), 0);
+        assert_eq!(efs.alloc_inode()?, 0);
+        // both "." and ".." of root refer to root itself
         let (root_inode_block_id, root_inode_offset) = efs.get_disk_inode_pos(0);
         get_block_cache(
             root_inode_block_id as usize,
@@ -80,34 +159,104 @@ impl EasyFileSystem {
         )
         .lock()
         .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
-            disk_inode.initialize(DiskInodeType::Directory);
-        });
-        Arc::new(Mutex::new(efs))
+            disk_inode.initialize(DiskInodeType::Directory, efs.now());
+            let format = efs.dir_format;
+            for name in [".", ".."].iter() {
+                dir::insert(disk_inode, format, &block_device, name, 0, &mut |disk_inode, bytes| {
+                    let size = disk_inode.size as usize;
+                    disk_inode.increase_size((size + bytes) as u32);
+                    let first = (size / BLOCK_SZ) as u32;
+                    disk_inode.alloc_blocks(first, disk_inode.data_blocks(), &mut efs, &block_device)?;
+                    Ok(size)
+                })?;
+            }
+            Ok(())
+        })?;
+        // write back immediately
+        efs.commit()?;
+        Ok(Arc::new(Mutex::new(efs)))
     }
 
-    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> Result<Arc<Mutex<Self>>> {
+        let journal_blocks = get_block_cache(0, Arc::clone(&block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                if super_block.is_valid() {
+                    Ok(super_block.journal_blocks)
+                } else {
+                    Err(FsError::Corrupt)
+                }
+            })?;
+        let journal = Journal::new(1, journal_blocks as usize);
+        journal.replay(&block_device);
         // read SuperBlock
-        get_block_cache(0, Arc::clone(&block_device))
+        let mut efs = get_block_cache(0, Arc::clone(&block_device))
             .lock()
             .read(0, |super_block: &SuperBlock| {
-                assert!(super_block.is_valid(), "Error loading EFS!");
                 let inode_total_blocks =
                     super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
-                let efs = Self {
+                let first_block = 1 + super_block.journal_blocks;
+                Self {
                     block_device,
                     inode_bitmap: Bitmap::new(
-                        1,
+                        first_block as usize,
                         super_block.inode_bitmap_blocks as usize
                     ),
                     data_bitmap: Bitmap::new(
-                        (1 + inode_total_blocks) as usize,
+                        (first_block + inode_total_blocks) as usize,
                         super_block.data_bitmap_blocks as usize,
                     ),
-                    inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
-                    data_area_start_block: 1 + inode_total_blocks + super_block.data_bitmap_blocks,
-                };
-                Arc::new(Mutex::new(efs))
-            })        
+                    journal,
+                    inode_area_start_block: first_block + super_block.inode_bitmap_blocks,
+                    data_area_start_block: first_block + inode_total_blocks + super_block.data_bitmap_blocks,
+                    data_area_blocks: super_block.data_area_blocks,
+                    dir_format: if super_block.features & FEATURE_LONG_NAMES == 0 {
+                        DirFormat::Fixed
+                    } else if super_block.features & FEATURE_DIR_INDEX == 0 {
+                        DirFormat::Variable
+                    } else {
+                        DirFormat::Hashed
+                    },
+                    inline_data: super_block.features & FEATURE_INLINE_DATA != 0,
+                    used_inodes: 0,
+                    used_blocks: 0,
+                    checksums: if super_block.features & FEATURE_METADATA_CSUM != 0 {
+                        Some((super_block.total_blocks - super_block.checksum_blocks) as usize)
+                    } else {
+                        None
+                    },
+                    clock: no_clock,
+                    keys: Vec::new(),
+                    nonces: 0..0,
+                    owner: None,
+                }
+            });
+        if let Some(checksum_start) = efs.checksums {
+            checksum::register(&efs.block_device, checksum_start);
+            // the super block was read before there was a table to check it
+            get_block_cache(0, Arc::clone(&efs.block_device));
+        }
+        efs.count_used();
+        if efs.checksums.is_some() && checksum::take_errors(&efs.block_device) {
+            return Err(FsError::Corrupt);
+        }
+        // restoring or deleting a snapshot was interrupted
+        efs.finish_snapshot_work()?;
+        let orphan = get_block_cache(0, Arc::clone(&efs.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.orphan);
+        let block_device = Arc::clone(&efs.block_device);
+        let efs = Arc::new(Mutex::new(efs));
+        if orphan != 0 {
+            // freeing an unlinked inode was interrupted
+            Inode::new(orphan, Arc::clone(&efs), block_device).release_orphan()?;
+        }
+        Ok(efs)
+    }
+
+    pub(crate) fn count_used(&mut self) {
+        self.used_inodes = self.inode_bitmap.count(&self.block_device) as u32;
+        self.used_blocks = self.data_bitmap.count(&self.block_device) as u32;
     }
 
     pub fn root_inode(efs: &Arc<Mutex<Self>>) -> Inode {
@@ -119,6 +268,110 @@ impl EasyFileSystem {
         )
     }
 
+    /// Set the source of inode timestamps, in seconds.
+    pub fn set_clock(&mut self, clock: fn() -> u32) {
+        self.clock = clock;
+    }
+
+    /// How directories on this filesystem store their entries.
+    pub fn dir_format(&self) -> DirFormat {
+        self.dir_format
+    }
+
+    /// Whether new files and symbolic links start with their data inline.
+    pub fn inline_data(&self) -> bool {
+        self.inline_data
+    }
+
+    /// Report how much of the data area and the inodes is in use.
+    pub fn stat(&self) -> FsStat {
+        let inodes = self.inode_bitmap.maximum() as u32;
+        FsStat {
+            block_size: BLOCK_SZ as u32,
+            blocks: self.data_area_blocks,
+            free_blocks: self.data_area_blocks - self.used_blocks,
+            inodes,
+            free_inodes: inodes - self.used_inodes,
+        }
+    }
+
+    pub fn now(&self) -> u32 {
+        (self.clock)()
+    }
+
+    /// Commit all changes since the last commit as one transaction.
+    ///
+    /// If a damaged block was read meanwhile, the changes may rest on what it
+    /// seemed to hold. They are dropped instead, and this fails with `Corrupt`.
+    /// They are dropped as well if they do not fit into the journal, failing
+    /// with `JournalFull`.
+    pub fn commit(&mut self) -> Result<()> {
+        if let Some(checksum_start) = self.checksums {
+            if checksum::take_errors(&self.block_device) {
+                self.discard_changes();
+                return Err(FsError::Corrupt);
+            }
+            for block_cache in modified_block_caches(&self.block_device) {
+                let block_cache = block_cache.lock();
+                let block_id = block_cache.block_id();
+                let crc = block_cache.read(0, |data: &DataBlock| checksum::crc32c(data));
+                drop(block_cache);
+                if block_id >= checksum_start || !self.is_block_used(block_id) {
+                    continue;
+                }
+                checksum::write_entry(checksum_start, block_id, crc, &self.block_device);
+            }
+        }
+        if let Err(err) = self.journal.commit(&self.block_device) {
+            self.discard_changes();
+            return Err(err);
+        }
+        self.owner = None;
+        Ok(())
+    }
+
+    /// Return the most blocks one transaction may change.
+    pub(crate) fn transaction_capacity(&self) -> usize {
+        self.journal.capacity()
+    }
+
+    /// Record `inode_id` as the orphan being freed, or none for zero.
+    pub(crate) fn set_orphan(&self, inode_id: u32) {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| super_block.orphan = inode_id);
+    }
+
+    /// Drop all changes since the last commit.
+    pub(crate) fn discard_changes(&mut self) {
+        let block_caches = modified_block_caches(&self.block_device);
+        if !block_caches.is_empty() {
+            for block_cache in block_caches {
+                block_cache.lock().discard();
+            }
+            self.count_used();
+        }
+        self.owner = None;
+    }
+
+    /// Forget the checksum of a block which may hold file data from now on.
+    /// Free blocks keep theirs, which is not looked at, so that freeing many
+    /// blocks does not touch the table.
+    fn clear_checksum(&self, block_id: u32) {
+        if let Some(checksum_start) = self.checksums {
+            checksum::write_entry(checksum_start, block_id as usize, 0, &self.block_device);
+        }
+    }
+
+    /// Whether `block_id` is outside the data area or allocated in it.
+    pub(crate) fn is_block_used(&self, block_id: usize) -> bool {
+        block_id < self.data_area_start_block as usize
+            || self.data_bitmap.is_allocated(
+                &self.block_device,
+                block_id - self.data_area_start_block as usize,
+            )
+    }
+
     pub fn get_disk_inode_pos(&self, inode_id: u32) -> (u32, usize) {
         let inode_size = core::mem::size_of::<DiskInode>();
         let inodes_per_block = (BLOCK_SZ / inode_size) as u32;
@@ -130,28 +383,119 @@ impl EasyFileSystem {
         self.data_area_start_block + data_block_id
     }
 
-    pub fn alloc_inode(&mut self) -> u32 {
-        self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
+    pub fn alloc_inode(&mut self) -> Result<u32> {
+        if self.quota_room()?.1 == 0 {
+            return Err(FsError::QuotaExceeded);
+        }
+        let inode_id = self.inode_bitmap.alloc(&self.block_device).ok_or(FsError::NoSpace)?;
+        self.used_inodes += 1;
+        self.charge(0, 1);
+        Ok(inode_id as u32)
     }
 
-    /// Return a block ID not ID in the data area.
-    pub fn alloc_data(&mut self) -> u32 {
-        self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
+    pub fn dealloc_inode(&mut self, inode_id: u32) {
+        self.inode_bitmap.dealloc(&self.block_device, inode_id as usize);
+        self.used_inodes -= 1;
+        self.uncharge(0, 1);
     }
 
-    pub fn dealloc_data(&mut self, block_id: u32) {
-        get_block_cache(
+    /// Return a block ID not ID in the data area.
+    ///
+    /// The block is cleared to zero on disk right away, it is free there
+    /// until the allocation commits.
+    pub fn alloc_data(&mut self) -> Result<u32> {
+        if self.quota_room()?.0 == 0 {
+            return Err(FsError::QuotaExceeded);
+        }
+        let bit = self.data_bitmap.alloc(&self.block_device).ok_or(FsError::NoSpace)?;
+        // the last bitmap block may have bits past the end of the area
+        if bit >= self.data_area_blocks as usize {
+            self.data_bitmap.dealloc(&self.block_device, bit);
+            return Err(FsError::NoSpace);
+        }
+        self.used_blocks += 1;
+        self.charge(1, 0);
+        let block_id = bit as u32 + self.data_area_start_block;
+        self.clear_checksum(block_id);
+        let block_cache = get_block_cache(
             block_id as usize,
             Arc::clone(&self.block_device)
-        )
-        .lock()
-        .modify(0, |data_block: &mut DataBlock| {
+        );
+        let mut block_cache = block_cache.lock();
+        block_cache.modify(0, |data_block: &mut DataBlock| {
             data_block.iter_mut().for_each(|p| { *p = 0; })
         });
+        block_cache.sync();
+        Ok(block_id)
+    }
+
+    /// Allocate up to `len` data blocks in a row, starting at `goal` if it is
+    /// free, and return the first one and how many there are. They are
+    /// cleared like those from `alloc_data`, and no more are taken than the
+    /// quota of the owner leaves room for.
+    pub fn alloc_data_run(&mut self, goal: Option<u32>, len: u32) -> Result<(u32, u32)> {
+        let len = match self.quota_room()?.0 {
+            0 => return Err(FsError::QuotaExceeded),
+            room => len.min(room),
+        };
+        let goal = goal
+            .filter(|goal| *goal >= self.data_area_start_block)
+            .map(|goal| (goal - self.data_area_start_block) as usize);
+        let (bit, found) = self.data_bitmap
+            .alloc_run(&self.block_device, goal, len as usize, self.data_area_blocks as usize)
+            .ok_or(FsError::NoSpace)?;
+        self.used_blocks += found as u32;
+        self.charge(found as u32, 0);
+        let first = bit as u32 + self.data_area_start_block;
+        for block_id in first..first + found as u32 {
+            self.clear_checksum(block_id);
+            let block_cache = get_block_cache(
+                block_id as usize,
+                Arc::clone(&self.block_device)
+            );
+            let mut block_cache = block_cache.lock();
+            block_cache.modify(0, |data_block: &mut DataBlock| {
+                data_block.iter_mut().for_each(|p| { *p = 0; })
+            });
+            block_cache.sync();
+        }
+        Ok((first, found as u32))
+    }
+
+    /// Free a data block, unless a snapshot still holds it. It stops counting
+    /// against the quota of the owner either way.
+    pub fn dealloc_data(&mut self, block_id: u32) {
+        self.uncharge(1, 0);
+        if self.is_held(block_id) {
+            return;
+        }
         self.data_bitmap.dealloc(
             &self.block_device,
             (block_id - self.data_area_start_block) as usize
-        )
+        );
+        self.used_blocks -= 1;
     }
 
-}
\ No newline at end of file
+}
+
+impl Drop for EasyFileSystem {
+    fn drop(&mut self) {
+        if self.checksums.is_some() {
+            checksum::unregister(&self.block_device);
+        }
+    }
+}
+
+impl BlockAllocator for EasyFileSystem {
+    fn alloc_run(&mut self, goal: Option<u32>, len: u32) -> Result<(u32, u32)> {
+        self.alloc_data_run(goal, len)
+    }
+
+    fn dealloc(&mut self, block_id: u32) {
+        self.dealloc_data(block_id)
+    }
+
+    fn is_shared(&self, block_id: u32) -> bool {
+        self.is_held(block_id)
+    }
+}
@@ -0,0 +1,37 @@
+/// Why an easy-fs operation failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsError {
+    /// No free inode or data block is left.
+    NoSpace,
+    NotFound,
+    Exists,
+    /// A name is longer than the directory entries can hold.
+    NameTooLong,
+    /// The structures on disk are inconsistent.
+    Corrupt,
+    NotDir,
+    IsDir,
+    NotEmpty,
+    /// The operation does not apply, e.g. removing "." or reading a regular
+    /// file as a symbolic link.
+    Invalid,
+    /// The inodes involved are on different filesystems.
+    CrossDevice,
+    /// Too many symbolic links were followed while resolving a path.
+    Loop,
+    /// A file would grow beyond what its inode can map.
+    TooLarge,
+    /// An offset lies past the end of the file, or no data follows it.
+    OutOfRange,
+    /// The inode has no extended attribute of that name.
+    NoAttribute,
+    /// The key an encrypted file is sealed with is not registered.
+    NoKey,
+    /// The owner of the inode would exceed its quota.
+    QuotaExceeded,
+    /// An operation changed more metadata blocks than the journal holds.
+    /// Its changes were dropped.
+    JournalFull,
+}
+
+pub type Result<T> = core::result::Result<T, FsError>;
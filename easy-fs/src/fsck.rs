@@ -0,0 +1,344 @@
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{self, Display, Formatter};
+use super::{
+    BlockDevice,
+    EasyFileSystem,
+    SuperBlock,
+    DiskInode,
+    BLOCK_SZ,
+    FEATURE_METADATA_CSUM,
+    get_block_cache,
+    checksum,
+    dir,
+};
+
+/// An inconsistency found by `fsck`.
+pub enum Problem {
+    /// Wrong magic or version, nothing else is checked.
+    BadSuperBlock,
+    /// The areas described by the super block do not fit together.
+    BadLayout,
+    /// The root inode is not an allocated directory.
+    BadRoot,
+    /// The entries of a directory cannot be read past some point.
+    BadDirectory(u32),
+    /// A directory entry refers to an inode which is not allocated.
+    DanglingEntry { dir: u32, name: String, inode_id: u32 },
+    /// An allocated inode no directory entry refers to.
+    OrphanInode(u32),
+    /// The link count of an inode differs from the entries referring to it.
+    WrongLinkCount { inode_id: u32, nlink: u32, found: u32 },
+    /// An inode refers to a block outside the data area.
+    BadBlock { inode_id: u32, block_id: u32 },
+    /// An inode refers to a block which is held already, by another inode
+    /// or earlier by itself.
+    DuplicateBlock { inode_id: u32, block_id: u32 },
+    /// A block in use is free in the data bitmap.
+    FreeBlockInUse(u32),
+    /// A block allocated in the data bitmap is not used.
+    LeakedBlock(u32),
+    /// A metadata block does not match its checksum. Nothing else is checked
+    /// if there is one.
+    BadChecksum(u32),
+    /// The blocks and inodes charged to a uid differ from what it owns.
+    WrongQuota { uid: u32, used: (u32, u32), found: (u32, u32) },
+}
+
+impl Problem {
+    /// Whether `fsck` fixes it in repair mode.
+    pub fn repairable(&self) -> bool {
+        matches!(
+            self,
+            Problem::DanglingEntry { .. }
+                | Problem::WrongLinkCount { .. }
+                | Problem::FreeBlockInUse(_)
+                | Problem::LeakedBlock(_)
+                | Problem::WrongQuota { .. }
+        )
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::BadSuperBlock => write!(f, "bad super block magic or version"),
+            Problem::BadLayout => write!(f, "areas in the super block do not fit together"),
+            Problem::BadRoot => write!(f, "root inode is not an allocated directory"),
+            Problem::BadDirectory(inode_id) => write!(
+                f, "directory {} has malformed entries", inode_id
+            ),
+            Problem::DanglingEntry { dir, name, inode_id } => write!(
+                f, "entry {:?} in directory {} refers to free inode {}", name, dir, inode_id
+            ),
+            Problem::OrphanInode(inode_id) => write!(
+                f, "inode {} is allocated but not in any directory", inode_id
+            ),
+            Problem::WrongLinkCount { inode_id, nlink, found } => write!(
+                f, "inode {} has link count {}, should be {}", inode_id, nlink, found
+            ),
+            Problem::BadBlock { inode_id, block_id } => write!(
+                f, "inode {} refers to block {} outside the data area", inode_id, block_id
+            ),
+            Problem::DuplicateBlock { inode_id, block_id } => write!(
+                f, "inode {} refers to block {} which is already in use", inode_id, block_id
+            ),
+            Problem::FreeBlockInUse(block_id) => write!(
+                f, "block {} is in use but free in the bitmap", block_id
+            ),
+            Problem::LeakedBlock(block_id) => write!(
+                f, "block {} is allocated but not in use", block_id
+            ),
+            Problem::BadChecksum(block_id) => write!(
+                f, "block {} does not match its checksum", block_id
+            ),
+            Problem::WrongQuota { uid, used, found } => write!(
+                f, "uid {} is charged {} blocks and {} inodes, should be {} and {}",
+                uid, used.0, used.1, found.0, found.1
+            ),
+        }
+    }
+}
+
+/// Whether the areas described by the super block fit together.
+fn layout_is_valid(super_block: &SuperBlock) -> bool {
+    let inode_bits = super_block.inode_bitmap_blocks as usize * BLOCK_SZ * 8;
+    let inodes = super_block.inode_area_blocks as usize * BLOCK_SZ
+        / core::mem::size_of::<DiskInode>();
+    let data_bits = super_block.data_bitmap_blocks as usize * BLOCK_SZ * 8;
+    let used_blocks = 1u64
+        + super_block.journal_blocks as u64
+        + super_block.inode_bitmap_blocks as u64
+        + super_block.inode_area_blocks as u64
+        + super_block.data_bitmap_blocks as u64
+        + super_block.data_area_blocks as u64
+        + super_block.checksum_blocks as u64;
+    super_block.journal_blocks >= 2
+        && inode_bits > 0
+        && inodes >= inode_bits
+        && data_bits >= super_block.data_area_blocks as usize
+        && used_blocks == super_block.total_blocks as u64
+}
+
+fn read_disk_inode<V>(
+    fs: &EasyFileSystem,
+    inode_id: u32,
+    f: impl FnOnce(&DiskInode) -> V,
+) -> V {
+    let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+    get_block_cache(block_id as usize, Arc::clone(&fs.block_device))
+        .lock()
+        .read(block_offset, f)
+}
+
+fn modify_disk_inode<V>(
+    fs: &EasyFileSystem,
+    inode_id: u32,
+    f: impl FnOnce(&mut DiskInode) -> V,
+) -> V {
+    let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+    get_block_cache(block_id as usize, Arc::clone(&fs.block_device))
+        .lock()
+        .modify(block_offset, f)
+}
+
+/// Check the easy-fs image on `block_device` and return the problems found.
+///
+/// With `repair` set, the problems which are `repairable` are fixed as well,
+/// each in a transaction of its own. A pending journal transaction is
+/// completed before anything is checked.
+pub fn fsck(block_device: Arc<dyn BlockDevice>, repair: bool) -> Vec<Problem> {
+    let super_block = get_block_cache(0, Arc::clone(&block_device));
+    let (valid, layout_valid, data_area_blocks, checksum_start) = super_block
+        .lock()
+        .read(0, |super_block: &SuperBlock| (
+            super_block.is_valid(),
+            layout_is_valid(super_block),
+            super_block.data_area_blocks,
+            if super_block.features & FEATURE_METADATA_CSUM != 0 {
+                Some((super_block.total_blocks - super_block.checksum_blocks) as usize)
+            } else {
+                None
+            },
+        ));
+    if !valid {
+        return vec![Problem::BadSuperBlock];
+    }
+    if !layout_valid {
+        return vec![Problem::BadLayout];
+    }
+    // opening completes the journal, which the checksums count on
+    let efs = EasyFileSystem::open(Arc::clone(&block_device));
+    if let Some(checksum_start) = checksum_start {
+        let damaged = match &efs {
+            Ok(efs) => {
+                let fs = efs.lock();
+                let used = |block_id| fs.is_block_used(block_id);
+                checksum::damaged_blocks(checksum_start, &block_device, used)
+            }
+            Err(_) => checksum::damaged_blocks(checksum_start, &block_device, |_| true),
+        };
+        if !damaged.is_empty() {
+            return damaged
+                .into_iter()
+                .map(|block_id| Problem::BadChecksum(block_id as u32))
+                .collect();
+        }
+    }
+    let efs = match efs {
+        Ok(efs) => efs,
+        Err(_) => return vec![Problem::BadSuperBlock],
+    };
+    let mut fs = efs.lock();
+    let mut problems: Vec<Problem> = Vec::new();
+    let inode_count = fs.inode_bitmap.maximum() as u32;
+    let inode_allocated = |fs: &EasyFileSystem, inode_id: u32| {
+        inode_id < inode_count && fs.inode_bitmap.is_allocated(&block_device, inode_id as usize)
+    };
+    let data_start = fs.get_data_block_id(0);
+    let data_end = data_start + data_area_blocks;
+    let in_data_area = |block_id: u32| block_id >= data_start && block_id < data_end;
+    let format = fs.dir_format();
+
+    // blocks held by every allocated inode
+    let mut used_blocks: BTreeSet<u32> = BTreeSet::new();
+    // inodes whose contents cannot be read safely
+    let mut broken_inodes: BTreeSet<u32> = BTreeSet::new();
+    for inode_id in (0..inode_count).filter(|inode_id| inode_allocated(&fs, *inode_id)) {
+        let blocks = read_disk_inode(&fs, inode_id, |disk_inode| {
+            disk_inode.all_blocks(&block_device, in_data_area)
+        });
+        for block_id in blocks {
+            if !in_data_area(block_id) {
+                problems.push(Problem::BadBlock { inode_id, block_id });
+                broken_inodes.insert(inode_id);
+            } else if !used_blocks.insert(block_id) {
+                problems.push(Problem::DuplicateBlock { inode_id, block_id });
+            }
+        }
+    }
+
+    // walk the directory tree and count the entries referring to each inode
+    let mut links: BTreeMap<u32, u32> = BTreeMap::new();
+    let root_is_dir = inode_allocated(&fs, 0) && read_disk_inode(&fs, 0, |disk_inode| disk_inode.is_dir());
+    if !root_is_dir {
+        problems.push(Problem::BadRoot);
+    }
+    let mut visited: BTreeSet<u32> = BTreeSet::new();
+    let mut queue: VecDeque<u32> = VecDeque::new();
+    if root_is_dir {
+        queue.push_back(0);
+    }
+    while let Some(dir) = queue.pop_front() {
+        if !visited.insert(dir) || broken_inodes.contains(&dir) {
+            continue;
+        }
+        // entries before a malformed one are still checked
+        let mut dirents: Vec<(usize, u32, String)> = Vec::new();
+        let scanned = read_disk_inode(&fs, dir, |disk_inode| {
+            dir::scan(disk_inode, format, &block_device, |offset, inode_id, name| -> Option<()> {
+                dirents.push((offset, inode_id, String::from_utf8_lossy(name).into_owned()));
+                None
+            })
+        });
+        let indexed = read_disk_inode(&fs, dir, |disk_inode| {
+            dir::check_index(disk_inode, format, &block_device)
+        });
+        if scanned.is_err() || indexed != Ok(true) {
+            problems.push(Problem::BadDirectory(dir));
+        }
+        for (offset, inode_id, name) in dirents {
+            if !inode_allocated(&fs, inode_id) {
+                problems.push(Problem::DanglingEntry { dir, name, inode_id });
+                if repair {
+                    let removed = modify_disk_inode(&fs, dir, |disk_inode| {
+                        dir::remove(disk_inode, format, &block_device, offset)
+                    });
+                    assert!(removed.is_ok());
+                    let _ = fs.commit();
+                }
+                continue;
+            }
+            *links.entry(inode_id).or_insert(0) += 1;
+            if name != "." && name != ".."
+                && read_disk_inode(&fs, inode_id, |disk_inode| disk_inode.is_dir()) {
+                queue.push_back(inode_id);
+            }
+        }
+    }
+
+    // link counts
+    for inode_id in 0..inode_count {
+        if !inode_allocated(&fs, inode_id) {
+            continue;
+        }
+        let found = links.get(&inode_id).copied().unwrap_or(0);
+        if found == 0 {
+            // keep the data of an orphan rather than freeing it
+            problems.push(Problem::OrphanInode(inode_id));
+            continue;
+        }
+        let nlink = read_disk_inode(&fs, inode_id, |disk_inode| disk_inode.nlink as u32);
+        if nlink != found {
+            problems.push(Problem::WrongLinkCount { inode_id, nlink, found });
+            if repair {
+                modify_disk_inode(&fs, inode_id, |disk_inode| {
+                    disk_inode.nlink = found as u16;
+                });
+                let _ = fs.commit();
+            }
+        }
+    }
+
+    // the quota records are not in any inode
+    used_blocks.extend(fs.quota_blocks());
+
+    // data bitmap against the blocks in use, including those of snapshots
+    let snapshot_blocks = fs.snapshot_blocks();
+    for block_id in data_start..data_end {
+        let bit = (block_id - data_start) as usize;
+        let allocated = fs.data_bitmap.is_allocated(&block_device, bit);
+        let used = used_blocks.contains(&block_id)
+            || snapshot_blocks[bit / 64] & (1 << (bit % 64)) != 0;
+        if used && !allocated {
+            problems.push(Problem::FreeBlockInUse(block_id));
+            if repair {
+                fs.data_bitmap.set(&block_device, bit);
+                let _ = fs.commit();
+            }
+        } else if allocated && !used {
+            problems.push(Problem::LeakedBlock(block_id));
+            if repair {
+                fs.data_bitmap.dealloc(&block_device, bit);
+                let _ = fs.commit();
+            }
+        }
+    }
+
+    // quota records against what the inodes hold
+    if !fs.quota_blocks().is_empty() {
+        let quotas = fs.quotas();
+        let mut usage = fs.quota_usage();
+        for quota in quotas.iter() {
+            usage.entry(quota.uid).or_insert((0, 0));
+        }
+        let mut wrong_quota = false;
+        for (uid, found) in usage {
+            let used = quotas
+                .iter()
+                .find(|quota| quota.uid == uid)
+                .map_or((0, 0), |quota| (quota.blocks.used, quota.inodes.used));
+            if used != found {
+                problems.push(Problem::WrongQuota { uid, used, found });
+                wrong_quota = true;
+            }
+        }
+        if repair && wrong_quota {
+            let _ = fs.recount_quotas().and_then(|()| fs.commit());
+        }
+    }
+    problems
+}
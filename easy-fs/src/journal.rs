@@ -0,0 +1,142 @@
+use alloc::sync::Arc;
+use spin::MutexGuard;
+use core::ops::{Deref, DerefMut};
+use super::{
+    BlockDevice,
+    EasyFileSystem,
+    FsError,
+    JournalHeader,
+    JOURNAL_CAPACITY,
+    BLOCK_SZ,
+    Result,
+    get_block_cache,
+    modified_block_caches,
+    checksum,
+};
+
+type DataBlock = [u8; BLOCK_SZ];
+
+/// Write-ahead log kept in the blocks right after the super block.
+///
+/// The metadata blocks changed by one filesystem operation form a
+/// transaction. They are copied into the log first, then the header records
+/// where they belong, which commits the transaction. Only after that they are
+/// written to their home locations and the header is cleared again. Finding a
+/// committed header on open means a crash before the last step, and copying
+/// the log once more completes the transaction.
+///
+/// File data is not logged but written before the metadata referring to it.
+pub struct Journal {
+    start_block: usize,
+    blocks: usize,
+}
+
+impl Journal {
+    pub fn new(start_block: usize, blocks: usize) -> Self {
+        Self {
+            start_block,
+            blocks,
+        }
+    }
+
+    /// Return the maximum number of blocks in one transaction.
+    pub fn capacity(&self) -> usize {
+        (self.blocks - 1).min(JOURNAL_CAPACITY)
+    }
+
+    /// Write all modified blocks of `block_device` as one transaction.
+    ///
+    /// Fails with `JournalFull` without writing anything if there are more
+    /// than `capacity` of them.
+    pub fn commit(&self, block_device: &Arc<dyn BlockDevice>) -> Result<()> {
+        let block_caches = modified_block_caches(block_device);
+        if block_caches.is_empty() {
+            return Ok(());
+        }
+        if block_caches.len() > self.capacity() {
+            return Err(FsError::JournalFull);
+        }
+        let mut header = JournalHeader::new();
+        for (i, block_cache) in block_caches.iter().enumerate() {
+            let block_cache = block_cache.lock();
+            block_cache.read(0, |data_block: &DataBlock| {
+                block_device.write_block(self.start_block + 1 + i, data_block);
+            });
+            header.push(block_cache.block_id() as u32);
+        }
+        block_device.write_block(self.start_block, header.as_bytes());
+        // committed, now the blocks may go home
+        for block_cache in block_caches.iter() {
+            block_cache.lock().sync();
+        }
+        block_device.write_block(self.start_block, JournalHeader::empty().as_bytes());
+        Ok(())
+    }
+
+    /// Complete the transaction interrupted by a crash, if there is one.
+    pub fn replay(&self, block_device: &Arc<dyn BlockDevice>) {
+        let mut header = JournalHeader::empty();
+        block_device.read_block(self.start_block, header.as_bytes_mut());
+        if !header.is_committed() {
+            return;
+        }
+        let mut data = [0u8; BLOCK_SZ];
+        for (i, block_id) in header.block_ids().iter().enumerate() {
+            block_device.read_block(self.start_block + 1 + i, &mut data);
+            // through the cache so that no stale copy of the block is left
+            let block_cache = get_block_cache(*block_id as usize, Arc::clone(block_device));
+            let mut block_cache = block_cache.lock();
+            block_cache.modify(0, |data_block: &mut DataBlock| {
+                data_block.copy_from_slice(&data);
+            });
+            block_cache.sync();
+        }
+        block_device.write_block(self.start_block, JournalHeader::empty().as_bytes());
+    }
+}
+
+/// Exclusive access to the filesystem for one operation. What it changes
+/// is committed as a single transaction by `end` if the operation succeeds,
+/// and dropped if it fails or the transaction is dropped without `end`.
+pub struct Transaction<'a>(MutexGuard<'a, EasyFileSystem>);
+
+impl<'a> Transaction<'a> {
+    pub fn new(efs: MutexGuard<'a, EasyFileSystem>) -> Self {
+        // damaged blocks read outside a transaction do not concern this one
+        checksum::take_errors(&efs.block_device);
+        Self(efs)
+    }
+
+    /// Commit now and return `result` if it is `Ok`, or drop the changes
+    /// and return the error. Fails if the commit does, see
+    /// `EasyFileSystem::commit`.
+    pub fn end<T>(mut self, result: Result<T>) -> Result<T> {
+        if result.is_err() {
+            // half an operation must not become permanent
+            self.0.discard_changes();
+            return result;
+        }
+        self.0.commit()?;
+        result
+    }
+}
+
+impl Deref for Transaction<'_> {
+    type Target = EasyFileSystem;
+    fn deref(&self) -> &EasyFileSystem {
+        &self.0
+    }
+}
+
+impl DerefMut for Transaction<'_> {
+    fn deref_mut(&mut self) -> &mut EasyFileSystem {
+        &mut self.0
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        // left by an early return, nothing was committed by `end`
+        self.0.discard_changes();
+    }
+}
@@ -1,40 +1,131 @@
+use core::convert::TryFrom;
 use core::fmt::{Debug, Formatter, Result};
 use super::{
     BLOCK_SZ,
     BlockDevice,
+    FsError,
     get_block_cache,
+    checksum::CHECKSUMS_PER_BLOCK,
+    compress::MAX_COMPRESSED_SIZE,
+    crypt::{MAX_ENCRYPTED_SIZE, TAG_LIST_DEPTH},
+    aead::SALT_SZ,
 };
+use crate::error::Result as FsResult;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
 const EFS_MAGIC: u32 = 0x3b800001;
+/// Bumped whenever the on-disk format changes incompatibly.
+const EFS_VERSION: u32 = 3;
+const JOURNAL_MAGIC: u32 = 0x4a4e4c01;
 const INODE_DIRECT_COUNT: usize = 28;
-const NAME_LENGTH_LIMIT: usize = 27;
+pub const NAME_LENGTH_LIMIT: usize = 27;
+pub const LONG_NAME_LENGTH_LIMIT: usize = 255;
 const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
 const INODE_INDIRECT2_COUNT: usize = INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COUNT;
 const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
 const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
-#[allow(unused)]
 const INDIRECT2_BOUND: usize = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
 
+/// Directories hold `DirRecord`s rather than `DirEntry`s.
+pub const FEATURE_LONG_NAMES: u32 = 1 << 0;
+/// Directories of more than one block are indexed by name hash.
+pub const FEATURE_DIR_INDEX: u32 = 1 << 1;
+/// Inodes may map their blocks through extents.
+pub const FEATURE_EXTENTS: u32 = 1 << 2;
+/// Small files and symbolic links keep their data in the inode.
+pub const FEATURE_INLINE_DATA: u32 = 1 << 3;
+/// Metadata blocks are checked against a table of CRC-32C checksums at the
+/// end of the image, see `checksum`.
+pub const FEATURE_METADATA_CSUM: u32 = 1 << 4;
+/// The super block may list snapshots, which share blocks with the inodes,
+/// see `snapshot`.
+pub const FEATURE_SNAPSHOTS: u32 = 1 << 5;
+/// The blocks and inodes each uid uses are tracked against its limits, see
+/// `quota`.
+pub const FEATURE_QUOTA: u32 = 1 << 6;
+/// Features this version understands, images using others are rejected.
+pub(crate) const FEATURES_SUPPORTED: u32 = FEATURE_LONG_NAMES
+    | FEATURE_DIR_INDEX
+    | FEATURE_EXTENTS
+    | FEATURE_INLINE_DATA
+    | FEATURE_METADATA_CSUM
+    | FEATURE_SNAPSHOTS
+    | FEATURE_QUOTA;
+
+/// The directory starts with a hash index, see `dir`.
+pub const INODE_FLAG_DIR_INDEX: u32 = 1 << 0;
+/// `direct` holds the root of an extent tree instead of block IDs, and
+/// `indirect1`/`indirect2` are unused.
+pub const INODE_FLAG_EXTENTS: u32 = 1 << 1;
+/// The data is stored in `direct`, see `INLINE_DATA_CAPACITY`.
+pub const INODE_FLAG_INLINE: u32 = 1 << 2;
+/// The data is stored in compressed clusters, see `compress`.
+pub const INODE_FLAG_COMPRESSED: u32 = 1 << 3;
+/// The data is sealed block by block, see `crypt`.
+pub const INODE_FLAG_ENCRYPTED: u32 = 1 << 4;
+/// Bytes of data an inode holds inline.
+pub const INLINE_DATA_CAPACITY: usize = INODE_DIRECT_COUNT * 4;
+/// Words of an extent or an extent index entry.
+const EXTENT_WORDS: usize = 3;
+/// Entries in the root, behind a word with their count and the depth.
+const INODE_EXTENT_COUNT: usize = (INODE_DIRECT_COUNT - 1) / EXTENT_WORDS;
+/// Extents in a leaf block, behind a word with their count.
+const LEAF_EXTENT_COUNT: usize = (BLOCK_SZ / 4 - 1) / EXTENT_WORDS;
+
 #[repr(C)]
 pub struct SuperBlock {
     magic: u32,
+    version: u32,
     pub total_blocks: u32,
+    /// Size of the journal, which starts right after the super block.
+    pub journal_blocks: u32,
     pub inode_bitmap_blocks: u32,
     pub inode_area_blocks: u32,
     pub data_bitmap_blocks: u32,
     pub data_area_blocks: u32,
+    /// `FEATURE_*` flags. Images from before there were any read as zero.
+    pub features: u32,
+    /// Size of the checksum table, which takes the last blocks.
+    pub checksum_blocks: u32,
+    /// Block listing the snapshots, zero if none was ever taken.
+    pub snapshot_table: u32,
+    /// Snapshots taken so far, see `DiskInode::epoch`.
+    pub snapshot_epoch: u32,
+    /// Nonces below this are reserved for sealing data, low word first,
+    /// see `EasyFileSystem::reserve_nonces`.
+    pub nonce_counter: [u32; 2],
+    /// Block listing the quota records, zero while quotas are not used.
+    pub quota_table: u32,
+    /// Inode whose blocks are being freed, see `Inode::release`. Zero for
+    /// none, as the root is never freed.
+    pub orphan: u32,
+    /// Descriptor of the snapshot being restored, zero for none.
+    pub restoring: u32,
+    /// Nonzero while the data bitmap is being rebuilt after a snapshot was
+    /// restored or deleted.
+    pub rebuilding: u32,
 }
 
 impl Debug for SuperBlock {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         f.debug_struct("SuperBlock")
+            .field("version", &self.version)
             .field("total_blocks", &self.total_blocks)
+            .field("journal_blocks", &self.journal_blocks)
             .field("inode_bitmap_blocks", &self.inode_bitmap_blocks)
             .field("inode_area_blocks", &self.inode_area_blocks)
             .field("data_bitmap_blocks", &self.data_bitmap_blocks)
             .field("data_area_blocks", &self.data_area_blocks)
+            .field("features", &self.features)
+            .field("checksum_blocks", &self.checksum_blocks)
+            .field("snapshot_table", &self.snapshot_table)
+            .field("snapshot_epoch", &self.snapshot_epoch)
+            .field("nonce_counter", &self.nonce_counter)
+            .field("quota_table", &self.quota_table)
+            .field("orphan", &self.orphan)
+            .field("restoring", &self.restoring)
+            .field("rebuilding", &self.rebuilding)
             .finish()
     }
 }
@@ -43,6 +134,7 @@ impl SuperBlock {
     pub fn initialize(
         &mut self,
         total_blocks: u32,
+        journal_blocks: u32,
         inode_bitmap_blocks: u32,
         inode_area_blocks: u32,
         data_bitmap_blocks: u32,
@@ -50,51 +142,495 @@ impl SuperBlock {
     ) {
         *self = Self {
             magic: EFS_MAGIC,
+            version: EFS_VERSION,
             total_blocks,
+            journal_blocks,
             inode_bitmap_blocks,
             inode_area_blocks,
             data_bitmap_blocks,
             data_area_blocks,
+            // new images use everything there is
+            features: FEATURES_SUPPORTED,
+            checksum_blocks: 0,
+            snapshot_table: 0,
+            snapshot_epoch: 0,
+            nonce_counter: [0; 2],
+            quota_table: 0,
+            orphan: 0,
+            restoring: 0,
+            rebuilding: 0,
         }
     }
     pub fn is_valid(&self) -> bool {
         self.magic == EFS_MAGIC
+            && self.version == EFS_VERSION
+            && self.features & !FEATURES_SUPPORTED == 0
+            && (self.features & FEATURE_METADATA_CSUM == 0 || self.checksum_table_fits())
+    }
+
+    /// Whether the checksum table at the end has an entry for every block
+    /// before it.
+    fn checksum_table_fits(&self) -> bool {
+        self.checksum_blocks <= self.total_blocks
+            && self.checksum_blocks as u64 * CHECKSUMS_PER_BLOCK as u64
+                >= (self.total_blocks - self.checksum_blocks) as u64
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum DiskInodeType {
-    File,
-    Directory,
+    File = 0,
+    Directory = 1,
+    /// The data holds the path the link points to.
+    Symlink = 2,
+}
+
+impl TryFrom<u8> for DiskInodeType {
+    type Error = FsError;
+
+    fn try_from(value: u8) -> FsResult<Self> {
+        match value {
+            0 => Ok(DiskInodeType::File),
+            1 => Ok(DiskInodeType::Directory),
+            2 => Ok(DiskInodeType::Symlink),
+            _ => Err(FsError::Corrupt),
+        }
+    }
 }
 
 type IndirectBlock = [u32; BLOCK_SZ / 4];
+type ExtentBlock = [u32; BLOCK_SZ / 4];
 type DataBlock = [u8; BLOCK_SZ];
 
+/// `len` data blocks from logical block `logical` of a file, stored from
+/// block `physical` on.
+///
+/// In the root of an extent tree of depth 1 the entries are an index
+/// instead: `logical` is where the leaf block `physical` starts and `len`
+/// how many extents it holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extent {
+    pub logical: u32,
+    pub physical: u32,
+    pub len: u32,
+}
+
+impl Extent {
+    fn from_words(words: &[u32]) -> Self {
+        Self { logical: words[0], physical: words[1], len: words[2] }
+    }
+    fn write_to(&self, words: &mut [u32]) {
+        words[..EXTENT_WORDS].copy_from_slice(&[self.logical, self.physical, self.len]);
+    }
+    fn end(&self) -> u32 {
+        self.logical + self.len
+    }
+    /// Map a logical block inside the extent.
+    fn map(&self, inner_id: u32) -> Option<u32> {
+        if inner_id >= self.logical && inner_id < self.end() {
+            Some(self.physical + inner_id - self.logical)
+        } else {
+            None
+        }
+    }
+}
+
+/// Leaf blocks needed for `count` extents, or `None` if the tree cannot hold
+/// that many.
+fn extent_leaves_for(count: usize) -> Option<usize> {
+    if count <= INODE_EXTENT_COUNT {
+        return Some(0);
+    }
+    let leaves = (count + LEAF_EXTENT_COUNT - 1) / LEAF_EXTENT_COUNT;
+    if leaves <= INODE_EXTENT_COUNT {
+        Some(leaves)
+    } else {
+        None
+    }
+}
+
+/// Add `offset` to the block IDs in `list` and, `depth` levels down, in the
+/// lists it refers to, writing each back right away.
+fn shift_list(list: u32, depth: u32, offset: u32, block_device: &Arc<dyn BlockDevice>) {
+    let block_cache = get_block_cache(list as usize, Arc::clone(block_device));
+    let mut block_cache = block_cache.lock();
+    let block_ids = block_cache.modify(0, |block_ids: &mut IndirectBlock| {
+        block_ids.iter_mut().filter(|block_id| **block_id != 0).for_each(|block_id| *block_id += offset);
+        *block_ids
+    });
+    block_cache.sync();
+    drop(block_cache);
+    if depth > 1 {
+        for block_id in block_ids.iter().filter(|block_id| **block_id != 0) {
+            shift_list(*block_id, depth - 1, offset, block_device);
+        }
+    }
+}
+
+/// Push `list` and the blocks it refers to, `depth` levels down, each after
+/// the list holding it. Lists for which `valid` fails are not read.
+fn list_blocks(
+    list: u32,
+    depth: u32,
+    block_device: &Arc<dyn BlockDevice>,
+    valid: &impl Fn(u32) -> bool,
+    v: &mut Vec<u32>,
+) {
+    v.push(list);
+    if !valid(list) {
+        return;
+    }
+    let block_ids = get_block_cache(list as usize, Arc::clone(block_device))
+        .lock()
+        .read(0, |block_ids: &IndirectBlock| *block_ids);
+    for block_id in block_ids.iter().filter(|block_id| **block_id != 0) {
+        if depth > 1 {
+            list_blocks(*block_id, depth - 1, block_device, valid, v);
+        } else {
+            v.push(*block_id);
+        }
+    }
+}
+
+/// Add an extent for a hole to a sorted list, merging it with its
+/// neighbours where they continue each other on disk.
+fn insert_extent(extents: &mut Vec<Extent>, extent: Extent) {
+    let pos = extents.iter().position(|other| other.logical > extent.logical).unwrap_or(extents.len());
+    extents.insert(pos, extent);
+    let mut merged: Vec<Extent> = Vec::with_capacity(extents.len());
+    for extent in extents.drain(..) {
+        match merged.last_mut() {
+            Some(last) if last.end() == extent.logical && last.physical + last.len == extent.physical => {
+                last.len += extent.len;
+            }
+            _ => merged.push(extent),
+        }
+    }
+    *extents = merged;
+}
+
+/// Source of the blocks an inode maps, the data area of the filesystem.
+pub trait BlockAllocator {
+    /// Allocate up to `len` cleared blocks in a row, starting at `goal` if
+    /// that is free, and return the first one and how many there are.
+    fn alloc_run(&mut self, goal: Option<u32>, len: u32) -> FsResult<(u32, u32)>;
+    fn dealloc(&mut self, block_id: u32);
+    /// Whether `block_id` is shared with a snapshot, which must not see it
+    /// change.
+    fn is_shared(&self, block_id: u32) -> bool;
+}
+
+/// Return `block_id`, or a copy of it in a new block if it is shared. The
+/// copy is written through, as nothing refers to it before the pointer to
+/// it commits. The shared block goes to `allocator.dealloc`.
+pub(crate) fn copy_block(
+    block_id: u32,
+    goal: Option<u32>,
+    allocator: &mut dyn BlockAllocator,
+    block_device: &Arc<dyn BlockDevice>,
+) -> FsResult<u32> {
+    if block_id == 0 || !allocator.is_shared(block_id) {
+        return Ok(block_id);
+    }
+    let data = get_block_cache(block_id as usize, Arc::clone(block_device))
+        .lock()
+        .read(0, |data_block: &DataBlock| *data_block);
+    let copy = allocator.alloc_run(goal, 1)?.0;
+    let block_cache = get_block_cache(copy as usize, Arc::clone(block_device));
+    let mut block_cache = block_cache.lock();
+    block_cache.modify(0, |data_block: &mut DataBlock| *data_block = data);
+    block_cache.sync();
+    allocator.dealloc(block_id);
+    Ok(copy)
+}
+
+/// Return `block_id` if it may be changed in place, or else a new block:
+/// a cleared one for zero, a copy if a snapshot holds it.
+pub(crate) fn writable_block(
+    block_id: u32,
+    allocator: &mut dyn BlockAllocator,
+    block_device: &Arc<dyn BlockDevice>,
+) -> FsResult<u32> {
+    if block_id == 0 {
+        return Ok(allocator.alloc_run(None, 1)?.0);
+    }
+    copy_block(block_id, None, allocator, block_device)
+}
+
+/// Takes 256 bytes on disk, two inodes per block.
 #[repr(C)]
+#[derive(Clone)]
 pub struct DiskInode {
     pub size: u32,
     pub direct: [u32; INODE_DIRECT_COUNT],
     pub indirect1: u32,
     pub indirect2: u32,
-    type_: DiskInodeType,
+    /// A `DiskInodeType`, kept as a byte as the disk may hold any value.
+    type_: u8,
+    /// Number of directory entries referring to this inode, counting "."
+    /// and the ".." of every subdirectory for a directory.
+    pub nlink: u16,
+    /// Permission bits.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Last access, modification and status change time in seconds.
+    pub atime: u32,
+    pub mtime: u32,
+    pub ctime: u32,
+    /// `INODE_FLAG_*` bits.
+    pub flags: u32,
+    /// The `snapshot_epoch` when the blocks were last copied. They may be
+    /// shared with a snapshot if it is older than the current one.
+    pub epoch: u32,
+    /// Block of extended attributes, zero if there are none, see `xattr`.
+    pub xattr: u32,
+    /// Block listing the tag blocks of an encrypted file, zero if there
+    /// are none yet, see `crypt`.
+    pub tags: u32,
+    /// Identifier of the key an encrypted file is sealed with, low word
+    /// first.
+    pub key_id: [u32; 2],
+    /// Random salt the key of an encrypted file is derived with, see
+    /// `aead::derive_key`.
+    pub salt: [u32; 3],
+    /// Block listing the blocks of the cluster map of a compressed file,
+    /// zero if there are none yet, see `compress`.
+    pub clusters: u32,
+    reserved: [u32; 16],
 }
 
 impl DiskInode {
     /// indirect1 and indirect2 block are allocated only when they are needed.
-    pub fn initialize(&mut self, type_: DiskInodeType) {
+    pub fn initialize(&mut self, type_: DiskInodeType, now: u32) {
         self.size = 0;
         self.direct.iter_mut().for_each(|v| *v = 0);
         self.indirect1 = 0;
         self.indirect2 = 0;
-        self.type_ = type_;
+        self.nlink = if type_ == DiskInodeType::Directory { 2 } else { 1 };
+        self.mode = match type_ {
+            DiskInodeType::File => 0o644,
+            DiskInodeType::Directory => 0o755,
+            DiskInodeType::Symlink => 0o777,
+        };
+        self.uid = 0;
+        self.gid = 0;
+        self.atime = now;
+        self.mtime = now;
+        self.ctime = now;
+        self.flags = 0;
+        self.epoch = 0;
+        self.xattr = 0;
+        self.tags = 0;
+        self.key_id = [0; 2];
+        self.salt = [0; 3];
+        self.clusters = 0;
+        self.reserved.iter_mut().for_each(|v| *v = 0);
+        self.type_ = type_ as u8;
+    }
+    /// `Corrupt` if the type is none of `DiskInodeType`.
+    pub fn type_(&self) -> FsResult<DiskInodeType> {
+        DiskInodeType::try_from(self.type_)
     }
     pub fn is_dir(&self) -> bool {
-        self.type_ == DiskInodeType::Directory
+        self.type_ == DiskInodeType::Directory as u8
     }
-    #[allow(unused)]
     pub fn is_file(&self) -> bool {
-        self.type_ == DiskInodeType::File
+        self.type_ == DiskInodeType::File as u8
+    }
+    pub fn is_symlink(&self) -> bool {
+        self.type_ == DiskInodeType::Symlink as u8
+    }
+    pub fn uses_extents(&self) -> bool {
+        self.flags & INODE_FLAG_EXTENTS != 0
+    }
+    pub fn is_inline(&self) -> bool {
+        self.flags & INODE_FLAG_INLINE != 0
+    }
+    pub fn is_compressed(&self) -> bool {
+        self.flags & INODE_FLAG_COMPRESSED != 0
+    }
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & INODE_FLAG_ENCRYPTED != 0
+    }
+    pub fn key_id(&self) -> u64 {
+        self.key_id[0] as u64 | (self.key_id[1] as u64) << 32
+    }
+    pub fn set_key_id(&mut self, key_id: u64) {
+        self.key_id = [key_id as u32, (key_id >> 32) as u32];
+    }
+    pub fn salt(&self) -> [u8; SALT_SZ] {
+        let mut salt = [0u8; SALT_SZ];
+        for (bytes, word) in salt.chunks_mut(4).zip(self.salt.iter()) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        salt
+    }
+    pub fn set_salt(&mut self, salt: &[u8; SALT_SZ]) {
+        for (word, bytes) in self.salt.iter_mut().zip(salt.chunks(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+    }
+    fn inline_data(&self) -> [u8; INLINE_DATA_CAPACITY] {
+        let mut data = [0u8; INLINE_DATA_CAPACITY];
+        for (bytes, word) in data.chunks_mut(4).zip(self.direct.iter()) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        data
+    }
+    fn set_inline_data(&mut self, data: &[u8; INLINE_DATA_CAPACITY]) {
+        for (word, bytes) in self.direct.iter_mut().zip(data.chunks(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+    }
+    /// Move inline data to a data block and map blocks from now on.
+    pub fn uninline(
+        &mut self,
+        allocator: &mut dyn BlockAllocator,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> FsResult<()> {
+        let data = self.inline_data();
+        self.direct.iter_mut().for_each(|v| *v = 0);
+        self.flags &= !INODE_FLAG_INLINE;
+        if self.size == 0 {
+            return Ok(());
+        }
+        if let Err(err) = self.alloc_blocks(0, 1, allocator, block_device) {
+            self.set_inline_data(&data);
+            self.flags |= INODE_FLAG_INLINE;
+            return Err(err);
+        }
+        let size = (self.size as usize).min(INLINE_DATA_CAPACITY);
+        self.write_at(0, &data[..size], block_device);
+        Ok(())
+    }
+    /// Return the depth of the extent tree and the entries of its root.
+    fn extent_root(&self) -> (u32, Vec<Extent>) {
+        let count = (self.direct[0] as usize & 0xffff).min(INODE_EXTENT_COUNT);
+        let entries = self.direct[1..]
+            .chunks(EXTENT_WORDS)
+            .take(count)
+            .map(Extent::from_words)
+            .collect();
+        (self.direct[0] >> 16, entries)
+    }
+    fn extent_leaves(&self) -> Vec<u32> {
+        match self.extent_root() {
+            (0, _) => Vec::new(),
+            (_, entries) => entries.iter().map(|entry| entry.physical).collect(),
+        }
+    }
+    /// Return the extents in order. Leaf blocks for which `valid` fails are
+    /// skipped.
+    fn read_extents(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        valid: impl Fn(u32) -> bool,
+    ) -> Vec<Extent> {
+        let (depth, entries) = self.extent_root();
+        if depth == 0 {
+            return entries;
+        }
+        let mut extents = Vec::new();
+        for entry in entries.iter().filter(|entry| valid(entry.physical)) {
+            get_block_cache(entry.physical as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |leaf: &ExtentBlock| {
+                    let count = (leaf[0] as usize).min(LEAF_EXTENT_COUNT);
+                    extents.extend(leaf[1..].chunks(EXTENT_WORDS).take(count).map(Extent::from_words));
+                });
+        }
+        extents
+    }
+    /// Write `extents` to the root and `leaves`, of which there are as many
+    /// as `extent_leaves_for` says.
+    fn store_extents(
+        &mut self,
+        extents: &[Extent],
+        leaves: &[u32],
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        assert_eq!(extent_leaves_for(extents.len()), Some(leaves.len()));
+        let (depth, root) = if leaves.is_empty() {
+            (0, extents.to_vec())
+        } else {
+            let mut root = Vec::new();
+            for (leaf_id, chunk) in leaves.iter().zip(extents.chunks(LEAF_EXTENT_COUNT)) {
+                let mut leaf: ExtentBlock = [0; BLOCK_SZ / 4];
+                leaf[0] = chunk.len() as u32;
+                for (extent, words) in chunk.iter().zip(leaf[1..].chunks_mut(EXTENT_WORDS)) {
+                    extent.write_to(words);
+                }
+                // only changed leaves go through the journal
+                let block_cache = get_block_cache(*leaf_id as usize, Arc::clone(block_device));
+                let mut block_cache = block_cache.lock();
+                if block_cache.read(0, |old: &ExtentBlock| *old != leaf) {
+                    block_cache.modify(0, |old: &mut ExtentBlock| *old = leaf);
+                }
+                root.push(Extent { logical: chunk[0].logical, physical: *leaf_id, len: chunk.len() as u32 });
+            }
+            (1, root)
+        };
+        self.set_extent_root(depth, &root);
+    }
+    fn set_extent_root(&mut self, depth: u32, root: &[Extent]) {
+        self.direct.iter_mut().for_each(|v| *v = 0);
+        self.direct[0] = depth << 16 | root.len() as u32;
+        for (entry, words) in root.iter().zip(self.direct[1..].chunks_mut(EXTENT_WORDS)) {
+            entry.write_to(words);
+        }
+    }
+    /// Add `offset` to the ID of every block this inode refers to, after all
+    /// of them have been moved by that much. Changed indirect and leaf blocks
+    /// are written back right away.
+    pub fn relocate(&mut self, offset: u32, block_device: &Arc<dyn BlockDevice>) {
+        let shift = |block_id: &mut u32| {
+            if *block_id != 0 {
+                *block_id += offset;
+            }
+        };
+        let shift_block = |block_id: u32| shift_list(block_id, 1, offset, block_device);
+        shift(&mut self.xattr);
+        shift(&mut self.tags);
+        shift(&mut self.clusters);
+        for (list, depth) in [(self.tags, TAG_LIST_DEPTH), (self.clusters, 1)].iter() {
+            if *list != 0 {
+                shift_list(*list, *depth, offset, block_device);
+            }
+        }
+        if self.is_inline() {
+            return;
+        }
+        if self.uses_extents() {
+            let (depth, mut root) = self.extent_root();
+            root.iter_mut().for_each(|entry| entry.physical += offset);
+            self.set_extent_root(depth, &root);
+            if depth > 0 {
+                let mut extents = self.read_extents(block_device, |_| true);
+                extents.iter_mut().for_each(|extent| extent.physical += offset);
+                let leaves = self.extent_leaves();
+                self.store_extents(&extents, &leaves, block_device);
+                for leaf in leaves {
+                    get_block_cache(leaf as usize, Arc::clone(block_device)).lock().sync();
+                }
+            }
+            return;
+        }
+        self.direct.iter_mut().for_each(shift);
+        shift(&mut self.indirect1);
+        if self.indirect1 != 0 {
+            shift_block(self.indirect1);
+        }
+        shift(&mut self.indirect2);
+        if self.indirect2 != 0 {
+            shift_block(self.indirect2);
+            let indirect1s = get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |indirect2: &IndirectBlock| *indirect2);
+            for indirect1 in indirect1s.iter().filter(|block_id| **block_id != 0) {
+                shift_block(*indirect1);
+            }
+        }
     }
     /// Return block number correspond to size.
     pub fn data_blocks(&self) -> u32 {
@@ -103,37 +639,43 @@ impl DiskInode {
     fn _data_blocks(size: u32) -> u32 {
         (size + BLOCK_SZ as u32 - 1) / BLOCK_SZ as u32
     }
-    /// Return number of blocks needed include indirect1/2.
-    pub fn total_blocks(size: u32) -> u32 {
-        let data_blocks = Self::_data_blocks(size) as usize;
-        let mut total = data_blocks as usize;
-        // indirect1
-        if data_blocks > INODE_DIRECT_COUNT {
-            total += 1;
-        }
-        // indirect2
-        if data_blocks > INDIRECT1_BOUND {
-            total += 1;
-            // sub indirect1
-            total += (data_blocks - INDIRECT1_BOUND + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
-        }
-        total as u32
-    }
-    pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
-        assert!(new_size >= self.size);
-        Self::total_blocks(new_size) - Self::total_blocks(self.size)
-    }
     pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        if self.uses_extents() {
+            let (depth, entries) = self.extent_root();
+            if depth == 0 {
+                return entries.iter().find_map(|extent| extent.map(inner_id)).unwrap_or(0);
+            }
+            let leaf = match entries.iter().rev().find(|entry| entry.logical <= inner_id) {
+                Some(entry) => entry,
+                None => return 0,
+            };
+            return get_block_cache(leaf.physical as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |leaf: &ExtentBlock| {
+                    let count = (leaf[0] as usize).min(LEAF_EXTENT_COUNT);
+                    leaf[1..]
+                        .chunks(EXTENT_WORDS)
+                        .take(count)
+                        .find_map(|words| Extent::from_words(words).map(inner_id))
+                        .unwrap_or(0)
+                });
+        }
         let inner_id = inner_id as usize;
         if inner_id < INODE_DIRECT_COUNT {
             self.direct[inner_id]
         } else if inner_id < INDIRECT1_BOUND {
+            if self.indirect1 == 0 {
+                return 0;
+            }
             get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
                 .lock()
                 .read(0, |indirect_block: &IndirectBlock| {
                     indirect_block[inner_id - INODE_DIRECT_COUNT]
                 })
         } else {
+            if self.indirect2 == 0 {
+                return 0;
+            }
             let last = inner_id - INDIRECT1_BOUND;
             let indirect1 = get_block_cache(
                 self.indirect2 as usize,
@@ -143,6 +685,9 @@ impl DiskInode {
             .read(0, |indirect2: &IndirectBlock| {
                 indirect2[last / INODE_INDIRECT1_COUNT]
             });
+            if indirect1 == 0 {
+                return 0;
+            }
             get_block_cache(
                 indirect1 as usize,
                 Arc::clone(block_device)
@@ -153,175 +698,600 @@ impl DiskInode {
             })
         }
     }
-    pub fn increase_size(
-        &mut self,
-        new_size: u32,
-        new_blocks: Vec<u32>,
+    /// Return all blocks held, data blocks first and then indirect1/2 blocks.
+    ///
+    /// Indirect blocks for which `valid` fails are returned but not read.
+    /// The attribute block comes last, but for the tag blocks of an
+    /// encrypted file and the cluster map of a compressed one, each after
+    /// the list blocks leading to them.
+    pub fn all_blocks(
+        &self,
         block_device: &Arc<dyn BlockDevice>,
-    ) {
-        let mut current_blocks = self.data_blocks();
-        self.size = new_size;
-        let mut total_blocks = self.data_blocks();
-        let mut new_blocks = new_blocks.into_iter();
-        // fill direct
-        while current_blocks < total_blocks.min(INODE_DIRECT_COUNT as u32) {
-            self.direct[current_blocks as usize] = new_blocks.next().unwrap();
-            current_blocks += 1;
-        }
-        // alloc indirect1
-        if total_blocks > INODE_DIRECT_COUNT as u32{
-            if current_blocks == INODE_DIRECT_COUNT as u32 {
-                self.indirect1 = new_blocks.next().unwrap();
-            }
-            current_blocks -= INODE_DIRECT_COUNT as u32;
-            total_blocks -= INODE_DIRECT_COUNT as u32;
-        } else {
-            return;
+        valid: impl Fn(u32) -> bool,
+    ) -> Vec<u32> {
+        let mut v = self.mapped_blocks(block_device, &valid);
+        if self.xattr != 0 {
+            v.push(self.xattr);
         }
-        // fill indirect1
-        get_block_cache(
-            self.indirect1 as usize,
-            Arc::clone(block_device)
-        )
-        .lock()
-        .modify(0, |indirect1: &mut IndirectBlock| {
-            while current_blocks < total_blocks.min(INODE_INDIRECT1_COUNT as u32) {
-                indirect1[current_blocks as usize] = new_blocks.next().unwrap();
-                current_blocks += 1;
-            }
-        });
-        // alloc indirect2
-        if total_blocks > INODE_INDIRECT1_COUNT as u32 {
-            if current_blocks == INODE_INDIRECT1_COUNT as u32 {
-                self.indirect2 = new_blocks.next().unwrap();
-            }
-            current_blocks -= INODE_INDIRECT1_COUNT as u32;
-            total_blocks -= INODE_INDIRECT1_COUNT as u32;
-        } else {
-            return;
+        for (list, depth) in [(self.tags, TAG_LIST_DEPTH), (self.clusters, 1)].iter() {
+            if *list != 0 {
+                list_blocks(*list, *depth, block_device, &valid, &mut v);
+            }
         }
-        // fill indirect2 from (a0, b0) -> (a1, b1)
-        let mut a0 = current_blocks as usize / INODE_INDIRECT1_COUNT;
-        let mut b0 = current_blocks as usize % INODE_INDIRECT1_COUNT;
-        let a1 = total_blocks as usize / INODE_INDIRECT1_COUNT;
-        let b1 = total_blocks as usize % INODE_INDIRECT1_COUNT;
-        // alloc low-level indirect1
-        get_block_cache(
-            self.indirect2 as usize,
-            Arc::clone(block_device)
-        )
-        .lock()
-        .modify(0, |indirect2: &mut IndirectBlock| {
-            while (a0 < a1) || (a0 == a1 && b0 < b1) {
-                if b0 == 0 {
-                    indirect2[a0] = new_blocks.next().unwrap();
-                }
-                // fill current
-                get_block_cache(
-                    indirect2[a0] as usize,
-                    Arc::clone(block_device)
-                )
+        v
+    }
+    fn mapped_blocks(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        valid: impl Fn(u32) -> bool,
+    ) -> Vec<u32> {
+        if self.is_inline() {
+            return Vec::new();
+        }
+        if self.uses_extents() {
+            let data_blocks = self.data_blocks();
+            let mut v: Vec<u32> = Vec::new();
+            for extent in self.read_extents(block_device, &valid) {
+                let len = extent.len.min(data_blocks.saturating_sub(extent.logical));
+                v.extend(extent.physical..extent.physical + len);
+            }
+            v.extend(self.extent_leaves());
+            return v;
+        }
+        let data_blocks = self.data_blocks() as usize;
+        let mut v: Vec<u32> = self.direct[..data_blocks.min(INODE_DIRECT_COUNT)].to_vec();
+        if data_blocks > INODE_DIRECT_COUNT && valid(self.indirect1) {
+            get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
                 .lock()
-                .modify(0, |indirect1: &mut IndirectBlock| {
-                    indirect1[b0] = new_blocks.next().unwrap();
+                .read(0, |indirect1: &IndirectBlock| {
+                    let count = data_blocks.min(INDIRECT1_BOUND) - INODE_DIRECT_COUNT;
+                    v.extend_from_slice(&indirect1[..count]);
+                });
+        }
+        let mut indirect1_blocks: Vec<u32> = Vec::new();
+        if data_blocks > INDIRECT1_BOUND && valid(self.indirect2) {
+            let last = data_blocks - INDIRECT1_BOUND;
+            let a1 = ((last + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT)
+                .min(INODE_INDIRECT1_COUNT);
+            get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |indirect2: &IndirectBlock| {
+                    indirect1_blocks.extend_from_slice(&indirect2[..a1]);
                 });
-                // move to next
-                b0 += 1;
-                if b0 == INODE_INDIRECT1_COUNT {
-                    b0 = 0;
-                    a0 += 1;
+            for (a, indirect1) in indirect1_blocks.iter().enumerate() {
+                // a hole may have no indirect1 block
+                if *indirect1 == 0 || !valid(*indirect1) {
+                    continue;
                 }
-            } 
-        });
-    }
-
-    /// Clear size to zero and return blocks that should be deallocated.
-    ///
-    /// We will clear the block contents to zero later.
-    pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
-        let mut v: Vec<u32> = Vec::new();
-        let mut data_blocks = self.data_blocks() as usize;
-        self.size = 0;
-        let mut current_blocks = 0usize;
-        // direct
-        while current_blocks < data_blocks.min(INODE_DIRECT_COUNT) {
-            v.push(self.direct[current_blocks]);
-            self.direct[current_blocks] = 0;
-            current_blocks += 1;
-        }
-        // indirect1 block
+                let count = (last - a * INODE_INDIRECT1_COUNT).min(INODE_INDIRECT1_COUNT);
+                get_block_cache(*indirect1 as usize, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |indirect1: &IndirectBlock| {
+                        v.extend_from_slice(&indirect1[..count]);
+                    });
+            }
+        }
         if data_blocks > INODE_DIRECT_COUNT {
             v.push(self.indirect1);
-            data_blocks -= INODE_DIRECT_COUNT;
-            current_blocks = 0;
-        } else {
-            return v;
         }
-        // indirect1
-        get_block_cache(
-            self.indirect1 as usize,
-            Arc::clone(block_device),
-        )
-        .lock()
-        .modify(0, |indirect1: &mut IndirectBlock| {
-            while current_blocks < data_blocks.min(INODE_INDIRECT1_COUNT) {
-                v.push(indirect1[current_blocks]);
-                //indirect1[current_blocks] = 0;
-                current_blocks += 1;
-            }
-        });
-        self.indirect1 = 0;
-        // indirect2 block
-        if data_blocks > INODE_INDIRECT1_COUNT {
+        if data_blocks > INDIRECT1_BOUND {
             v.push(self.indirect2);
-            data_blocks -= INODE_INDIRECT1_COUNT;
+            v.extend(indirect1_blocks);
+        }
+        // holes
+        v.retain(|block_id| *block_id != 0);
+        v
+    }
+    /// Return the largest size the blocks can be mapped for, and for an
+    /// encrypted file their tags be kept, for a compressed one the cluster
+    /// map.
+    pub fn max_size(&self) -> u32 {
+        let max_size = if self.uses_extents() {
+            u32::MAX
         } else {
-            return v;
+            (INDIRECT2_BOUND * BLOCK_SZ) as u32
+        };
+        if self.is_encrypted() {
+            max_size.min(MAX_ENCRYPTED_SIZE)
+        } else if self.is_compressed() {
+            max_size.min(MAX_COMPRESSED_SIZE)
+        } else {
+            max_size
         }
-        // indirect2
-        assert!(data_blocks <= INODE_INDIRECT2_COUNT);
-        let a1 = data_blocks / INODE_INDIRECT1_COUNT;
-        let b1 = data_blocks % INODE_INDIRECT1_COUNT;
-        get_block_cache(
-            self.indirect2 as usize,
-            Arc::clone(block_device),
-        )
-        .lock()
-        .modify(0, |indirect2: &mut IndirectBlock| {
-            // full indirect1 blocks
-            for i in 0..a1 {
-                v.push(indirect2[i]);
-                get_block_cache(
-                    indirect2[i] as usize,
-                    Arc::clone(block_device),
-                )
+    }
+    /// Grow to `new_size`. The new blocks are holes until they are
+    /// allocated with `alloc_blocks`.
+    pub fn increase_size(&mut self, new_size: u32) {
+        assert!(new_size >= self.size && new_size <= self.max_size());
+        self.size = new_size;
+    }
+    /// Allocate the data blocks `first..end` which are holes, together with
+    /// the indirect blocks or extent leaves to map them.
+    ///
+    /// If that fails part of the way, the blocks allocated so far stay
+    /// mapped.
+    pub fn alloc_blocks(
+        &mut self,
+        first: u32,
+        end: u32,
+        allocator: &mut dyn BlockAllocator,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> FsResult<()> {
+        assert!(end <= self.data_blocks() && !self.is_inline());
+        if self.uses_extents() {
+            return self.alloc_extent_blocks(first, end, allocator, block_device);
+        }
+        for inner_id in first..end {
+            if self.get_block_id(inner_id, block_device) != 0 {
+                continue;
+            }
+            // continue where the previous block lies
+            let goal = match inner_id.checked_sub(1) {
+                Some(prev) => Some(self.get_block_id(prev, block_device)).filter(|id| *id != 0),
+                None => None,
+            }.map(|id| id + 1);
+            self.alloc_block(inner_id, goal, allocator, block_device)?;
+        }
+        Ok(())
+    }
+    /// Map a data block at `inner_id`, which is a hole.
+    fn alloc_block(
+        &mut self,
+        inner_id: u32,
+        goal: Option<u32>,
+        allocator: &mut dyn BlockAllocator,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> FsResult<()> {
+        let inner_id = inner_id as usize;
+        if inner_id < INODE_DIRECT_COUNT {
+            self.direct[inner_id] = allocator.alloc_run(goal, 1)?.0;
+            return Ok(());
+        }
+        // indirect blocks on the way come first, so that a data block is
+        // never left without a pointer to it
+        if inner_id < INDIRECT1_BOUND {
+            if self.indirect1 == 0 {
+                self.indirect1 = allocator.alloc_run(None, 1)?.0;
+            }
+            let block_id = allocator.alloc_run(goal, 1)?.0;
+            get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
                 .lock()
                 .modify(0, |indirect1: &mut IndirectBlock| {
-                    for j in 0..INODE_INDIRECT1_COUNT {
-                        v.push(indirect1[j]);
-                        //indirect1[j] = 0;
-                    }
+                    indirect1[inner_id - INODE_DIRECT_COUNT] = block_id;
                 });
-                //indirect2[i] = 0;
+            return Ok(());
+        }
+        if self.indirect2 == 0 {
+            self.indirect2 = allocator.alloc_run(None, 1)?.0;
+        }
+        let last = inner_id - INDIRECT1_BOUND;
+        let indirect2 = get_block_cache(self.indirect2 as usize, Arc::clone(block_device));
+        let mut indirect1 = indirect2
+            .lock()
+            .read(0, |indirect2: &IndirectBlock| indirect2[last / INODE_INDIRECT1_COUNT]);
+        if indirect1 == 0 {
+            indirect1 = allocator.alloc_run(None, 1)?.0;
+            indirect2.lock().modify(0, |indirect2: &mut IndirectBlock| {
+                indirect2[last / INODE_INDIRECT1_COUNT] = indirect1;
+            });
+        }
+        let block_id = allocator.alloc_run(goal, 1)?.0;
+        get_block_cache(indirect1 as usize, Arc::clone(block_device))
+            .lock()
+            .modify(0, |indirect1: &mut IndirectBlock| {
+                indirect1[last % INODE_INDIRECT1_COUNT] = block_id;
+            });
+        Ok(())
+    }
+    /// `alloc_blocks` for inodes using extents, filling each hole with as
+    /// few runs as possible.
+    fn alloc_extent_blocks(
+        &mut self,
+        first: u32,
+        end: u32,
+        allocator: &mut dyn BlockAllocator,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> FsResult<()> {
+        let mut extents = self.read_extents(block_device, |_| true);
+        let mut leaves = self.extent_leaves();
+        let mut inner_id = first;
+        let mut result = Ok(());
+        while inner_id < end {
+            if let Some(extent) = extents.iter().find(|extent| extent.map(inner_id).is_some()) {
+                inner_id = extent.end();
+                continue;
             }
-            // last indirect1 block
-            if b1 > 0 {
-                v.push(indirect2[a1]);
-                get_block_cache(
-                    indirect2[a1] as usize,
-                    Arc::clone(block_device),
-                )
+            let hole_end = extents
+                .iter()
+                .map(|extent| extent.logical)
+                .filter(|logical| *logical > inner_id)
+                .min()
+                .map_or(end, |logical| logical.min(end));
+            let goal = extents
+                .iter()
+                .find(|extent| extent.end() == inner_id)
+                .map(|extent| extent.physical + extent.len);
+            let (physical, len) = match allocator.alloc_run(goal, hole_end - inner_id) {
+                Ok(run) => run,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            };
+            let old_extents = extents.clone();
+            insert_extent(&mut extents, Extent { logical: inner_id, physical, len });
+            let leaves_added = match extent_leaves_for(extents.len()) {
+                None => Err(FsError::NoSpace),
+                Some(needed) if needed > leaves.len() => {
+                    allocator.alloc_run(None, 1).map(|(leaf, _)| leaves.push(leaf))
+                }
+                Some(_) => Ok(()),
+            };
+            if let Err(err) = leaves_added {
+                // the run cannot be mapped
+                extents = old_extents;
+                for block_id in physical..physical + len {
+                    allocator.dealloc(block_id);
+                }
+                result = Err(err);
+                break;
+            }
+            inner_id += len;
+        }
+        self.store_extents(&extents, &leaves, block_device);
+        result
+    }
+    /// Free the data blocks `first..end`, which become holes. Indirect blocks
+    /// stay even if they map nothing any more.
+    ///
+    /// Splitting an extent may take one more leaf, which can fail.
+    pub fn dealloc_blocks(
+        &mut self,
+        first: u32,
+        end: u32,
+        allocator: &mut dyn BlockAllocator,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> FsResult<()> {
+        assert!(!self.is_inline());
+        let end = end.min(self.data_blocks());
+        if self.uses_extents() {
+            let mut extents: Vec<Extent> = Vec::new();
+            let mut freed: Vec<u32> = Vec::new();
+            for extent in self.read_extents(block_device, |_| true) {
+                if extent.end() <= first || extent.logical >= end {
+                    extents.push(extent);
+                    continue;
+                }
+                if extent.logical < first {
+                    extents.push(Extent { len: first - extent.logical, ..extent });
+                }
+                if extent.end() > end {
+                    let skip = end - extent.logical;
+                    extents.push(Extent { logical: end, physical: extent.physical + skip, len: extent.len - skip });
+                }
+                let start = first.max(extent.logical) - extent.logical;
+                freed.extend(extent.physical + start..extent.physical + end.min(extent.end()) - extent.logical);
+            }
+            let mut leaves = self.extent_leaves();
+            match extent_leaves_for(extents.len()) {
+                None => return Err(FsError::NoSpace),
+                Some(needed) if needed > leaves.len() => leaves.push(allocator.alloc_run(None, 1)?.0),
+                Some(needed) => freed.extend(leaves.split_off(needed)),
+            }
+            self.store_extents(&extents, &leaves, block_device);
+            freed.into_iter().for_each(|block_id| allocator.dealloc(block_id));
+            return Ok(());
+        }
+        for inner_id in first..end {
+            let block_id = self.get_block_id(inner_id, block_device);
+            if block_id == 0 {
+                continue;
+            }
+            self.set_block_id(inner_id as usize, 0, block_device);
+            allocator.dealloc(block_id);
+        }
+        Ok(())
+    }
+    /// Make the blocks mapping the data blocks `first..end` safe to change:
+    /// the indirect blocks on the way to them, or all extent leaves, which
+    /// any change of the extents may rewrite. With `data` set, the data
+    /// blocks themselves as well. Blocks `allocator` says are shared are
+    /// replaced with copies, see `copy_block`.
+    pub fn copy_on_write(
+        &mut self,
+        first: u32,
+        end: u32,
+        data: bool,
+        allocator: &mut dyn BlockAllocator,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> FsResult<()> {
+        if self.is_inline() || first >= end {
+            return Ok(());
+        }
+        if self.uses_extents() {
+            return self.copy_extents_on_write(first, end, data, allocator, block_device);
+        }
+        let end = end.min(INDIRECT2_BOUND as u32) as usize;
+        let first = first as usize;
+        if first < INDIRECT1_BOUND && end > DIRECT_BOUND {
+            self.indirect1 = copy_block(self.indirect1, None, allocator, block_device)?;
+        }
+        if end > INDIRECT1_BOUND && self.indirect2 != 0 {
+            self.indirect2 = copy_block(self.indirect2, None, allocator, block_device)?;
+            let first = first.max(INDIRECT1_BOUND) - INDIRECT1_BOUND;
+            let last = end - INDIRECT1_BOUND;
+            let indirect2 = get_block_cache(self.indirect2 as usize, Arc::clone(block_device));
+            let count = INODE_INDIRECT1_COUNT;
+            for a in first / count..(last + count - 1) / count {
+                let indirect1 = indirect2.lock().read(0, |indirect2: &IndirectBlock| indirect2[a]);
+                let copy = copy_block(indirect1, None, allocator, block_device)?;
+                if copy != indirect1 {
+                    indirect2.lock().modify(0, |indirect2: &mut IndirectBlock| indirect2[a] = copy);
+                }
+            }
+        }
+        if !data {
+            return Ok(());
+        }
+        let mut goal = None;
+        for inner_id in first..end {
+            let block_id = self.get_block_id(inner_id as u32, block_device);
+            let copy = copy_block(block_id, goal, allocator, block_device)?;
+            if copy != block_id {
+                self.set_block_id(inner_id, copy, block_device);
+                goal = Some(copy + 1);
+            }
+        }
+        Ok(())
+    }
+    /// `copy_on_write` for inodes using extents. Copied data blocks are
+    /// taken out of their extents and mapped by new ones.
+    fn copy_extents_on_write(
+        &mut self,
+        first: u32,
+        end: u32,
+        data: bool,
+        allocator: &mut dyn BlockAllocator,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> FsResult<()> {
+        let mut extents = self.read_extents(block_device, |_| true);
+        let old_leaves = self.extent_leaves();
+        let mut copies: Vec<Extent> = Vec::new();
+        if data {
+            for extent in extents.iter().filter(|extent| extent.end() > first && extent.logical < end) {
+                for inner_id in first.max(extent.logical)..end.min(extent.end()) {
+                    let block_id = extent.physical + inner_id - extent.logical;
+                    let goal = copies.last().map(|copy| copy.physical + copy.len);
+                    let copy = copy_block(block_id, goal, allocator, block_device)?;
+                    if copy == block_id {
+                        continue;
+                    }
+                    match copies.last_mut() {
+                        Some(last) if last.end() == inner_id && last.physical + last.len == copy => {
+                            last.len += 1;
+                        }
+                        _ => copies.push(Extent { logical: inner_id, physical: copy, len: 1 }),
+                    }
+                }
+            }
+        }
+        let shared_leaves = old_leaves.iter().any(|leaf| allocator.is_shared(*leaf));
+        if copies.is_empty() && !shared_leaves {
+            return Ok(());
+        }
+        for copy in copies {
+            let (start, end) = (copy.logical, copy.end());
+            let mut split: Vec<Extent> = Vec::with_capacity(extents.len() + 1);
+            for extent in extents {
+                if extent.end() <= start || extent.logical >= end {
+                    split.push(extent);
+                    continue;
+                }
+                if extent.logical < start {
+                    split.push(Extent { len: start - extent.logical, ..extent });
+                }
+                if extent.end() > end {
+                    let skip = end - extent.logical;
+                    split.push(Extent { logical: end, physical: extent.physical + skip, len: extent.len - skip });
+                }
+            }
+            extents = split;
+            insert_extent(&mut extents, copy);
+        }
+        // leaves are rewritten from the extents, new ones need no copying
+        let mut leaves = Vec::with_capacity(old_leaves.len());
+        for leaf in old_leaves.iter() {
+            if allocator.is_shared(*leaf) {
+                leaves.push(allocator.alloc_run(None, 1)?.0);
+                allocator.dealloc(*leaf);
+            } else {
+                leaves.push(*leaf);
+            }
+        }
+        match extent_leaves_for(extents.len()) {
+            None => return Err(FsError::NoSpace),
+            Some(needed) if needed > leaves.len() => leaves.push(allocator.alloc_run(None, 1)?.0),
+            Some(needed) => leaves.split_off(needed).into_iter().for_each(|leaf| allocator.dealloc(leaf)),
+        }
+        self.store_extents(&extents, &leaves, block_device);
+        Ok(())
+    }
+    /// Point the data block `inner_id` to `block_id`, where the indirect
+    /// block it goes through exists already.
+    fn set_block_id(&mut self, inner_id: usize, block_id: u32, block_device: &Arc<dyn BlockDevice>) {
+        let (indirect1, index) = if inner_id < INODE_DIRECT_COUNT {
+            self.direct[inner_id] = block_id;
+            return;
+        } else if inner_id < INDIRECT1_BOUND {
+            (self.indirect1, inner_id - INODE_DIRECT_COUNT)
+        } else {
+            let last = inner_id - INDIRECT1_BOUND;
+            let indirect1 = get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
                 .lock()
-                .modify(0, |indirect1: &mut IndirectBlock| {
-                    for j in 0..b1 {
-                        v.push(indirect1[j]);
-                        //indirect1[j] = 0;
+                .read(0, |indirect2: &IndirectBlock| indirect2[last / INODE_INDIRECT1_COUNT]);
+            (indirect1, last % INODE_INDIRECT1_COUNT)
+        };
+        get_block_cache(indirect1 as usize, Arc::clone(block_device))
+            .lock()
+            .modify(0, |indirect1: &mut IndirectBlock| indirect1[index] = block_id);
+    }
+    /// Return the first data block from `inner_id` on which is allocated if
+    /// `data` is set or a hole otherwise, or `data_blocks()` if there is none.
+    pub fn seek_block(
+        &self,
+        inner_id: u32,
+        data: bool,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> u32 {
+        let end = self.data_blocks();
+        if self.is_inline() {
+            return if data { inner_id.min(end) } else { end };
+        }
+        if self.uses_extents() {
+            let extents = self.read_extents(block_device, |_| true);
+            let mut inner_id = inner_id;
+            while inner_id < end {
+                match extents.iter().find(|extent| extent.end() > inner_id) {
+                    Some(extent) if extent.logical <= inner_id => {
+                        if data {
+                            return inner_id;
+                        }
+                        inner_id = extent.end();
+                    }
+                    Some(extent) => {
+                        return if data { extent.logical.min(end) } else { inner_id };
+                    }
+                    None => return if data { end } else { inner_id },
+                }
+            }
+            return end;
+        }
+        let mut inner_id = inner_id;
+        while inner_id < end {
+            // whole ranges without an indirect block are holes
+            let skip_to = if inner_id as usize >= INDIRECT1_BOUND && self.indirect2 == 0 {
+                Some(end)
+            } else if inner_id as usize >= DIRECT_BOUND
+                && (inner_id as usize) < INDIRECT1_BOUND
+                && self.indirect1 == 0 {
+                Some(INDIRECT1_BOUND as u32)
+            } else {
+                None
+            };
+            match skip_to {
+                Some(_) if !data => return inner_id,
+                Some(skip_to) => inner_id = skip_to,
+                None => {
+                    if (self.get_block_id(inner_id, block_device) != 0) == data {
+                        return inner_id;
                     }
+                    inner_id += 1;
+                }
+            }
+        }
+        end
+    }
+
+    /// Shrink size to `new_size` and return blocks that should be deallocated,
+    /// including indirect1/2 blocks which are not needed any more.
+    pub fn decrease_size(
+        &mut self,
+        new_size: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u32> {
+        assert!(new_size <= self.size);
+        let mut v: Vec<u32> = Vec::new();
+        if self.is_inline() {
+            // bytes past the end read as zeros when the file grows again
+            let mut data = self.inline_data();
+            let end = (new_size as usize).min(INLINE_DATA_CAPACITY);
+            data[end..].iter_mut().for_each(|v| *v = 0);
+            self.set_inline_data(&data);
+            self.size = new_size;
+            return v;
+        }
+        if self.uses_extents() {
+            let new_data_blocks = Self::_data_blocks(new_size);
+            let mut extents = self.read_extents(block_device, |_| true);
+            for extent in extents.iter_mut().filter(|extent| extent.end() > new_data_blocks) {
+                let keep = new_data_blocks.saturating_sub(extent.logical);
+                v.extend(extent.physical + keep..extent.physical + extent.len);
+                extent.len = keep;
+            }
+            extents.retain(|extent| extent.len > 0);
+            let mut leaves = self.extent_leaves();
+            v.extend(leaves.split_off(extent_leaves_for(extents.len()).unwrap()));
+            self.size = new_size;
+            self.store_extents(&extents, &leaves, block_device);
+            return v;
+        }
+        let data_blocks = self.data_blocks() as usize;
+        let new_data_blocks = Self::_data_blocks(new_size) as usize;
+        // data blocks past the new end
+        for inner_id in new_data_blocks..data_blocks {
+            let block_id = self.get_block_id(inner_id as u32, block_device);
+            if block_id != 0 {
+                v.push(block_id);
+            }
+        }
+        // pointers to them are cleared, the range is a hole if the file
+        // grows again
+        for inner_id in new_data_blocks..data_blocks.min(INODE_DIRECT_COUNT) {
+            self.direct[inner_id] = 0;
+        }
+        if data_blocks > INODE_DIRECT_COUNT && self.indirect1 != 0 {
+            if new_data_blocks <= INODE_DIRECT_COUNT {
+                v.push(self.indirect1);
+                self.indirect1 = 0;
+            } else if new_data_blocks < INDIRECT1_BOUND {
+                get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
+                    .lock()
+                    .modify(0, |indirect1: &mut IndirectBlock| {
+                        let end = data_blocks.min(INDIRECT1_BOUND) - INODE_DIRECT_COUNT;
+                        indirect1[new_data_blocks - INODE_DIRECT_COUNT..end]
+                            .iter_mut()
+                            .for_each(|v| *v = 0);
+                    });
+            }
+        }
+        if data_blocks > INDIRECT1_BOUND && self.indirect2 != 0 {
+            let first = new_data_blocks.max(INDIRECT1_BOUND) - INDIRECT1_BOUND;
+            let last = data_blocks - INDIRECT1_BOUND;
+            // low-level indirect1 blocks which become empty
+            let a0 = (first + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
+            let a1 = (last + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
+            let indirect2 = get_block_cache(self.indirect2 as usize, Arc::clone(block_device));
+            let mut indirect2 = indirect2.lock();
+            let partial = indirect2.read(0, |indirect2: &IndirectBlock| {
+                v.extend(indirect2[a0..a1].iter().filter(|block_id| **block_id != 0));
+                if first % INODE_INDIRECT1_COUNT != 0 {
+                    Some(indirect2[first / INODE_INDIRECT1_COUNT]).filter(|block_id| *block_id != 0)
+                } else {
+                    None
+                }
+            });
+            if new_data_blocks <= INDIRECT1_BOUND {
+                v.push(self.indirect2);
+                self.indirect2 = 0;
+            } else {
+                indirect2.modify(0, |indirect2: &mut IndirectBlock| {
+                    indirect2[a0..a1].iter_mut().for_each(|v| *v = 0);
                 });
-                //indirect2[a1] = 0;
+                // the one left partly used
+                if let Some(indirect1) = partial {
+                    get_block_cache(indirect1 as usize, Arc::clone(block_device))
+                        .lock()
+                        .modify(0, |indirect1: &mut IndirectBlock| {
+                            let end = (last - first / INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COUNT)
+                                .min(INODE_INDIRECT1_COUNT);
+                            indirect1[first % INODE_INDIRECT1_COUNT..end]
+                                .iter_mut()
+                                .for_each(|v| *v = 0);
+                        });
+                }
             }
-        });
-        self.indirect2 = 0;
+        }
+        self.size = new_size;
         v
     }
     pub fn read_at(
@@ -335,6 +1305,12 @@ impl DiskInode {
         if start >= end {
             return 0;
         }
+        if self.is_inline() {
+            let end = end.min(INLINE_DATA_CAPACITY);
+            let len = end.saturating_sub(start);
+            buf[..len].copy_from_slice(&self.inline_data()[start..start + len]);
+            return len;
+        }
         let mut start_block = start / BLOCK_SZ;
         let mut read_size = 0usize;
         loop {
@@ -344,15 +1320,19 @@ impl DiskInode {
             // read and update read size
             let block_read_size = end_current_block - start;
             let dst = &mut buf[read_size..read_size + block_read_size];
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .read(0, |data_block: &DataBlock| {
-                let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
-                dst.copy_from_slice(src);
-            });
+            match self.get_block_id(start_block as u32, block_device) {
+                // a hole
+                0 => dst.iter_mut().for_each(|byte| *byte = 0),
+                block_id => get_block_cache(
+                    block_id as usize,
+                    Arc::clone(block_device),
+                )
+                .lock()
+                .read(0, |data_block: &DataBlock| {
+                    let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
+                    dst.copy_from_slice(src);
+                }),
+            }
             read_size += block_read_size;
             // move to next block
             if end_current_block == end { break; }
@@ -361,7 +1341,10 @@ impl DiskInode {
         }
         read_size
     }
-    /// File size must be adjusted before.
+    /// File size must be adjusted and the blocks allocated before.
+    ///
+    /// Data of a regular file is written through to the disk at once rather
+    /// than going through the journal.
     pub fn write_at(
         &mut self,
         offset: usize,
@@ -371,6 +1354,12 @@ impl DiskInode {
         let mut start = offset;
         let end = (offset + buf.len()).min(self.size as usize);
         assert!(start <= end);
+        if self.is_inline() {
+            let mut data = self.inline_data();
+            data[start..end].copy_from_slice(&buf[..end - start]);
+            self.set_inline_data(&data);
+            return end - start;
+        }
         let mut start_block = start / BLOCK_SZ;
         let mut write_size = 0usize;
         loop {
@@ -379,16 +1368,21 @@ impl DiskInode {
             end_current_block = end_current_block.min(end);
             // write and update write size
             let block_write_size = end_current_block - start;
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
+            let block_id = self.get_block_id(start_block as u32, block_device);
+            assert_ne!(block_id, 0, "Writing to a hole!");
+            let block_cache = get_block_cache(
+                block_id as usize,
                 Arc::clone(block_device)
-            )
-            .lock()
-            .modify(0, |data_block: &mut DataBlock| {
+            );
+            let mut block_cache = block_cache.lock();
+            block_cache.modify(0, |data_block: &mut DataBlock| {
                 let src = &buf[write_size..write_size + block_write_size];
                 let dst = &mut data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_write_size];
                 dst.copy_from_slice(src);
             });
+            if self.is_file() {
+                block_cache.sync();
+            }
             write_size += block_write_size;
             // move to next block
             if end_current_block == end { break; }
@@ -399,6 +1393,62 @@ impl DiskInode {
     }
 }
 
+/// Number of home block IDs a journal header has room for.
+pub const JOURNAL_CAPACITY: usize = BLOCK_SZ / 4 - 2;
+
+/// First block of the journal, followed by copies of the blocks of one
+/// transaction. The transaction is committed once a header with the magic
+/// has been written.
+#[repr(C)]
+pub struct JournalHeader {
+    magic: u32,
+    count: u32,
+    block_ids: [u32; JOURNAL_CAPACITY],
+}
+
+impl JournalHeader {
+    pub fn empty() -> Self {
+        Self {
+            magic: 0,
+            count: 0,
+            block_ids: [0; JOURNAL_CAPACITY],
+        }
+    }
+    pub fn new() -> Self {
+        Self {
+            magic: JOURNAL_MAGIC,
+            ..Self::empty()
+        }
+    }
+    pub fn is_committed(&self) -> bool {
+        self.magic == JOURNAL_MAGIC
+    }
+    /// Add the home location of the next block in the log.
+    pub fn push(&mut self, block_id: u32) {
+        self.block_ids[self.count as usize] = block_id;
+        self.count += 1;
+    }
+    pub fn block_ids(&self) -> &[u32] {
+        &self.block_ids[..(self.count as usize).min(JOURNAL_CAPACITY)]
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self as *const _ as usize as *const u8,
+                BLOCK_SZ,
+            )
+        }
+    }
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                self as *mut _ as usize as *mut u8,
+                BLOCK_SZ,
+            )
+        }
+    }
+}
+
 #[repr(C)]
 pub struct DirEntry {
     name: [u8; NAME_LENGTH_LIMIT + 1],
@@ -414,9 +1464,11 @@ impl DirEntry {
             inode_number: 0,
         }
     }
+    /// `name` must not be longer than `NAME_LENGTH_LIMIT`.
     pub fn new(name: &str, inode_number: u32) -> Self {
+        assert!(name.len() <= NAME_LENGTH_LIMIT, "Name too long for a DirEntry!");
         let mut bytes = [0u8; NAME_LENGTH_LIMIT + 1];
-        &mut bytes[..name.len()].copy_from_slice(name.as_bytes());
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
         Self {
             name: bytes,
             inode_number,
@@ -438,11 +1490,60 @@ impl DirEntry {
             )
         }
     }
-    pub fn name(&self) -> &str {
-        let len = (0usize..).find(|i| self.name[*i] == 0).unwrap();
-        core::str::from_utf8(&self.name[..len]).unwrap()
+    /// An empty name marks a free slot left behind by a removed entry.
+    pub fn is_empty(&self) -> bool {
+        self.name[0] == 0
+    }
+    pub fn name(&self) -> &[u8] {
+        let len = self.name.iter().position(|byte| *byte == 0).unwrap_or(self.name.len());
+        &self.name[..len]
     }
     pub fn inode_number(&self) -> u32 {
         self.inode_number
     }
+    pub fn set_inode_number(&mut self, inode_number: u32) {
+        self.inode_number = inode_number;
+    }
+}
+
+/// Header of a directory entry with `FEATURE_LONG_NAMES`, the name follows
+/// right after it.
+///
+/// Records are aligned to 4 bytes and never cross a block boundary.
+/// `rec_len` reaches up to the next record, so the records of a block cover
+/// all of it. A record with an empty name is free.
+pub struct DirRecord {
+    pub inode_number: u32,
+    pub rec_len: u16,
+    pub name_len: u8,
+}
+
+pub const DIR_RECORD_SZ: usize = 8;
+
+impl DirRecord {
+    pub fn new(inode_number: u32, rec_len: usize, name_len: usize) -> Self {
+        assert!(name_len <= LONG_NAME_LENGTH_LIMIT, "Name too long for a DirRecord!");
+        Self {
+            inode_number,
+            rec_len: rec_len as u16,
+            name_len: name_len as u8,
+        }
+    }
+    /// Bytes taken by a record with a name of `name_len` bytes.
+    pub fn size_for(name_len: usize) -> usize {
+        (DIR_RECORD_SZ + name_len + 3) / 4 * 4
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            inode_number: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            rec_len: u16::from_le_bytes([bytes[4], bytes[5]]),
+            name_len: bytes[6],
+        }
+    }
+    pub fn write_to(&self, bytes: &mut [u8]) {
+        bytes[0..4].copy_from_slice(&self.inode_number.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.rec_len.to_le_bytes());
+        bytes[6] = self.name_len;
+        bytes[7] = 0;
+    }
 }
\ No newline at end of file
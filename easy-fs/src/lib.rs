@@ -3,16 +3,40 @@
 extern crate alloc;
 
 mod block_dev;
+mod error;
 mod layout;
+mod dir;
 mod efs;
 mod bitmap;
 mod vfs;
 mod block_cache;
+mod journal;
+mod fsck;
+mod checksum;
+mod resize;
+mod snapshot;
+mod xattr;
+mod lz4;
+mod compress;
+mod aead;
+mod crypt;
+mod quota;
 
 pub const BLOCK_SZ: usize = 512;
 pub use block_dev::BlockDevice;
-pub use efs::EasyFileSystem;
-pub use vfs::Inode;
+pub use error::{FsError, Result};
+pub use efs::{EasyFileSystem, FsStat};
+pub use vfs::{Inode, Stat};
+pub use layout::DiskInodeType;
+pub use fsck::{fsck, Problem};
+pub use resize::resize;
+pub use snapshot::{Snapshot, SNAPSHOT_NAME_LIMIT};
+pub use xattr::XATTR_NAME_LIMIT;
+pub use aead::{KEY_SZ, SALT_SZ};
+pub use quota::{Quota, QuotaLimit, QuotaUsage, QUOTA_GRACE};
+pub use block_cache::{block_cache_sync_all, set_block_cache_capacity};
 use layout::*;
 use bitmap::Bitmap;
-use block_cache::get_block_cache;
\ No newline at end of file
+use dir::DirFormat;
+use block_cache::{get_block_cache, modified_block_caches};
+use journal::{Journal, Transaction};
\ No newline at end of file
@@ -0,0 +1,197 @@
+use alloc::vec::Vec;
+
+/// Shortest match the format can express.
+const MIN_MATCH: usize = 4;
+/// The last match has to start this far before the end of the input.
+const MF_LIMIT: usize = 12;
+/// The input always ends with at least this many literals.
+const LAST_LITERALS: usize = 5;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_LOG: u32 = 12;
+
+fn read_u32(input: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([input[pos], input[pos + 1], input[pos + 2], input[pos + 3]])
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+/// Append a length which did not fit into its 4 bits of the token.
+fn write_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+/// Append a sequence of `literals` followed by a match of `match_len` bytes
+/// `offset` bytes back, or by nothing if it is the last one.
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], found: Option<(usize, usize)>) {
+    let match_code = found.map_or(0, |(_, match_len)| match_len - MIN_MATCH);
+    out.push((literals.len().min(15) << 4 | match_code.min(15)) as u8);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = found {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_code >= 15 {
+            write_length(out, match_code - 15);
+        }
+    }
+}
+
+/// Compress `input` into an LZ4 block, without the frame around it.
+///
+/// Matches are found greedily through a table of the last position of
+/// each hashed 4-byte sequence, which is fast and good enough for binaries.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut table = [0usize; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+    while pos + MF_LIMIT < input.len() {
+        let sequence = read_u32(input, pos);
+        let slot = &mut table[hash(sequence)];
+        // positions are stored plus one, zero is an empty slot
+        let candidate = slot.checked_sub(1);
+        *slot = pos + 1;
+        match candidate {
+            Some(start) if pos - start <= MAX_OFFSET && read_u32(input, start) == sequence => {
+                let limit = input.len() - LAST_LITERALS;
+                let mut match_len = MIN_MATCH;
+                while pos + match_len < limit && input[start + match_len] == input[pos + match_len] {
+                    match_len += 1;
+                }
+                write_sequence(&mut out, &input[anchor..pos], Some((pos - start, match_len)));
+                pos += match_len;
+                anchor = pos;
+            }
+            _ => pos += 1,
+        }
+    }
+    write_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+/// Read a length continued past its 4 bits of the token.
+fn read_length(input: &[u8], pos: &mut usize, mut len: usize) -> Option<usize> {
+    if len == 15 {
+        loop {
+            let byte = *input.get(*pos)?;
+            *pos += 1;
+            len += byte as usize;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Some(len)
+}
+
+/// Decompress the LZ4 block `input` into `out` and return how many bytes it
+/// produced, or `None` if it is malformed or does not fit.
+pub fn decompress(input: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut pos = 0;
+    let mut out_pos = 0;
+    loop {
+        let token = *input.get(pos)? as usize;
+        pos += 1;
+        let literals = read_length(input, &mut pos, token >> 4)?;
+        out.get_mut(out_pos..out_pos + literals)?
+            .copy_from_slice(input.get(pos..pos + literals)?);
+        pos += literals;
+        out_pos += literals;
+        if pos == input.len() {
+            return Some(out_pos);
+        }
+        let offset = u16::from_le_bytes([*input.get(pos)?, *input.get(pos + 1)?]) as usize;
+        pos += 2;
+        let match_len = read_length(input, &mut pos, token & 15)? + MIN_MATCH;
+        if offset == 0 || offset > out_pos || out_pos + match_len > out.len() {
+            return None;
+        }
+        // byte by byte, a match may overlap what it produces
+        for i in out_pos..out_pos + match_len {
+            out[i] = out[i - offset];
+        }
+        out_pos += match_len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn noise(len: usize) -> Vec<u8> {
+        let mut seed = 1u32;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (seed >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn round_trip(input: &[u8]) -> usize {
+        let packed = compress(input);
+        let mut out = vec![0u8; input.len()];
+        assert_eq!(decompress(&packed, &mut out), Some(input.len()));
+        assert!(out == input);
+        packed.len()
+    }
+
+    #[test]
+    fn round_trip_test() {
+        round_trip(&[]);
+        round_trip(b"a");
+        round_trip(&noise(MF_LIMIT + 1));
+        // matches overlapping what they produce, and of every length code
+        assert!(round_trip(&[7u8; 4096]) < 64);
+        for len in 1..40 {
+            let text: Vec<u8> = b"abcdefgh".iter().cycle().take(len * 10).copied().collect();
+            round_trip(&text);
+        }
+        // incompressible data grows by little
+        let input = noise(4096);
+        assert!(round_trip(&input) <= input.len() + input.len() / 255 + 16);
+        // a repetition further back than an offset reaches
+        let mut input = noise(1000);
+        input.extend(vec![0u8; MAX_OFFSET]);
+        input.extend(noise(1000));
+        round_trip(&input);
+    }
+
+    #[test]
+    fn malformed_test() {
+        let mut out = [0u8; 64];
+        assert_eq!(decompress(&[], &mut out), None);
+        // more literals than follow
+        assert_eq!(decompress(&[0x50, b'a'], &mut out), None);
+        // a length continued past the end
+        assert_eq!(decompress(&[0xf0, 255], &mut out), None);
+        // an offset of zero, and one before the start
+        assert_eq!(decompress(&[0x10, b'a', 0, 0], &mut out), None);
+        assert_eq!(decompress(&[0x10, b'a', 2, 0], &mut out), None);
+        // more than fits
+        assert_eq!(decompress(&[0x1f, b'a', 1, 0, 255, 10], &mut out), None);
+        assert_eq!(decompress(&compress(&[1u8; 65]), &mut out), None);
+    }
+
+    #[test]
+    fn truncated_test() {
+        let mut input: Vec<u8> = b"an elf binary, ".iter().cycle().take(2000).copied().collect();
+        input.extend(noise(500));
+        let packed = compress(&input);
+        let mut out = vec![0u8; input.len()];
+        // cut anywhere, it fails or falls short
+        for len in 0..packed.len() {
+            if let Some(produced) = decompress(&packed[..len], &mut out) {
+                assert!(produced < input.len());
+            }
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use super::{
+    EasyFileSystem,
+    SuperBlock,
+    DiskInode,
+    FsError,
+    Result,
+    BLOCK_SZ,
+    FEATURE_QUOTA,
+    get_block_cache,
+};
+
+/// Seconds a soft limit may be exceeded before it is enforced like a hard
+/// one.
+pub const QUOTA_GRACE: u32 = 7 * 24 * 60 * 60;
+
+/// Soft and hard limit on the blocks or the inodes of a uid, zero for none.
+///
+/// Past the soft limit allocating still succeeds for `QUOTA_GRACE`, past the
+/// hard limit it fails with `QuotaExceeded` right away.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaLimit {
+    pub soft: u32,
+    pub hard: u32,
+}
+
+/// How many blocks or inodes a uid uses, and its limits on them.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub used: u32,
+    pub limit: QuotaLimit,
+    /// Until when the soft limit is tolerated, zero while it is kept.
+    pub grace: u32,
+}
+
+/// The quota of a uid as listed by `EasyFileSystem::quotas`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub uid: u32,
+    /// Data blocks of the inodes the uid owns, including indirect blocks,
+    /// extent leaves and attribute blocks.
+    pub blocks: QuotaUsage,
+    pub inodes: QuotaUsage,
+}
+
+/// An entry of a record block.
+///
+/// `SuperBlock::quota_table` lists the record blocks. Every allocation and
+/// free of a data block or an inode is charged to the owner set with
+/// `EasyFileSystem::charge_to`, the uid of the inode it is for. The table
+/// and the record blocks themselves are charged to nobody.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct QuotaRecord {
+    /// Zero for a free entry.
+    in_use: u32,
+    quota: Quota,
+}
+
+const RECORDS_PER_BLOCK: usize = BLOCK_SZ / core::mem::size_of::<QuotaRecord>();
+const RECORD_BLOCKS: usize = BLOCK_SZ / 4;
+
+type RecordBlock = [QuotaRecord; RECORDS_PER_BLOCK];
+type RecordBlockList = [u32; RECORD_BLOCKS];
+
+/// Where a record lies, its block and its index in there.
+type RecordPos = (u32, usize);
+
+impl QuotaUsage {
+    /// How many more may be charged at `now`, `u32::MAX` for no limit.
+    fn room(&self, now: u32) -> u32 {
+        if self.grace != 0 && now >= self.grace {
+            return 0;
+        }
+        if self.limit.hard == 0 {
+            u32::MAX
+        } else {
+            self.limit.hard.saturating_sub(self.used)
+        }
+    }
+
+    /// Start the grace period once the soft limit is exceeded, and end it
+    /// once it is kept again.
+    fn settle(&mut self, now: u32) {
+        if self.limit.soft == 0 || self.used <= self.limit.soft {
+            self.grace = 0;
+        } else if self.grace == 0 {
+            self.grace = now.saturating_add(QUOTA_GRACE);
+        }
+    }
+}
+
+impl EasyFileSystem {
+    fn quota_table(&self) -> u32 {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.quota_table)
+    }
+
+    /// Return the blocks of the quota records, the table listing them
+    /// first. There are none until `set_quota` is first called.
+    pub(crate) fn quota_blocks(&self) -> Vec<u32> {
+        let table = self.quota_table();
+        if table == 0 {
+            return Vec::new();
+        }
+        let mut blocks = alloc::vec![table];
+        get_block_cache(table as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |record_blocks: &RecordBlockList| {
+                blocks.extend(record_blocks.iter().filter(|block_id| **block_id != 0));
+            });
+        blocks
+    }
+
+    fn quota_records(&self) -> Vec<(RecordPos, Quota)> {
+        let mut records = Vec::new();
+        for block_id in self.quota_blocks().into_iter().skip(1) {
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .read(0, |block: &RecordBlock| {
+                    for (i, record) in block.iter().enumerate().filter(|(_, record)| record.in_use != 0) {
+                        records.push(((block_id, i), record.quota));
+                    }
+                });
+        }
+        records
+    }
+
+    fn read_quota(&self, (block_id, i): RecordPos) -> Quota {
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |block: &RecordBlock| block[i].quota)
+    }
+
+    fn modify_quota<V>(&self, (block_id, i): RecordPos, f: impl FnOnce(&mut Quota) -> V) -> V {
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |block: &mut RecordBlock| f(&mut block[i].quota))
+    }
+
+    fn find_quota(&self, uid: u32) -> Option<(RecordPos, Quota)> {
+        self.quota_records().into_iter().find(|(_, quota)| quota.uid == uid)
+    }
+
+    /// Return the record of `uid`, adding one if it has none yet.
+    fn quota_record(&mut self, uid: u32) -> Result<RecordPos> {
+        if let Some((pos, _)) = self.find_quota(uid) {
+            return Ok(pos);
+        }
+        let table = self.quota_table();
+        let record_blocks = get_block_cache(table as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |record_blocks: &RecordBlockList| *record_blocks);
+        let mut free = None;
+        for block_id in record_blocks.iter().filter(|block_id| **block_id != 0) {
+            free = get_block_cache(*block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .read(0, |block: &RecordBlock| block.iter().position(|record| record.in_use == 0))
+                .map(|i| (*block_id, i));
+            if free.is_some() {
+                break;
+            }
+        }
+        let (block_id, i) = match free {
+            Some(pos) => pos,
+            None => {
+                let slot = record_blocks
+                    .iter()
+                    .position(|block_id| *block_id == 0)
+                    .ok_or(FsError::NoSpace)?;
+                // the records belong to nobody
+                let owner = self.owner.take();
+                let block_id = self.alloc_data();
+                self.owner = owner;
+                let block_id = block_id?;
+                get_block_cache(table as usize, Arc::clone(&self.block_device))
+                    .lock()
+                    .modify(0, |record_blocks: &mut RecordBlockList| record_blocks[slot] = block_id);
+                (block_id, 0)
+            }
+        };
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |block: &mut RecordBlock| {
+                block[i] = QuotaRecord {
+                    in_use: 1,
+                    quota: Quota { uid, ..Quota::default() },
+                };
+            });
+        Ok((block_id, i))
+    }
+
+    /// List the quotas of all uids which own something or have limits.
+    pub fn quotas(&self) -> Vec<Quota> {
+        self.quota_records().into_iter().map(|(_, quota)| quota).collect()
+    }
+
+    /// Set the limits of `uid` on blocks and inodes.
+    ///
+    /// The first call starts keeping track of what each uid uses, counted
+    /// from all inodes then. Pending changes are committed first.
+    pub fn set_quota(&mut self, uid: u32, blocks: QuotaLimit, inodes: QuotaLimit) -> Result<()> {
+        self.commit()?;
+        match self.save_quota(uid, blocks, inodes) {
+            Ok(()) => self.commit(),
+            Err(err) => {
+                self.discard_changes();
+                Err(err)
+            }
+        }
+    }
+
+    fn save_quota(&mut self, uid: u32, blocks: QuotaLimit, inodes: QuotaLimit) -> Result<()> {
+        if self.quota_table() == 0 {
+            let table = self.alloc_data()?;
+            get_block_cache(0, Arc::clone(&self.block_device))
+                .lock()
+                .modify(0, |super_block: &mut SuperBlock| {
+                    super_block.quota_table = table;
+                    super_block.features |= FEATURE_QUOTA;
+                });
+            self.recount_quotas()?;
+        }
+        let pos = self.quota_record(uid)?;
+        let now = self.now();
+        self.modify_quota(pos, |quota| {
+            quota.blocks.limit = blocks;
+            quota.inodes.limit = inodes;
+            quota.blocks.settle(now);
+            quota.inodes.settle(now);
+        });
+        Ok(())
+    }
+
+    /// Set whose quota the blocks and inodes allocated or freed from now
+    /// on until the next commit are charged to.
+    pub(crate) fn charge_to(&mut self, uid: u32) {
+        self.owner = Some(uid);
+    }
+
+    /// Return how many more blocks and inodes may be charged to the owner,
+    /// `u32::MAX` for no limit.
+    pub(crate) fn quota_room(&mut self) -> Result<(u32, u32)> {
+        let uid = match self.owner {
+            Some(uid) if self.quota_table() != 0 => uid,
+            _ => return Ok((u32::MAX, u32::MAX)),
+        };
+        let pos = self.quota_record(uid)?;
+        let quota = self.read_quota(pos);
+        let now = self.now();
+        Ok((quota.blocks.room(now), quota.inodes.room(now)))
+    }
+
+    /// Charge `blocks` blocks and `inodes` inodes to the owner, after
+    /// `quota_room` made sure there is room for them.
+    pub(crate) fn charge(&mut self, blocks: u32, inodes: u32) {
+        let now = self.now();
+        self.update_quota(|quota| {
+            quota.blocks.used += blocks;
+            quota.inodes.used += inodes;
+            quota.blocks.settle(now);
+            quota.inodes.settle(now);
+        });
+    }
+
+    /// Give back `blocks` blocks and `inodes` inodes to the owner.
+    pub(crate) fn uncharge(&mut self, blocks: u32, inodes: u32) {
+        let now = self.now();
+        self.update_quota(|quota| {
+            quota.blocks.used = quota.blocks.used.saturating_sub(blocks);
+            quota.inodes.used = quota.inodes.used.saturating_sub(inodes);
+            quota.blocks.settle(now);
+            quota.inodes.settle(now);
+        });
+    }
+
+    fn update_quota(&self, f: impl FnOnce(&mut Quota)) {
+        let uid = match self.owner {
+            Some(uid) if self.quota_table() != 0 => uid,
+            _ => return,
+        };
+        if let Some((pos, _)) = self.find_quota(uid) {
+            self.modify_quota(pos, f);
+        }
+    }
+
+    /// Move the usage of `disk_inode` over to `uid`, which it is given to.
+    /// Fails with `QuotaExceeded` if that is beyond the limits of `uid`.
+    pub(crate) fn transfer_quota(&mut self, disk_inode: &DiskInode, uid: u32) -> Result<()> {
+        let blocks = self.charged_blocks(disk_inode);
+        self.charge_to(uid);
+        let (block_room, inode_room) = self.quota_room()?;
+        if block_room < blocks || inode_room == 0 {
+            return Err(FsError::QuotaExceeded);
+        }
+        self.charge(blocks, 1);
+        self.charge_to(disk_inode.uid);
+        self.uncharge(blocks, 1);
+        Ok(())
+    }
+
+    /// The blocks of `disk_inode` counted against the quota of its owner.
+    fn charged_blocks(&self, disk_inode: &DiskInode) -> u32 {
+        let data_start = self.get_data_block_id(0);
+        let data_end = data_start + self.stat().blocks;
+        let in_data_area = |block_id: u32| block_id >= data_start && block_id < data_end;
+        disk_inode
+            .all_blocks(&self.block_device, in_data_area)
+            .into_iter()
+            .filter(|block_id| in_data_area(*block_id))
+            .count() as u32
+    }
+
+    /// Count the blocks and inodes each uid owns from the inodes in use.
+    pub(crate) fn quota_usage(&self) -> BTreeMap<u32, (u32, u32)> {
+        let mut usage = BTreeMap::new();
+        for inode_id in 0..self.inode_bitmap.maximum() {
+            if !self.inode_bitmap.is_allocated(&self.block_device, inode_id) {
+                continue;
+            }
+            let (block_id, offset) = self.get_disk_inode_pos(inode_id as u32);
+            let (uid, blocks) = get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .read(offset, |disk_inode: &DiskInode| (disk_inode.uid, self.charged_blocks(disk_inode)));
+            let entry = usage.entry(uid).or_insert((0, 0));
+            entry.0 += blocks;
+            entry.1 += 1;
+        }
+        usage
+    }
+
+    /// Set the usage in the records to what the inodes hold, after they
+    /// changed behind the back of the quotas, e.g. by a restored snapshot.
+    pub(crate) fn recount_quotas(&mut self) -> Result<()> {
+        if self.quota_table() == 0 {
+            return Ok(());
+        }
+        let mut usage = self.quota_usage();
+        for (_, quota) in self.quota_records() {
+            usage.entry(quota.uid).or_insert((0, 0));
+        }
+        let now = self.now();
+        for (uid, (blocks, inodes)) in usage {
+            let pos = self.quota_record(uid)?;
+            self.modify_quota(pos, |quota| {
+                quota.blocks.used = blocks;
+                quota.inodes.used = inodes;
+                quota.blocks.settle(now);
+                quota.inodes.settle(now);
+            });
+        }
+        Ok(())
+    }
+}
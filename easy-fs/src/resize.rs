@@ -0,0 +1,169 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use super::{
+    BlockDevice,
+    EasyFileSystem,
+    SuperBlock,
+    DiskInode,
+    FsError,
+    Result,
+    BLOCK_SZ,
+    FEATURE_METADATA_CSUM,
+    get_block_cache,
+    checksum,
+};
+
+type DataBlock = [u8; BLOCK_SZ];
+
+/// Grow the easy-fs image on `block_device` to `total_blocks`, which the
+/// device must already hold. The new blocks are added to the data area.
+///
+/// If the data bitmap needs more blocks, they are taken from the start of the
+/// data area and all data is moved up to make room. The image must not be
+/// mounted meanwhile, and it is damaged if this is interrupted. Shrinking is
+/// not supported and fails with `Invalid`, as does an image with snapshots,
+/// and a damaged image is refused with `Corrupt`.
+pub fn resize(block_device: Arc<dyn BlockDevice>, total_blocks: u32) -> Result<()> {
+    let efs = EasyFileSystem::open(Arc::clone(&block_device))?;
+    let fs = efs.lock();
+    let super_block = get_block_cache(0, Arc::clone(&block_device));
+    let (old_total, fixed_blocks, old_bitmap_blocks, old_area_blocks, old_checksum_blocks, checksums) = super_block
+        .lock()
+        .read(0, |super_block: &SuperBlock| (
+            super_block.total_blocks,
+            1 + super_block.journal_blocks
+                + super_block.inode_bitmap_blocks
+                + super_block.inode_area_blocks,
+            super_block.data_bitmap_blocks,
+            super_block.data_area_blocks,
+            super_block.checksum_blocks,
+            super_block.features & FEATURE_METADATA_CSUM != 0,
+        ));
+    // the saved inodes of a snapshot would have to be moved as well
+    if total_blocks < old_total || !fs.snapshots().is_empty() {
+        return Err(FsError::Invalid);
+    }
+    if total_blocks == old_total {
+        return Ok(());
+    }
+    // same split as in `EasyFileSystem::create`
+    let checksum_blocks = if checksums { checksum::checksum_blocks_for(total_blocks) } else { 0 };
+    let data_total_blocks = total_blocks - checksum_blocks - fixed_blocks;
+    let bitmap_blocks = (data_total_blocks + 4096) / 4097;
+    let area_blocks = data_total_blocks - bitmap_blocks;
+    if area_blocks < old_area_blocks {
+        // the few blocks added do not even make up for the larger table
+        return Err(FsError::Invalid);
+    }
+    let offset = bitmap_blocks - old_bitmap_blocks;
+    let data_start = fs.get_data_block_id(0);
+    // the old table is overwritten by the moved data, so keep a copy
+    let old_checksums = if checksums {
+        let old_start = (old_total - old_checksum_blocks) as usize;
+        let used = |block_id| fs.is_block_used(block_id);
+        if !checksum::damaged_blocks(old_start, &block_device, used).is_empty() {
+            return Err(FsError::Corrupt);
+        }
+        let old_checksums: Vec<u32> = (0..old_start)
+            .map(|block_id| checksum::read_entry(old_start, block_id, &block_device))
+            .collect();
+        // blocks are rewritten below without matching checksums for a while
+        checksum::unregister(&block_device);
+        Some(old_checksums)
+    } else {
+        None
+    };
+    if offset > 0 {
+        // from the top down, so that nothing is overwritten before it moved
+        for bit in (0..old_area_blocks as usize).rev() {
+            if !fs.data_bitmap.is_allocated(&block_device, bit) {
+                continue;
+            }
+            let block_id = data_start as usize + bit;
+            let data = get_block_cache(block_id, Arc::clone(&block_device))
+                .lock()
+                .read(0, |data_block: &DataBlock| *data_block);
+            copy_block(&block_device, block_id + offset as usize, &data);
+        }
+        // these become the new bitmap blocks
+        for block_id in data_start..data_start + offset {
+            copy_block(&block_device, block_id as usize, &[0u8; BLOCK_SZ]);
+        }
+        for inode_id in 0..fs.inode_bitmap.maximum() {
+            if !fs.inode_bitmap.is_allocated(&block_device, inode_id) {
+                continue;
+            }
+            let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id as u32);
+            let block_cache = get_block_cache(block_id as usize, Arc::clone(&block_device));
+            let mut block_cache = block_cache.lock();
+            block_cache.modify(block_offset, |disk_inode: &mut DiskInode| {
+                disk_inode.relocate(offset, &block_device);
+            });
+            block_cache.sync();
+        }
+        // the quota table lists the blocks of the records
+        let quota_table = super_block.lock().read(0, |super_block: &SuperBlock| super_block.quota_table);
+        if quota_table != 0 {
+            let block_cache = get_block_cache((quota_table + offset) as usize, Arc::clone(&block_device));
+            let mut block_cache = block_cache.lock();
+            block_cache.modify(0, |record_blocks: &mut [u32; BLOCK_SZ / 4]| {
+                record_blocks
+                    .iter_mut()
+                    .filter(|block_id| **block_id != 0)
+                    .for_each(|block_id| *block_id += offset);
+            });
+            block_cache.sync();
+        }
+    }
+    {
+        let mut super_block = super_block.lock();
+        super_block.modify(0, |super_block: &mut SuperBlock| {
+            super_block.total_blocks = total_blocks;
+            super_block.data_bitmap_blocks = bitmap_blocks;
+            super_block.data_area_blocks = area_blocks;
+            super_block.checksum_blocks = checksum_blocks;
+            if super_block.snapshot_table != 0 {
+                super_block.snapshot_table += offset;
+            }
+            if super_block.quota_table != 0 {
+                super_block.quota_table += offset;
+            }
+        });
+        super_block.sync();
+    }
+    if let Some(old_checksums) = old_checksums {
+        // the blocks keep being checked if they were, with their new
+        // content at their new place
+        let block_checksum = |block_id: usize| {
+            get_block_cache(block_id, Arc::clone(&block_device))
+                .lock()
+                .read(0, |data_block: &DataBlock| checksum::crc32c(data_block))
+        };
+        let start = (total_blocks - checksum_blocks) as usize;
+        let mut new_checksums = vec![0u32; start];
+        for (block_id, old_checksum) in old_checksums.into_iter().enumerate() {
+            if old_checksum == 0 || !fs.is_block_used(block_id) {
+                continue;
+            }
+            let block_id = if block_id < data_start as usize {
+                block_id
+            } else {
+                block_id + offset as usize
+            };
+            new_checksums[block_id] = block_checksum(block_id);
+        }
+        for block_id in data_start..data_start + offset {
+            new_checksums[block_id as usize] = block_checksum(block_id as usize);
+        }
+        checksum::write_table(start, &new_checksums, &block_device);
+    }
+    Ok(())
+}
+
+fn copy_block(block_device: &Arc<dyn BlockDevice>, block_id: usize, data: &DataBlock) {
+    let block_cache = get_block_cache(block_id, Arc::clone(block_device));
+    let mut block_cache = block_cache.lock();
+    block_cache.modify(0, |data_block: &mut DataBlock| *data_block = *data);
+    block_cache.sync();
+}
@@ -0,0 +1,442 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use super::{
+    EasyFileSystem,
+    SuperBlock,
+    DiskInode,
+    DiskInodeType,
+    FsError,
+    Result,
+    BLOCK_SZ,
+    FEATURE_SNAPSHOTS,
+    INODE_FLAG_EXTENTS,
+    get_block_cache,
+};
+
+/// Longest snapshot name in bytes.
+pub const SNAPSHOT_NAME_LIMIT: usize = 23;
+
+const SNAPSHOTS_PER_BLOCK: usize = BLOCK_SZ / core::mem::size_of::<SnapshotRecord>();
+
+type DataBlock = [u8; BLOCK_SZ];
+
+/// An entry of the snapshot table.
+///
+/// The descriptor block holds an extent-mapped inode whose data is the saved
+/// state: the inode bitmap, the inode area and a bitmap of the data blocks
+/// the inodes mapped, which the snapshot holds from then on. A held block is
+/// never written in place, an inode sharing blocks with a snapshot gets
+/// copies of those it changes first, see `EasyFileSystem::unshare_blocks`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SnapshotRecord {
+    name: [u8; SNAPSHOT_NAME_LIMIT + 1],
+    ctime: u32,
+    /// Zero for a free entry.
+    descriptor: u32,
+}
+
+type SnapshotTable = [SnapshotRecord; SNAPSHOTS_PER_BLOCK];
+
+impl SnapshotRecord {
+    fn name(&self) -> &[u8] {
+        let len = self.name.iter().position(|v| *v == 0).unwrap_or(self.name.len());
+        &self.name[..len]
+    }
+}
+
+/// A snapshot as listed by `EasyFileSystem::snapshots`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub name: String,
+    /// When it was taken.
+    pub ctime: u32,
+}
+
+/// Where the parts of the saved state lie.
+struct Layout {
+    inode_bitmap_start: usize,
+    /// Blocks of the inode bitmap and the inode area, which follows it.
+    inode_blocks: usize,
+    data_bitmap_blocks: usize,
+    data_area_blocks: usize,
+}
+
+impl EasyFileSystem {
+    fn read_super_block<V>(&self, f: impl FnOnce(&SuperBlock) -> V) -> V {
+        get_block_cache(0, Arc::clone(&self.block_device)).lock().read(0, f)
+    }
+
+    fn modify_super_block(&self, f: impl FnOnce(&mut SuperBlock)) {
+        get_block_cache(0, Arc::clone(&self.block_device)).lock().modify(0, f);
+    }
+
+    /// Blocks restoring or deleting a snapshot changes per transaction,
+    /// leaving room for the super block and the checksum table.
+    fn snapshot_step(&self) -> usize {
+        self.transaction_capacity() / 2
+    }
+
+    /// Set `block_id` to `data` in the current transaction, committing it
+    /// every `snapshot_step` blocks. Blocks which hold `data` already are
+    /// not counted.
+    fn update_block(&mut self, block_id: usize, data: &DataBlock, changed: &mut usize) -> Result<()> {
+        let block_cache = get_block_cache(block_id, Arc::clone(&self.block_device));
+        let mut block_cache = block_cache.lock();
+        if block_cache.read(0, |old: &DataBlock| old == data) {
+            return Ok(());
+        }
+        block_cache.modify(0, |old: &mut DataBlock| *old = *data);
+        drop(block_cache);
+        *changed += 1;
+        if *changed % self.snapshot_step() == 0 {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn layout(&self) -> Layout {
+        self.read_super_block(|super_block| Layout {
+            inode_bitmap_start: 1 + super_block.journal_blocks as usize,
+            inode_blocks: (super_block.inode_bitmap_blocks + super_block.inode_area_blocks) as usize,
+            data_bitmap_blocks: super_block.data_bitmap_blocks as usize,
+            data_area_blocks: super_block.data_area_blocks as usize,
+        })
+    }
+
+    /// Return the used entries of the snapshot table with their positions.
+    fn snapshot_records(&self) -> Vec<(usize, SnapshotRecord)> {
+        let table = self.read_super_block(|super_block| super_block.snapshot_table);
+        if table == 0 {
+            return Vec::new();
+        }
+        get_block_cache(table as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |records: &SnapshotTable| {
+                records
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, record)| record.descriptor != 0)
+                    .map(|(pos, record)| (pos, *record))
+                    .collect()
+            })
+    }
+
+    fn find_snapshot(&self, name: &str) -> Result<(usize, SnapshotRecord)> {
+        self.snapshot_records()
+            .into_iter()
+            .find(|(_, record)| record.name() == name.as_bytes())
+            .ok_or(FsError::NotFound)
+    }
+
+    fn saved_inode(&self, descriptor: u32) -> DiskInode {
+        get_block_cache(descriptor as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |saved: &DiskInode| saved.clone())
+    }
+
+    /// List the snapshots in the order of the table.
+    pub fn snapshots(&self) -> Vec<Snapshot> {
+        self.snapshot_records()
+            .into_iter()
+            .map(|(_, record)| Snapshot {
+                name: String::from_utf8_lossy(record.name()).into_owned(),
+                ctime: record.ctime,
+            })
+            .collect()
+    }
+
+    /// Return whether a snapshot holds the data block `block_id`.
+    pub(crate) fn is_held(&self, block_id: u32) -> bool {
+        let records = self.snapshot_records();
+        if records.is_empty() {
+            return false;
+        }
+        let bit = (block_id - self.get_data_block_id(0)) as usize;
+        let offset = self.layout().inode_blocks * BLOCK_SZ + bit / 8;
+        records.iter().any(|(_, record)| {
+            let mut byte = [0u8];
+            self.saved_inode(record.descriptor).read_at(offset, &mut byte, &self.block_device);
+            byte[0] & (1 << (bit % 8)) != 0
+        })
+    }
+
+    /// Data blocks mapped by the inodes in use, as bits of the data area.
+    fn mapped_blocks(&self, layout: &Layout) -> Vec<u64> {
+        let mut words = vec![0u64; layout.data_bitmap_blocks * BLOCK_SZ / 8];
+        let data_start = self.get_data_block_id(0);
+        let data_end = data_start + layout.data_area_blocks as u32;
+        for inode_id in 0..self.inode_bitmap.maximum() {
+            if !self.inode_bitmap.is_allocated(&self.block_device, inode_id) {
+                continue;
+            }
+            let (block_id, block_offset) = self.get_disk_inode_pos(inode_id as u32);
+            let blocks = get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .read(block_offset, |disk_inode: &DiskInode| {
+                    disk_inode.all_blocks(&self.block_device, |block_id| {
+                        block_id >= data_start && block_id < data_end
+                    })
+                });
+            for block_id in blocks.into_iter().filter(|id| *id >= data_start && *id < data_end) {
+                let bit = (block_id - data_start) as usize;
+                words[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        words
+    }
+
+    /// Data blocks taken by the snapshots, both those holding their state
+    /// and those they hold, as bits of the data area.
+    pub(crate) fn snapshot_blocks(&self) -> Vec<u64> {
+        let layout = self.layout();
+        let mut held = vec![0u8; layout.data_bitmap_blocks * BLOCK_SZ];
+        let mut words = vec![0u64; held.len() / 8];
+        let mut blocks = Vec::new();
+        let table = self.read_super_block(|super_block| super_block.snapshot_table);
+        if table != 0 {
+            blocks.push(table);
+        }
+        for (_, record) in self.snapshot_records() {
+            let saved = self.saved_inode(record.descriptor);
+            blocks.push(record.descriptor);
+            blocks.extend(saved.all_blocks(&self.block_device, |_| true));
+            saved.read_at(layout.inode_blocks * BLOCK_SZ, &mut held, &self.block_device);
+            for (word, bytes) in words.iter_mut().zip(held.chunks(8)) {
+                let mut le = [0u8; 8];
+                le.copy_from_slice(bytes);
+                *word |= u64::from_le_bytes(le);
+            }
+        }
+        let data_start = self.get_data_block_id(0);
+        for block_id in blocks {
+            let bit = (block_id - data_start) as usize;
+            words[bit / 64] |= 1 << (bit % 64);
+        }
+        words
+    }
+
+    /// Rewrite the data bitmap from the blocks in use, those mapped by the
+    /// inodes, those taken by the snapshots and the quota records, freeing
+    /// all others. This takes several transactions, which all leave the
+    /// blocks in use allocated.
+    fn rebuild_data_bitmap(&mut self) -> Result<()> {
+        let layout = self.layout();
+        let mut words = self.mapped_blocks(&layout);
+        for (word, taken) in words.iter_mut().zip(self.snapshot_blocks()) {
+            *word |= taken;
+        }
+        let data_start = self.get_data_block_id(0);
+        for block_id in self.quota_blocks() {
+            let bit = (block_id - data_start) as usize;
+            words[bit / 64] |= 1 << (bit % 64);
+        }
+        let data_bitmap_start = layout.inode_bitmap_start + layout.inode_blocks;
+        let mut changed = 0;
+        for (i, chunk) in words.chunks(BLOCK_SZ / 8).enumerate() {
+            let mut data = [0u8; BLOCK_SZ];
+            for (bytes, word) in data.chunks_mut(8).zip(chunk) {
+                bytes.copy_from_slice(&word.to_ne_bytes());
+            }
+            self.update_block(data_bitmap_start + i, &data, &mut changed)?;
+        }
+        self.count_used();
+        Ok(())
+    }
+
+    /// Save the current state of the whole tree as snapshot `name`.
+    ///
+    /// Only the inodes are copied, the data blocks they map are shared until
+    /// they change. Pending changes are committed first.
+    pub fn create_snapshot(&mut self, name: &str) -> Result<()> {
+        if name.is_empty() || name.contains('\0') {
+            return Err(FsError::Invalid);
+        }
+        if name.len() > SNAPSHOT_NAME_LIMIT {
+            return Err(FsError::NameTooLong);
+        }
+        self.commit()?;
+        if self.find_snapshot(name).is_ok() {
+            return Err(FsError::Exists);
+        }
+        match self.save_snapshot(name) {
+            Ok(()) => self.commit(),
+            Err(err) => {
+                self.discard_changes();
+                Err(err)
+            }
+        }
+    }
+
+    fn save_snapshot(&mut self, name: &str) -> Result<()> {
+        let layout = self.layout();
+        let records = self.snapshot_records();
+        let pos = (0..SNAPSHOTS_PER_BLOCK)
+            .find(|pos| records.iter().all(|(used, _)| used != pos))
+            .ok_or(FsError::NoSpace)?;
+        let mut table = self.read_super_block(|super_block| super_block.snapshot_table);
+        if table == 0 {
+            table = self.alloc_data()?;
+        }
+        let held = self.mapped_blocks(&layout);
+        let descriptor = self.alloc_data()?;
+        let now = self.now();
+        let block_device = Arc::clone(&self.block_device);
+        let saved = get_block_cache(descriptor as usize, Arc::clone(&block_device));
+        saved.lock().modify(0, |saved: &mut DiskInode| -> Result<()> {
+            saved.initialize(DiskInodeType::File, now);
+            saved.flags |= INODE_FLAG_EXTENTS;
+            let size = (layout.inode_blocks + layout.data_bitmap_blocks) * BLOCK_SZ;
+            saved.increase_size(size as u32);
+            let mut blocks: Vec<DataBlock> = (0..layout.inode_blocks)
+                .map(|i| {
+                    get_block_cache(layout.inode_bitmap_start + i, Arc::clone(&block_device))
+                        .lock()
+                        .read(0, |data_block: &DataBlock| *data_block)
+                })
+                .collect();
+            for words in held.chunks(BLOCK_SZ / 8) {
+                let mut data = [0u8; BLOCK_SZ];
+                for (bytes, word) in data.chunks_mut(8).zip(words) {
+                    bytes.copy_from_slice(&word.to_le_bytes());
+                }
+                blocks.push(data);
+            }
+            // most of the inode area is usually unused, it stays a hole
+            for (i, data) in blocks.iter().enumerate() {
+                if data.iter().all(|byte| *byte == 0) {
+                    continue;
+                }
+                saved.alloc_blocks(i as u32, i as u32 + 1, self, &block_device)?;
+                saved.write_at(i * BLOCK_SZ, data, &block_device);
+            }
+            // the extent leaves are only referred to once this commits, so
+            // they need no journal
+            for block_id in saved.all_blocks(&block_device, |_| true) {
+                get_block_cache(block_id as usize, Arc::clone(&block_device)).lock().sync();
+            }
+            Ok(())
+        })?;
+        get_block_cache(table as usize, Arc::clone(&block_device))
+            .lock()
+            .modify(0, |records: &mut SnapshotTable| {
+                let record = &mut records[pos];
+                record.name = [0; SNAPSHOT_NAME_LIMIT + 1];
+                record.name[..name.len()].copy_from_slice(name.as_bytes());
+                record.ctime = now;
+                record.descriptor = descriptor;
+            });
+        get_block_cache(0, block_device)
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                super_block.snapshot_table = table;
+                // every inode in use shares its blocks now
+                super_block.snapshot_epoch += 1;
+                super_block.features |= FEATURE_SNAPSHOTS;
+            });
+        Ok(())
+    }
+
+    /// Return the tree to the state saved as snapshot `name`, which is kept.
+    ///
+    /// This takes several transactions. If it is interrupted, the super
+    /// block says so and `EasyFileSystem::open` completes it. No `Inode` of
+    /// this filesystem may be in use meanwhile.
+    pub fn restore_snapshot(&mut self, name: &str) -> Result<()> {
+        self.commit()?;
+        let (_, record) = self.find_snapshot(name)?;
+        self.modify_super_block(|super_block| {
+            super_block.restoring = record.descriptor;
+            super_block.rebuilding = 1;
+        });
+        self.commit()?;
+        self.finish_snapshot_work()
+    }
+
+    /// Drop snapshot `name` and free the blocks only it held.
+    ///
+    /// Freeing them takes several transactions. If it is interrupted, the
+    /// super block says so and `EasyFileSystem::open` completes it.
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<()> {
+        self.commit()?;
+        let (pos, _) = self.find_snapshot(name)?;
+        let table = self.read_super_block(|super_block| super_block.snapshot_table);
+        get_block_cache(table as usize, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |records: &mut SnapshotTable| records[pos].descriptor = 0);
+        self.modify_super_block(|super_block| super_block.rebuilding = 1);
+        self.commit()?;
+        self.finish_snapshot_work()
+    }
+
+    /// Complete a restore or deletion of a snapshot the super block records
+    /// as started. Doing it again from the start after an interruption
+    /// gives the same result.
+    pub(crate) fn finish_snapshot_work(&mut self) -> Result<()> {
+        let (restoring, rebuilding) =
+            self.read_super_block(|super_block| (super_block.restoring, super_block.rebuilding));
+        if restoring != 0 {
+            let layout = self.layout();
+            let saved = self.saved_inode(restoring);
+            let mut data = [0u8; BLOCK_SZ];
+            let mut changed = 0;
+            for i in 0..layout.inode_blocks {
+                saved.read_at(i * BLOCK_SZ, &mut data, &self.block_device);
+                self.update_block(layout.inode_bitmap_start + i, &data, &mut changed)?;
+            }
+            self.modify_super_block(|super_block| super_block.restoring = 0);
+            self.commit()?;
+        }
+        if rebuilding != 0 {
+            // the restored inodes are older than the current epoch, so they
+            // share their blocks with the snapshot right away
+            self.rebuild_data_bitmap()?;
+            // the quotas are not part of the snapshot, what the uids own
+            // changed
+            self.recount_quotas()?;
+            self.modify_super_block(|super_block| super_block.rebuilding = 0);
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Give `disk_inode` copies of the data blocks `first..end` and of the
+    /// blocks mapping them where it shares them with a snapshot, before
+    /// they are changed in place. See `DiskInode::copy_on_write`.
+    pub(crate) fn unshare_blocks(&mut self, disk_inode: &mut DiskInode, first: u32, end: u32) -> Result<()> {
+        self.copy_on_write(disk_inode, first, end, true)
+    }
+
+    /// Like `unshare_blocks` for data blocks which are about to be freed
+    /// rather than changed: only the blocks mapping them are copied.
+    pub(crate) fn unshare_mapping(&mut self, disk_inode: &mut DiskInode, first: u32, end: u32) -> Result<()> {
+        self.copy_on_write(disk_inode, first, end, false)
+    }
+
+    /// `unshare_blocks` for all of `disk_inode`, for inodes changed all
+    /// over like directories.
+    pub(crate) fn unshare(&mut self, disk_inode: &mut DiskInode) -> Result<()> {
+        let end = disk_inode.data_blocks();
+        self.unshare_blocks(disk_inode, 0, end)
+    }
+
+    /// An inode from the current epoch shares nothing. One from before
+    /// joins it once all of it has been unshared.
+    fn copy_on_write(&mut self, disk_inode: &mut DiskInode, first: u32, end: u32, data: bool) -> Result<()> {
+        let epoch = self.read_super_block(|super_block| super_block.snapshot_epoch);
+        if disk_inode.epoch >= epoch {
+            return Ok(());
+        }
+        if !self.snapshot_records().is_empty() {
+            let block_device = Arc::clone(&self.block_device);
+            disk_inode.copy_on_write(first, end, data, self, &block_device)?;
+            if !data || first > 0 || end < disk_inode.data_blocks() {
+                return Ok(());
+            }
+        }
+        disk_inode.epoch = epoch;
+        Ok(())
+    }
+}
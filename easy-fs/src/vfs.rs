@@ -2,19 +2,92 @@ use super::{
     BlockDevice,
     DiskInode,
     DiskInodeType,
-    DirEntry,
+    DirFormat,
     EasyFileSystem,
-    DIRENT_SZ,
+    FsError,
+    Result,
+    Transaction,
+    BLOCK_SZ,
+    INODE_FLAG_EXTENTS,
+    INODE_FLAG_INLINE,
+    INODE_FLAG_COMPRESSED,
+    INODE_FLAG_ENCRYPTED,
+    INLINE_DATA_CAPACITY,
+    SALT_SZ,
     get_block_cache,
+    checksum,
+    compress,
+    dir,
+    xattr,
 };
+use xattr::Xattr;
 use alloc::sync::Arc;
 use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
-use spin::{Mutex, MutexGuard};
+use spin::Mutex;
+
+/// A write is split into transactions of at most this many bytes, so that
+/// none of them changes more metadata than the journal holds.
+const WRITE_CHUNK_SZ: usize = 64 * BLOCK_SZ;
+
+/// A file shrinks in steps of this many bytes, each in a transaction of
+/// its own. The blocks freed in one step touch few enough bitmap, checksum
+/// and quota blocks to fit into the journal. Steps end on the cluster
+/// boundaries of compressed files.
+const FREE_STEP_SZ: u32 = compress::CLUSTER_BLOCKS * BLOCK_SZ as u32;
+
+/// How many symbolic links may be followed while resolving one path.
+const SYMLINK_MAX_DEPTH: usize = 8;
+
+/// Seconds after which a read updates the access time even if the inode
+/// has not changed since it was last read.
+const ATIME_INTERVAL: u32 = 24 * 60 * 60;
+
+/// Metadata of an inode as reported by `Inode::stat`.
+pub struct Stat {
+    pub inode_id: u32,
+    pub type_: DiskInodeType,
+    /// Permission bits.
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+    pub atime: u32,
+    pub mtime: u32,
+    pub ctime: u32,
+}
+
+/// Data blocks holding the bytes `start..end` of `disk_inode`, in whole
+/// clusters for compressed files.
+fn block_range(disk_inode: &DiskInode, start: usize, end: usize) -> (u32, u32) {
+    let unit = if disk_inode.is_compressed() {
+        FREE_STEP_SZ as usize
+    } else {
+        BLOCK_SZ
+    };
+    let first = start / unit * unit / BLOCK_SZ;
+    let last = (end + unit - 1) / unit * unit / BLOCK_SZ;
+    (first as u32, last as u32)
+}
+
+/// Check that `name` can be stored in a directory of `format`.
+fn check_name(name: &str, format: DirFormat) -> Result<()> {
+    if name.is_empty() || name.contains('/') || name.contains('\0') {
+        Err(FsError::Invalid)
+    } else if name.len() > format.name_limit() {
+        Err(FsError::NameTooLong)
+    } else {
+        Ok(())
+    }
+}
 
 pub struct Inode {
+    inode_id: u32,
     block_id: usize,
     block_offset: usize,
+    dir_format: DirFormat,
     fs: Arc<Mutex<EasyFileSystem>>,
     block_device: Arc<dyn BlockDevice>,
 }
@@ -25,15 +98,45 @@ impl Inode {
         fs: Arc<Mutex<EasyFileSystem>>,
         block_device: Arc<dyn BlockDevice>,
     ) -> Self {
-        let (block_id, block_offset) = fs.lock().get_disk_inode_pos(inode_id);
+        let (block_id, block_offset, dir_format) = {
+            let efs = fs.lock();
+            let (block_id, block_offset) = efs.get_disk_inode_pos(inode_id);
+            (block_id, block_offset, efs.dir_format())
+        };
         Self {
+            inode_id,
             block_id: block_id as usize,
             block_offset,
+            dir_format,
             fs,
             block_device,
         }
     }
 
+    /// Lock the filesystem for an operation which changes it.
+    fn begin(&self) -> Transaction<'_> {
+        Transaction::new(self.fs.lock())
+    }
+
+    /// Run `f` as one transaction, failing with `Corrupt` if it read a
+    /// damaged block.
+    fn transact<T>(&self, f: impl FnOnce(&mut Transaction) -> Result<T>) -> Result<T> {
+        let mut fs = self.begin();
+        let result = f(&mut fs);
+        fs.end(result)
+    }
+
+    /// Run the read-only operation `f`, failing with `Corrupt` if it read a
+    /// damaged block, which it saw as zeros.
+    fn checked<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        checksum::take_errors(&self.block_device);
+        let result = f();
+        if checksum::take_errors(&self.block_device) {
+            return Err(FsError::Corrupt);
+        }
+        result
+    }
+
     fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> V {
         get_block_cache(
             self.block_id,
@@ -48,153 +151,960 @@ impl Inode {
         ).lock().modify(self.block_offset, f)
     }
 
-    fn find_inode_id(
+    /// Return the offset and inode number of the entry called `name` in
+    /// this directory.
+    fn lookup_dirent(&self, name: &str) -> Result<(usize, u32)> {
+        self.read_disk_inode(|disk_inode| {
+            if !disk_inode.is_dir() {
+                return Err(FsError::NotDir);
+            }
+            dir::find(disk_inode, self.dir_format, &self.block_device, name.as_bytes())?
+                .ok_or(FsError::NotFound)
+        })
+    }
+
+    /// Fail with `Exists` if this directory has an entry called `name`.
+    fn check_absent(&self, name: &str) -> Result<()> {
+        match self.lookup_dirent(name) {
+            Ok(_) => Err(FsError::Exists),
+            Err(FsError::NotFound) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Result<Arc<Inode>> {
+        let (_, inode_id) = {
+            let _fs = self.fs.lock();
+            self.checked(|| self.lookup_dirent(name))?
+        };
+        // Inode::new locks the filesystem again
+        Ok(Arc::new(Self::new(
+            inode_id,
+            self.fs.clone(),
+            self.block_device.clone(),
+        )))
+    }
+
+    /// Look up `path` component by component, starting from the root
+    /// directory if it is absolute and from this inode otherwise.
+    ///
+    /// Symbolic links are followed, including the last component.
+    pub fn find_path(&self, path: &str) -> Result<Arc<Inode>> {
+        self.resolve_path(path, true, &mut 0)
+    }
+
+    /// Like `find_path`, but a symbolic link as the last component is
+    /// returned itself rather than followed.
+    pub fn find_path_nofollow(&self, path: &str) -> Result<Arc<Inode>> {
+        self.resolve_path(path, false, &mut 0)
+    }
+
+    fn resolve_path(
         &self,
-        name: &str,
-        disk_inode: &DiskInode,
-    ) -> Option<u32> {
-        // assert it is a directory
-        assert!(disk_inode.is_dir());
-        let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-        let mut dirent = DirEntry::empty();
-        for i in 0..file_count {
-            assert_eq!(
-                disk_inode.read_at(
-                    DIRENT_SZ * i,
-                    dirent.as_bytes_mut(),
-                    &self.block_device,
-                ),
-                DIRENT_SZ,
-            );
-            if dirent.name() == name {
-                return Some(dirent.inode_number() as u32);
+        path: &str,
+        follow_last: bool,
+        depth: &mut usize,
+    ) -> Result<Arc<Inode>> {
+        let mut inode = if path.starts_with('/') {
+            Arc::new(EasyFileSystem::root_inode(&self.fs))
+        } else {
+            Arc::new(Self::new(
+                self.inode_id,
+                self.fs.clone(),
+                self.block_device.clone(),
+            ))
+        };
+        let names: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+        for (i, name) in names.iter().enumerate() {
+            let mut next = inode.find(name)?;
+            if next.is_symlink() && (i + 1 < names.len() || follow_last) {
+                *depth += 1;
+                if *depth > SYMLINK_MAX_DEPTH {
+                    return Err(FsError::Loop);
+                }
+                // a relative target starts from the directory holding the link
+                let target = next.readlink()?;
+                next = inode.resolve_path(target.as_str(), true, depth)?;
             }
+            inode = next;
         }
-        None
+        Ok(inode)
     }
 
-    pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
-        let _ = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            self.find_inode_id(name, disk_inode)
-            .map(|inode_id| {
-                Arc::new(Self::new(
-                    inode_id,
-                    self.fs.clone(),
-                    self.block_device.clone(),
-                ))
+    pub fn inode_id(&self) -> u32 {
+        self.inode_id
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.read_disk_inode(|disk_inode| disk_inode.is_dir())
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.read_disk_inode(|disk_inode| disk_inode.is_file())
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.read_disk_inode(|disk_inode| disk_inode.is_symlink())
+    }
+
+    pub fn stat(&self) -> Result<Stat> {
+        let _fs = self.fs.lock();
+        self.checked(|| self.read_disk_inode(|disk_inode| {
+            Ok(Stat {
+                inode_id: self.inode_id,
+                type_: disk_inode.type_()?,
+                mode: disk_inode.mode,
+                nlink: disk_inode.nlink as u32,
+                uid: disk_inode.uid,
+                gid: disk_inode.gid,
+                size: disk_inode.size,
+                atime: disk_inode.atime,
+                mtime: disk_inode.mtime,
+                ctime: disk_inode.ctime,
+            })
+        }))
+    }
+
+    /// Set the permission bits.
+    pub fn chmod(&self, mode: u32) -> Result<()> {
+        self.transact(|fs| {
+            self.modify_disk_inode(|disk_inode| {
+                disk_inode.mode = mode & 0o7777;
+                disk_inode.ctime = fs.now();
+            });
+            Ok(())
+        })
+    }
+
+    /// Give the inode to `uid` and `gid`. What it holds moves over to the
+    /// quota of `uid`, which fails with `QuotaExceeded` if that is full.
+    pub fn chown(&self, uid: u32, gid: u32) -> Result<()> {
+        self.transact(|fs| {
+            self.modify_disk_inode(|disk_inode| {
+                if uid != disk_inode.uid {
+                    fs.transfer_quota(disk_inode, uid)?;
+                }
+                disk_inode.uid = uid;
+                disk_inode.gid = gid;
+                disk_inode.ctime = fs.now();
+                Ok(())
             })
         })
     }
 
-    fn increase_size(
+    /// Return the value of the extended attribute `name`, failing with
+    /// `NoAttribute` if it is not set.
+    pub fn get_xattr(&self, name: &str) -> Result<Vec<u8>> {
+        let _fs = self.fs.lock();
+        self.checked(|| {
+            self.read_xattrs()?
+                .into_iter()
+                .find(|(xattr_name, _)| xattr_name.as_slice() == name.as_bytes())
+                .map(|(_, value)| value)
+                .ok_or(FsError::NoAttribute)
+        })
+    }
+
+    /// Return the names of the extended attributes in the order they were
+    /// first set.
+    pub fn list_xattr(&self) -> Result<Vec<String>> {
+        let _fs = self.fs.lock();
+        self.checked(|| {
+            Ok(self.read_xattrs()?
+                .into_iter()
+                .map(|(name, _)| String::from_utf8_lossy(&name).into_owned())
+                .collect())
+        })
+    }
+
+    /// Set the extended attribute `name` to `value`, replacing the old value
+    /// if there is one. All attributes of an inode share one block, setting
+    /// more than fits fails with `NoSpace`.
+    pub fn set_xattr(&self, name: &str, value: &[u8]) -> Result<()> {
+        xattr::check_name(name)?;
+        self.transact(|fs| {
+            let mut xattrs = self.read_xattrs()?;
+            match xattrs.iter_mut().find(|(xattr_name, _)| xattr_name.as_slice() == name.as_bytes()) {
+                Some((_, old_value)) => *old_value = value.to_vec(),
+                None => xattrs.push((name.as_bytes().to_vec(), value.to_vec())),
+            }
+            self.write_xattrs(&xattrs, fs)
+        })
+    }
+
+    /// Remove the extended attribute `name`, failing with `NoAttribute` if
+    /// it is not set.
+    pub fn remove_xattr(&self, name: &str) -> Result<()> {
+        self.transact(|fs| {
+            let mut xattrs = self.read_xattrs()?;
+            let count = xattrs.len();
+            xattrs.retain(|(xattr_name, _)| xattr_name.as_slice() != name.as_bytes());
+            if xattrs.len() == count {
+                return Err(FsError::NoAttribute);
+            }
+            self.write_xattrs(&xattrs, fs)
+        })
+    }
+
+    fn read_xattrs(&self) -> Result<Vec<Xattr>> {
+        let block_id = self.read_disk_inode(|disk_inode| disk_inode.xattr);
+        if block_id == 0 {
+            return Ok(Vec::new());
+        }
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, xattr::parse)
+    }
+
+    /// Store `xattrs` in the attribute block, which is freed once there are
+    /// none. A block held by a snapshot is not changed but replaced.
+    fn write_xattrs(&self, xattrs: &[Xattr], fs: &mut EasyFileSystem) -> Result<()> {
+        let block = xattr::build(xattrs)?;
+        let now = fs.now();
+        self.modify_disk_inode(|disk_inode| {
+            fs.charge_to(disk_inode.uid);
+            let old_block_id = disk_inode.xattr;
+            if xattrs.is_empty() {
+                disk_inode.xattr = 0;
+            } else {
+                if old_block_id == 0 || fs.is_held(old_block_id) {
+                    disk_inode.xattr = fs.alloc_data()?;
+                }
+                get_block_cache(disk_inode.xattr as usize, Arc::clone(&self.block_device))
+                    .lock()
+                    .modify(0, |data_block: &mut [u8; BLOCK_SZ]| *data_block = block);
+            }
+            if old_block_id != 0 && old_block_id != disk_inode.xattr {
+                fs.dealloc_data(old_block_id);
+            }
+            disk_inode.ctime = now;
+            Ok(())
+        })
+    }
+
+    /// Map the blocks of this inode through extents from now on, which
+    /// keeps a large file in few runs of blocks. It has to be empty.
+    pub fn use_extents(&self) -> Result<()> {
+        self.transact(|_| {
+            self.modify_disk_inode(|disk_inode| {
+                if disk_inode.size != 0 {
+                    return Err(FsError::Invalid);
+                }
+                disk_inode.flags |= INODE_FLAG_EXTENTS;
+                Ok(())
+            })
+        })
+    }
+
+    pub fn uses_extents(&self) -> bool {
+        self.read_disk_inode(|disk_inode| disk_inode.uses_extents())
+    }
+
+    /// Store the data of this regular file compressed from now on, see
+    /// `compress`. It has to be empty, and is never kept inline.
+    pub fn use_compression(&self) -> Result<()> {
+        self.transact(|_| {
+            self.modify_disk_inode(|disk_inode| {
+                if disk_inode.size != 0 || !disk_inode.is_file() || disk_inode.is_encrypted() {
+                    return Err(FsError::Invalid);
+                }
+                disk_inode.flags &= !INODE_FLAG_INLINE;
+                disk_inode.flags |= INODE_FLAG_COMPRESSED;
+                Ok(())
+            })
+        })
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.read_disk_inode(|disk_inode| disk_inode.is_compressed())
+    }
+
+    /// Store the data of this regular file encrypted with the key `key_id`
+    /// from now on, see `crypt`. It has to be empty and not compressed, and
+    /// the key registered with `EasyFileSystem::add_key`.
+    ///
+    /// `salt` has to be random. The file is sealed with a key derived from
+    /// it, as the nonces may repeat on other images sealed with the same key.
+    pub fn use_encryption(&self, key_id: u64, salt: &[u8; SALT_SZ]) -> Result<()> {
+        self.transact(|fs| {
+            if !fs.has_key(key_id) {
+                return Err(FsError::NoKey);
+            }
+            self.modify_disk_inode(|disk_inode| {
+                if disk_inode.size != 0 || !disk_inode.is_file() || disk_inode.is_compressed() {
+                    return Err(FsError::Invalid);
+                }
+                disk_inode.flags &= !INODE_FLAG_INLINE;
+                disk_inode.flags |= INODE_FLAG_ENCRYPTED;
+                disk_inode.set_key_id(key_id);
+                disk_inode.set_salt(salt);
+                Ok(())
+            })
+        })
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.read_disk_inode(|disk_inode| disk_inode.is_encrypted())
+    }
+
+    /// Grow to at least `end` bytes and allocate the blocks holding
+    /// `start..end`. On failure the size stays as it was.
+    fn alloc_range(
+        &self,
+        start: usize,
+        end: usize,
+        disk_inode: &mut DiskInode,
+        fs: &mut EasyFileSystem,
+    ) -> Result<()> {
+        let size = disk_inode.size;
+        if end > disk_inode.max_size() as usize {
+            return Err(FsError::TooLarge);
+        }
+        fs.charge_to(disk_inode.uid);
+        let (first, last) = block_range(disk_inode, start, end);
+        fs.unshare_blocks(disk_inode, first, last)?;
+        if disk_inode.is_inline() {
+            if end <= INLINE_DATA_CAPACITY {
+                disk_inode.increase_size(size.max(end as u32));
+                return Ok(());
+            }
+            disk_inode.uninline(fs, &self.block_device)?;
+        }
+        if end as u32 > size {
+            disk_inode.increase_size(end as u32);
+        }
+        let first = (start / BLOCK_SZ) as u32;
+        let last = ((end + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
+        let result = disk_inode.alloc_blocks(first, last, fs, &self.block_device);
+        if result.is_err() && disk_inode.size > size {
+            // give back what was allocated past the old end
+            self.decrease_size(size, disk_inode, fs);
+        }
+        result
+    }
+
+    /// Shrink to `new_size` and free the blocks past the new end.
+    fn decrease_size(
         &self,
         new_size: u32,
         disk_inode: &mut DiskInode,
-        fs: &mut MutexGuard<EasyFileSystem>,
+        fs: &mut EasyFileSystem,
     ) {
-        if new_size < disk_inode.size {
-            return;
+        let size = disk_inode.size;
+        // stale bytes after the new end in its last block must not
+        // show up again if the file grows later
+        let block_end = (new_size as usize + BLOCK_SZ - 1) / BLOCK_SZ * BLOCK_SZ;
+        let zero_end = block_end.min(size as usize);
+        let last_block = new_size / BLOCK_SZ as u32;
+        if zero_end > new_size as usize && !disk_inode.is_inline()
+            && disk_inode.get_block_id(last_block, &self.block_device) != 0 {
+            let zeros = [0u8; BLOCK_SZ];
+            disk_inode.write_at(
+                new_size as usize,
+                &zeros[..zero_end - new_size as usize],
+                &self.block_device,
+            );
         }
-        let blocks_needed = disk_inode.blocks_num_needed(new_size);
-        let mut v: Vec<u32> = Vec::new();
-        for _ in 0..blocks_needed {
-            v.push(fs.alloc_data());
+        let data_blocks_dealloc = disk_inode.decrease_size(new_size, &self.block_device);
+        for data_block in data_blocks_dealloc.into_iter() {
+            fs.dealloc_data(data_block);
         }
-        disk_inode.increase_size(new_size, v, &self.block_device);
     }
 
-    pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
-        let mut fs = self.fs.lock();
-        if self.modify_disk_inode(|root_inode| {
-            // assert it is a directory
-            assert!(root_inode.is_dir());
-            // has the file been created?
-            self.find_inode_id(name, root_inode)
-        }).is_some() {
-            return None;
+    /// Add an entry to the directory, reusing free space if there is some.
+    fn add_dirent(
+        &self,
+        name: &str,
+        inode_id: u32,
+        disk_inode: &mut DiskInode,
+        fs: &mut EasyFileSystem,
+    ) -> Result<()> {
+        fs.charge_to(disk_inode.uid);
+        fs.unshare(disk_inode)?;
+        dir::insert(
+            disk_inode,
+            self.dir_format,
+            &self.block_device,
+            name,
+            inode_id,
+            &mut |disk_inode, bytes| {
+                let size = disk_inode.size as usize;
+                self.alloc_range(size, size + bytes, disk_inode, fs)?;
+                Ok(size)
+            },
+        )?;
+        disk_inode.mtime = fs.now();
+        disk_inode.ctime = disk_inode.mtime;
+        Ok(())
+    }
+
+    /// Shrink to `new_size`, committing after every `FREE_STEP_SZ` bytes
+    /// freed. An interruption leaves the file at a size in between.
+    fn shrink(&self, new_size: u32, fs: &mut EasyFileSystem) -> Result<()> {
+        loop {
+            let done = self.modify_disk_inode(|disk_inode| {
+                let size = (disk_inode.size.saturating_sub(1) / FREE_STEP_SZ * FREE_STEP_SZ)
+                    .max(new_size);
+                fs.charge_to(disk_inode.uid);
+                // the block or cluster keeping the new end is rewritten,
+                // the blocks after it are only freed
+                let (first, last) = block_range(disk_inode, size as usize, size as usize);
+                fs.unshare_blocks(disk_inode, first, last)?;
+                let (first, last) = block_range(disk_inode, size as usize, disk_inode.size as usize);
+                fs.unshare_mapping(disk_inode, first, last)?;
+                if disk_inode.is_encrypted() {
+                    fs.shrink_encrypted(disk_inode, size)?;
+                } else if size % FREE_STEP_SZ == 0 {
+                    // whole clusters go without being read
+                    self.decrease_size(size, disk_inode, fs);
+                } else if disk_inode.is_compressed() {
+                    compress::resize(disk_inode, size, fs, &self.block_device)?;
+                } else {
+                    self.decrease_size(size, disk_inode, fs);
+                }
+                Ok(size == new_size)
+            })?;
+            if done {
+                return Ok(());
+            }
+            fs.commit()?;
         }
+    }
+
+    /// Create an inode called `name` in this directory holding `data`, all
+    /// in one transaction.
+    fn create_inode(&self, name: &str, type_: DiskInodeType, data: &[u8]) -> Result<Arc<Inode>> {
+        check_name(name, self.dir_format)?;
+        let mut fs = self.begin();
+        // has the file been created?
+        self.check_absent(name)?;
+        // a new inode belongs to the owner of the directory
+        let (uid, gid) = self.read_disk_inode(|root_inode| (root_inode.uid, root_inode.gid));
+        fs.charge_to(uid);
         // create a new file
-        // alloc a inode with an indirect block
-        let new_inode_id = fs.alloc_inode();
+        let new_inode_id = fs.alloc_inode()?;
         // initialize inode
-        let (new_inode_block_id, new_inode_block_offset) 
-            = fs.get_disk_inode_pos(new_inode_id);
-        get_block_cache(
-            new_inode_block_id as usize,
-            Arc::clone(&self.block_device)
-        ).lock().modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
-            new_inode.initialize(DiskInodeType::File);
-        });
-        self.modify_disk_inode(|root_inode| {
-            // append file in the dirent
-            let file_count = (root_inode.size as usize) / DIRENT_SZ;
-            let new_size = (file_count + 1) * DIRENT_SZ;
-            // increase size
-            self.increase_size(new_size as u32, root_inode, &mut fs);
-            // write dirent
-            let dirent = DirEntry::new(name, new_inode_id);
-            root_inode.write_at(
-                file_count * DIRENT_SZ,
-                dirent.as_bytes(),
-                &self.block_device,
-            );
+        let now = fs.now();
+        let new_inode = self.get_inode(new_inode_id, &fs);
+        let result = new_inode.modify_disk_inode(|disk_inode| {
+            disk_inode.initialize(type_, now);
+            disk_inode.uid = uid;
+            disk_inode.gid = gid;
+            // a new inode shares nothing with the snapshots
+            fs.unshare(disk_inode)?;
+            if fs.inline_data() && !disk_inode.is_dir() {
+                disk_inode.flags |= INODE_FLAG_INLINE;
+            }
+            if disk_inode.is_dir() {
+                self.add_dirent(".", new_inode_id, disk_inode, &mut fs)?;
+                self.add_dirent("..", self.inode_id, disk_inode, &mut fs)?;
+            }
+            if !data.is_empty() {
+                self.alloc_range(0, data.len(), disk_inode, &mut fs)?;
+                disk_inode.write_at(0, data, &self.block_device);
+            }
+            Ok(())
         });
-        // release efs lock manually because we will acquire it again in Inode::new
-        drop(fs);
+        let result = result.and_then(|()| self.modify_disk_inode(|root_inode| {
+            self.add_dirent(name, new_inode_id, root_inode, &mut fs)?;
+            if type_ == DiskInodeType::Directory {
+                // ".." of the new directory
+                root_inode.nlink += 1;
+            }
+            Ok(())
+        }));
+        // commit and release efs lock manually because we will acquire it
+        // again in Inode::new
+        fs.end(result)?;
         // return inode
-        Some(Arc::new(Self::new(
+        Ok(Arc::new(Self::new(
             new_inode_id,
             self.fs.clone(),
             self.block_device.clone(),
         )))
     }
 
-    pub fn ls(&self) -> Vec<String> {
-        let _ = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-            let mut v: Vec<String> = Vec::new();
-            for i in 0..file_count {
-                let mut dirent = DirEntry::empty();
-                assert_eq!(
-                    disk_inode.read_at(
-                        i * DIRENT_SZ,
-                        dirent.as_bytes_mut(),
-                        &self.block_device,
-                    ),
-                    DIRENT_SZ,
-                );
-                v.push(String::from(dirent.name()));
+    pub fn create(&self, name: &str) -> Result<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::File, &[])
+    }
+
+    pub fn mkdir(&self, name: &str) -> Result<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::Directory, &[])
+    }
+
+    /// Create a symbolic link `name` pointing to `target`.
+    pub fn symlink(&self, name: &str, target: &str) -> Result<Arc<Inode>> {
+        if target.is_empty() {
+            return Err(FsError::Invalid);
+        }
+        // a link is never seen without its target
+        self.create_inode(name, DiskInodeType::Symlink, target.as_bytes())
+    }
+
+    /// Return the target path if this inode is a symbolic link.
+    pub fn readlink(&self) -> Result<String> {
+        let _fs = self.fs.lock();
+        self.checked(|| self.read_disk_inode(|disk_inode| {
+            if !disk_inode.is_symlink() {
+                return Err(FsError::Invalid);
             }
-            v
+            let mut buf = vec![0u8; disk_inode.size as usize];
+            disk_inode.read_at(0, &mut buf, &self.block_device);
+            String::from_utf8(buf).map_err(|_| FsError::Corrupt)
+        }))
+    }
+
+    /// Add an entry `name` in this directory referring to the file `target`.
+    pub fn link(&self, name: &str, target: &Inode) -> Result<()> {
+        check_name(name, self.dir_format)?;
+        if !Arc::ptr_eq(&self.fs, &target.fs) {
+            return Err(FsError::CrossDevice);
+        }
+        self.transact(|fs| {
+            // hard links to directories are not allowed
+            if target.is_dir() {
+                return Err(FsError::IsDir);
+            }
+            self.check_absent(name)?;
+            self.modify_disk_inode(|root_inode| {
+                self.add_dirent(name, target.inode_id, root_inode, fs)
+            })?;
+            let now = fs.now();
+            target.modify_disk_inode(|disk_inode| {
+                disk_inode.nlink += 1;
+                disk_inode.ctime = now;
+            });
+            Ok(())
         })
     }
 
-    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
-        let _ = self.fs.lock();
+    /// Get another inode of this filesystem while it is already locked.
+    fn get_inode(&self, inode_id: u32, fs: &EasyFileSystem) -> Self {
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        Self {
+            inode_id,
+            block_id: block_id as usize,
+            block_offset,
+            dir_format: self.dir_format,
+            fs: self.fs.clone(),
+            block_device: self.block_device.clone(),
+        }
+    }
+
+    /// Check that an entry naming this inode may be removed by `unlink`
+    /// (`is_dir` unset) or `rmdir` (`is_dir` set).
+    fn check_removable(&self, is_dir: bool) -> Result<()> {
         self.read_disk_inode(|disk_inode| {
-            disk_inode.read_at(offset, buf, &self.block_device)
+            match (disk_inode.is_dir(), is_dir) {
+                (true, false) => Err(FsError::IsDir),
+                (false, true) => Err(FsError::NotDir),
+                (true, true) => {
+                    // only "." and ".." may be left in a directory
+                    let count = dir::count(disk_inode, self.dir_format, &self.block_device)?;
+                    if count > 2 { Err(FsError::NotEmpty) } else { Ok(()) }
+                }
+                _ => Ok(()),
+            }
         })
     }
 
-    pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
-        let mut fs = self.fs.lock();
-        self.modify_disk_inode(|disk_inode| {
-            self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs);
-            disk_inode.write_at(offset, buf, &self.block_device)
-        })
+    /// Drop a link to this inode. The inode and its blocks are freed once
+    /// no entry refers to it any more, which commits what was changed so far.
+    fn drop_link(&self, fs: &mut EasyFileSystem) -> Result<()> {
+        let now = fs.now();
+        let unlinked = self.modify_disk_inode(|disk_inode| {
+            if disk_inode.is_dir() {
+                // an empty directory loses its "." as well
+                disk_inode.nlink = 0;
+            } else {
+                disk_inode.nlink -= 1;
+            }
+            if disk_inode.nlink > 0 {
+                disk_inode.ctime = now;
+                return false;
+            }
+            true
+        });
+        if unlinked {
+            self.release(fs)?;
+        }
+        Ok(())
     }
 
-    pub fn clear(&self) {
-        let mut fs = self.fs.lock();
+    /// Free this inode, which no entry refers to any more, and all it holds.
+    ///
+    /// That takes several transactions for a large file. From the first on
+    /// the inode is the orphan of the super block, whose freeing
+    /// `EasyFileSystem::open` completes after an interruption.
+    pub(crate) fn release(&self, fs: &mut EasyFileSystem) -> Result<()> {
+        fs.set_orphan(self.inode_id);
+        fs.commit()?;
+        self.shrink(0, fs)?;
         self.modify_disk_inode(|disk_inode| {
-            let size = disk_inode.size;
-            let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
-            assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
-            for data_block in data_blocks_dealloc.into_iter() {
-                fs.dealloc_data(data_block);
+            fs.charge_to(disk_inode.uid);
+            if disk_inode.xattr != 0 {
+                fs.dealloc_data(disk_inode.xattr);
+                disk_inode.xattr = 0;
             }
+            fs.free_tags(disk_inode);
+            compress::free_map(disk_inode, fs, &self.block_device);
         });
+        fs.dealloc_inode(self.inode_id);
+        fs.set_orphan(0);
+        Ok(())
     }
-}
+
+    /// Complete `release` of an orphan which was interrupted.
+    pub(crate) fn release_orphan(&self) -> Result<()> {
+        let mut fs = self.begin();
+        let result = self.release(&mut fs);
+        fs.end(result)
+    }
+
+    /// Remove the entry at `offset` of this directory. Its space is left
+    /// for the next `create`.
+    fn clear_dirent(&self, offset: usize, is_dir: bool, fs: &mut EasyFileSystem) -> Result<()> {
+        let now = fs.now();
+        self.modify_disk_inode(|root_inode| {
+            fs.charge_to(root_inode.uid);
+            fs.unshare(root_inode)?;
+            dir::remove(root_inode, self.dir_format, &self.block_device, offset)?;
+            root_inode.mtime = now;
+            root_inode.ctime = now;
+            if is_dir {
+                // ".." of the removed directory
+                root_inode.nlink -= 1;
+            }
+            Ok(())
+        })
+    }
+
+    fn remove(&self, name: &str, is_dir: bool) -> Result<()> {
+        if name == "." || name == ".." {
+            return Err(FsError::Invalid);
+        }
+        self.transact(|fs| {
+            let (offset, inode_id) = self.lookup_dirent(name)?;
+            let inode = self.get_inode(inode_id, fs);
+            inode.check_removable(is_dir)?;
+            self.clear_dirent(offset, is_dir, fs)?;
+            inode.drop_link(fs)
+        })
+    }
+
+    /// Move the entry `old_name` of this directory to `new_name` in `new_dir`.
+    /// An existing `new_name` is replaced if it is of the same kind, and only
+    /// if empty for a directory. With `no_replace` it fails with `Exists`
+    /// instead.
+    pub fn rename(
+        &self,
+        old_name: &str,
+        new_dir: &Inode,
+        new_name: &str,
+        no_replace: bool,
+    ) -> Result<()> {
+        let special = |name: &str| name == "." || name == "..";
+        if special(old_name) || special(new_name) {
+            return Err(FsError::Invalid);
+        }
+        check_name(new_name, self.dir_format)?;
+        if !Arc::ptr_eq(&self.fs, &new_dir.fs) {
+            return Err(FsError::CrossDevice);
+        }
+        self.transact(|fs| {
+            let (_, inode_id) = self.lookup_dirent(old_name)?;
+            let inode = self.get_inode(inode_id, fs);
+            let is_dir = inode.is_dir();
+            let replaced = match new_dir.lookup_dirent(new_name) {
+                Ok(_) if no_replace => return Err(FsError::Exists),
+                Ok(pair) => Some(pair),
+                Err(FsError::NotFound) => None,
+                Err(err) => return Err(err),
+            };
+            if let Some((_, replaced_id)) = replaced {
+                if replaced_id == inode_id {
+                    return Ok(());
+                }
+                self.get_inode(replaced_id, fs).check_removable(is_dir)?;
+            }
+            // the offset of ".." in a directory being moved
+            let mut parent_offset = None;
+            if is_dir {
+                // a directory cannot be moved into itself or its subdirectories
+                let mut ancestor_id = new_dir.inode_id;
+                while ancestor_id != 0 {
+                    if ancestor_id == inode_id {
+                        return Err(FsError::Invalid);
+                    }
+                    let ancestor = self.get_inode(ancestor_id, fs);
+                    ancestor_id = ancestor.lookup_dirent("..").map_err(|_| FsError::Corrupt)?.1;
+                }
+                let (offset, _) = inode.lookup_dirent("..").map_err(|_| FsError::Corrupt)?;
+                parent_offset = Some(offset);
+            }
+            // write the new entry before clearing the old one, so that the inode
+            // is reachable all the time
+            match replaced {
+                Some((offset, _)) => {
+                    // a replaced directory takes its ".." away while ours brings one
+                    let now = fs.now();
+                    new_dir.modify_disk_inode(|root_inode| {
+                        fs.charge_to(root_inode.uid);
+                        fs.unshare(root_inode)?;
+                        dir::set_inode(root_inode, self.dir_format, &self.block_device, offset, inode_id);
+                        root_inode.mtime = now;
+                        root_inode.ctime = now;
+                        Ok(())
+                    })?;
+                }
+                None => {
+                    new_dir.modify_disk_inode(|root_inode| {
+                        new_dir.add_dirent(new_name, inode_id, root_inode, fs)?;
+                        if is_dir {
+                            root_inode.nlink += 1;
+                        }
+                        Ok(())
+                    })?;
+                }
+            }
+            // adding the entry may have moved the old one within the same
+            // directory, e.g. by splitting an indexed leaf
+            let (old_offset, _) = self.lookup_dirent(old_name)?;
+            let now = fs.now();
+            self.clear_dirent(old_offset, is_dir, fs)?;
+            inode.modify_disk_inode(|disk_inode| {
+                disk_inode.ctime = now;
+            });
+            if let Some(offset) = parent_offset.filter(|_| new_dir.inode_id != self.inode_id) {
+                inode.modify_disk_inode(|disk_inode| {
+                    fs.charge_to(disk_inode.uid);
+                    fs.unshare(disk_inode)?;
+                    dir::set_inode(
+                        disk_inode,
+                        self.dir_format,
+                        &self.block_device,
+                        offset,
+                        new_dir.inode_id,
+                    );
+                    Ok(())
+                })?;
+            }
+            // last, as freeing the replaced inode commits
+            match replaced {
+                Some((_, replaced_id)) => self.get_inode(replaced_id, fs).drop_link(fs),
+                None => Ok(()),
+            }
+        })
+    }
+
+    /// Remove the regular file `name`.
+    pub fn unlink(&self, name: &str) -> Result<()> {
+        self.remove(name, false)
+    }
+
+    /// Remove the empty subdirectory `name`.
+    pub fn rmdir(&self, name: &str) -> Result<()> {
+        self.remove(name, true)
+    }
+
+    pub fn ls(&self) -> Result<Vec<String>> {
+        let _fs = self.fs.lock();
+        self.checked(|| self.read_disk_inode(|disk_inode| {
+            if !disk_inode.is_dir() {
+                return Err(FsError::NotDir);
+            }
+            let mut v: Vec<String> = Vec::new();
+            let bad_name = dir::scan(disk_inode, self.dir_format, &self.block_device, |_, _, name| {
+                match core::str::from_utf8(name) {
+                    Ok(name) => {
+                        v.push(String::from(name));
+                        None
+                    }
+                    Err(_) => Some(()),
+                }
+            })?;
+            match bad_name {
+                Some(()) => Err(FsError::Corrupt),
+                None => Ok(v),
+            }
+        }))
+    }
+
+    /// Read into `buf` from `offset`. The access time is only brought up to
+    /// date if the inode changed since the last read or a day has passed,
+    /// like `relatime`, so most reads commit nothing.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let fs = self.fs.lock();
+        let now = fs.now();
+        let (read_size, stale) = self.checked(|| self.read_disk_inode(|disk_inode| {
+            let read_size = if disk_inode.is_compressed() {
+                compress::read_at(disk_inode, offset, buf, &self.block_device)?
+            } else if disk_inode.is_encrypted() {
+                fs.read_encrypted(disk_inode, offset, buf)?
+            } else {
+                disk_inode.read_at(offset, buf, &self.block_device)
+            };
+            let stale = disk_inode.atime <= disk_inode.mtime
+                || disk_inode.atime <= disk_inode.ctime
+                || now.saturating_sub(disk_inode.atime) >= ATIME_INTERVAL;
+            Ok((read_size, stale && disk_inode.atime != now))
+        }))?;
+        if stale {
+            let fs = Transaction::new(fs);
+            self.modify_disk_inode(|disk_inode| {
+                disk_inode.atime = now;
+            });
+            fs.end(Ok(()))?;
+        }
+        Ok(read_size)
+    }
+
+    /// Write `buf` at `offset`, growing the file as needed. If space runs
+    /// out part of the way, the bytes written so far are reported.
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let mut fs = self.begin();
+        let encrypted = self.is_encrypted();
+        let mut write_size = 0usize;
+        for chunk in buf.chunks(WRITE_CHUNK_SZ) {
+            let offset = offset + write_size;
+            // a nonce for every block the chunk touches
+            let result = if encrypted {
+                fs.reserve_nonces((WRITE_CHUNK_SZ / BLOCK_SZ + 1) as u64)
+            } else {
+                Ok(())
+            };
+            let result = result.and_then(|()| self.modify_disk_inode(|disk_inode| {
+                disk_inode.mtime = fs.now();
+                disk_inode.ctime = disk_inode.mtime;
+                if disk_inode.is_compressed() || disk_inode.is_encrypted() {
+                    if offset + chunk.len() > disk_inode.max_size() as usize {
+                        return Err(FsError::TooLarge);
+                    }
+                    fs.charge_to(disk_inode.uid);
+                    let (first, last) = block_range(disk_inode, offset, offset + chunk.len());
+                    fs.unshare_blocks(disk_inode, first, last)?;
+                    if disk_inode.is_encrypted() {
+                        return fs.write_encrypted(disk_inode, offset, chunk);
+                    }
+                    return compress::write_at(disk_inode, offset, chunk, &mut *fs, &self.block_device);
+                }
+                self.alloc_range(offset, offset + chunk.len(), disk_inode, &mut fs)?;
+                Ok(disk_inode.write_at(offset, chunk, &self.block_device))
+            }));
+            let result = match result {
+                Ok(size) => fs.commit().map(|()| size),
+                Err(err) => {
+                    fs.discard_changes();
+                    Err(err)
+                }
+            };
+            match result {
+                Ok(size) => write_size += size,
+                Err(err) if write_size == 0 => return Err(err),
+                Err(_) => break,
+            }
+        }
+        Ok(write_size)
+    }
+
+    /// Set the file size to `new_size`, freeing the blocks past the end if
+    /// it shrinks. Space beyond the old end is a hole, which reads as zeros.
+    pub fn truncate(&self, new_size: usize) -> Result<()> {
+        self.transact(|fs| {
+            if new_size % BLOCK_SZ != 0 && self.is_encrypted() {
+                // for the block keeping the new end
+                fs.reserve_nonces(1)?;
+            }
+            let now = fs.now();
+            let shrinks = self.modify_disk_inode(|disk_inode| {
+                if new_size > disk_inode.max_size() as usize {
+                    return Err(FsError::TooLarge);
+                }
+                disk_inode.mtime = now;
+                disk_inode.ctime = now;
+                if new_size < disk_inode.size as usize {
+                    return Ok(true);
+                }
+                fs.charge_to(disk_inode.uid);
+                if disk_inode.is_compressed() {
+                    // a partial cluster at the old end is rewritten
+                    let size = disk_inode.size as usize;
+                    let (first, last) = block_range(disk_inode, size, size);
+                    fs.unshare_blocks(disk_inode, first, last)?;
+                    let (first, last) = block_range(disk_inode, size, new_size);
+                    fs.unshare_mapping(disk_inode, first, last)?;
+                }
+                if disk_inode.is_compressed() {
+                    compress::resize(disk_inode, new_size as u32, &mut **fs, &self.block_device)?;
+                } else {
+                    if disk_inode.is_inline() && new_size > INLINE_DATA_CAPACITY {
+                        disk_inode.uninline(&mut **fs, &self.block_device)?;
+                    }
+                    disk_inode.increase_size(new_size as u32);
+                }
+                Ok(false)
+            })?;
+            if shrinks {
+                self.shrink(new_size as u32, fs)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Return the first offset from `offset` on which holds data, the
+    /// `SEEK_DATA` of `lseek`. Fails with `OutOfRange` at or past the end or
+    /// if only holes follow.
+    pub fn seek_data(&self, offset: usize) -> Result<usize> {
+        self.seek(offset, true)
+    }
+
+    /// Return the first offset from `offset` on which lies in a hole, the
+    /// `SEEK_HOLE` of `lseek`. The end of the file counts as a hole.
+    pub fn seek_hole(&self, offset: usize) -> Result<usize> {
+        self.seek(offset, false)
+    }
+
+    fn seek(&self, offset: usize, data: bool) -> Result<usize> {
+        let _fs = self.fs.lock();
+        self.checked(|| self.read_disk_inode(|disk_inode| {
+            let size = disk_inode.size as usize;
+            if offset >= size {
+                return Err(FsError::OutOfRange);
+            }
+            let inner_id = (offset / BLOCK_SZ) as u32;
+            // a cluster is stored or left a hole as a whole
+            let found = if disk_inode.is_compressed() {
+                compress::seek_block(disk_inode, inner_id, data, &self.block_device)
+            } else {
+                disk_inode.seek_block(inner_id, data, &self.block_device)
+            };
+            let found = if found == inner_id {
+                offset
+            } else {
+                (found as usize * BLOCK_SZ).min(size)
+            };
+            if data && found >= size {
+                return Err(FsError::OutOfRange);
+            }
+            Ok(found)
+        }))
+    }
+
+    /// Free all data, leaving an empty file.
+    pub fn clear(&self) -> Result<()> {
+        self.transact(|fs| {
+            self.shrink(0, fs)?;
+            let inline_data = fs.inline_data();
+            self.modify_disk_inode(|disk_inode| {
+                if inline_data
+                    && disk_inode.is_file()
+                    && !disk_inode.is_compressed()
+                    && !disk_inode.is_encrypted()
+                {
+                    disk_inode.flags |= INODE_FLAG_INLINE;
+                }
+            });
+            Ok(())
+        })
+    }
+}
\ No newline at end of file
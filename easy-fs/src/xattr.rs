@@ -0,0 +1,69 @@
+use alloc::vec::Vec;
+use super::{
+    FsError,
+    Result,
+    BLOCK_SZ,
+};
+
+/// Longest attribute name in bytes.
+pub const XATTR_NAME_LIMIT: usize = 255;
+
+/// Name length (one byte), a zero byte and the value length (two bytes,
+/// little endian) in front of every pair.
+const ENTRY_HEADER_SZ: usize = 4;
+
+type DataBlock = [u8; BLOCK_SZ];
+
+/// An attribute name and its value.
+pub type Xattr = (Vec<u8>, Vec<u8>);
+
+/// Check that `name` can be stored.
+pub fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains('\0') {
+        return Err(FsError::Invalid);
+    }
+    if name.len() > XATTR_NAME_LIMIT {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
+
+/// Return the pairs of an attribute block in the order they are stored.
+///
+/// The pairs are packed from the start of the block, a zero name length or
+/// the end of the block ends them.
+pub fn parse(block: &DataBlock) -> Result<Vec<Xattr>> {
+    let mut xattrs = Vec::new();
+    let mut pos = 0;
+    while pos + ENTRY_HEADER_SZ <= BLOCK_SZ && block[pos] != 0 {
+        let name_start = pos + ENTRY_HEADER_SZ;
+        let value_start = name_start + block[pos] as usize;
+        let end = value_start + u16::from_le_bytes([block[pos + 2], block[pos + 3]]) as usize;
+        if end > BLOCK_SZ {
+            return Err(FsError::Corrupt);
+        }
+        xattrs.push((block[name_start..value_start].to_vec(), block[value_start..end].to_vec()));
+        pos = end;
+    }
+    Ok(xattrs)
+}
+
+/// Pack `xattrs` into an attribute block, failing with `NoSpace` if they
+/// do not fit.
+pub fn build(xattrs: &[Xattr]) -> Result<DataBlock> {
+    let mut block = [0u8; BLOCK_SZ];
+    let mut pos = 0;
+    for (name, value) in xattrs {
+        let value_start = pos + ENTRY_HEADER_SZ + name.len();
+        let end = value_start + value.len();
+        if end > BLOCK_SZ {
+            return Err(FsError::NoSpace);
+        }
+        block[pos] = name.len() as u8;
+        block[pos + 2..pos + 4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        block[pos + ENTRY_HEADER_SZ..value_start].copy_from_slice(name);
+        block[value_start..end].copy_from_slice(value);
+        pos = end;
+    }
+    Ok(block)
+}
@@ -1,15 +1,22 @@
 use easy_fs::{
     EasyFileSystem,
+    FsStat,
     Inode,
+    DiskInodeType,
+    FsError,
+    KEY_SZ,
+    block_cache_sync_all,
 };
 use crate::drivers::BLOCK_DEVICE;
 use alloc::sync::Arc;
 use lazy_static::*;
 use bitflags::*;
 use alloc::vec::Vec;
+use alloc::string::String;
 use spin::Mutex;
-use super::File;
+use super::{File, Stat, S_IFREG, S_IFDIR, S_IFLNK, SEEK_SET, SEEK_CUR, SEEK_END, SEEK_DATA, SEEK_HOLE};
 use crate::mm::UserBuffer;
+use crate::timer::get_time_ms;
 
 pub struct OSInode {
     readable: bool,
@@ -37,32 +44,35 @@ impl OSInode {
             }),
         }
     }
-    pub fn read_all(&self) -> Vec<u8> {
+    pub fn read_all(&self) -> Result<Vec<u8>, FsError> {
         let mut inner = self.inner.lock();
         let mut buffer = [0u8; 512];
         let mut v: Vec<u8> = Vec::new();
         loop {
-            let len = inner.inode.read_at(inner.offset, &mut buffer);
+            let len = inner.inode.read_at(inner.offset, &mut buffer)?;
             if len == 0 {
                 break;
             }
             inner.offset += len;
             v.extend_from_slice(&buffer[..len]);
         }
-        v
+        Ok(v)
     }
 }
 
 lazy_static! {
-    pub static ref ROOT_INODE: Arc<Inode> = {
-        let efs = EasyFileSystem::open(BLOCK_DEVICE.clone());
-        Arc::new(EasyFileSystem::root_inode(&efs))
+    pub static ref EFS: Arc<Mutex<EasyFileSystem>> = {
+        let efs = EasyFileSystem::open(BLOCK_DEVICE.clone()).unwrap();
+        // there is no RTC, so timestamps count from boot
+        efs.lock().set_clock(|| (get_time_ms() / 1000) as u32);
+        efs
     };
+    pub static ref ROOT_INODE: Arc<Inode> = Arc::new(EasyFileSystem::root_inode(&EFS));
 }
 
 pub fn list_apps() {
     println!("/**** APPS ****");
-    for app in ROOT_INODE.ls() {
+    for app in ROOT_INODE.ls().unwrap() {
         println!("{}", app);
     }
     println!("**************/")
@@ -92,68 +102,246 @@ impl OpenFlags {
     }
 }
 
-pub fn open_file(name: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
+/// Split a path into its parent directory and its last component.
+fn split_path(path: &str) -> (&str, &str) {
+    let path = path.trim_end_matches('/');
+    match path.rfind('/') {
+        Some(idx) => (&path[..idx + 1], &path[idx + 1..]),
+        None => ("", path),
+    }
+}
+
+/// Find the directory `path` will be created in, together with the new name.
+fn find_parent(path: &str) -> Result<(Arc<Inode>, &str), FsError> {
+    let (parent_path, name) = split_path(path);
+    if name.is_empty() {
+        return Err(FsError::Invalid);
+    }
+    let parent = ROOT_INODE.find_path(parent_path)?;
+    if !parent.is_dir() {
+        return Err(FsError::NotDir);
+    }
+    Ok((parent, name))
+}
+
+pub fn open_file(path: &str, flags: OpenFlags) -> Result<Arc<OSInode>, FsError> {
     let (readable, writable) = flags.read_write();
     if flags.contains(OpenFlags::CREATE) {
-        if let Some(inode) = ROOT_INODE.find(name) {
-            // clear size
-            inode.clear();
-            Some(Arc::new(OSInode::new(
-                readable,
-                writable,
-                inode,
-            )))
-        } else {
-            // create file
-            ROOT_INODE.create(name)
-                .map(|inode| {
-                    Arc::new(OSInode::new(
-                        readable,
-                        writable,
-                        inode,
-                    ))
-                })
-        }
-    } else {
-        ROOT_INODE.find(name)
-            .map(|inode| {
-                if flags.contains(OpenFlags::TRUNC) {
-                    inode.clear();
+        match ROOT_INODE.find_path(path) {
+            Ok(inode) => {
+                if inode.is_dir() {
+                    return Err(FsError::IsDir);
                 }
-                Arc::new(OSInode::new(
+                // clear size
+                inode.clear()?;
+                Ok(Arc::new(OSInode::new(
                     readable,
                     writable,
-                    inode
-                ))
-            })
+                    inode,
+                )))
+            }
+            Err(FsError::NotFound) => {
+                // create file
+                let (parent, name) = find_parent(path)?;
+                parent.create(name)
+                    .map(|inode| {
+                        Arc::new(OSInode::new(
+                            readable,
+                            writable,
+                            inode,
+                        ))
+                    })
+            }
+            Err(err) => Err(err),
+        }
+    } else {
+        let inode = ROOT_INODE.find_path(path)?;
+        if writable && inode.is_dir() {
+            return Err(FsError::IsDir);
+        }
+        if flags.contains(OpenFlags::TRUNC) {
+            inode.clear()?;
+        }
+        Ok(Arc::new(OSInode::new(
+            readable,
+            writable,
+            inode
+        )))
     }
 }
 
+pub fn make_dir(path: &str) -> Result<(), FsError> {
+    let (parent, name) = find_parent(path)?;
+    parent.mkdir(name).map(|_| ())
+}
+
+/// Create a symbolic link at `path` pointing to `target`.
+pub fn symlink_file(target: &str, path: &str) -> Result<(), FsError> {
+    let (parent, name) = find_parent(path)?;
+    parent.symlink(name, target).map(|_| ())
+}
+
+pub fn read_link(path: &str) -> Result<String, FsError> {
+    ROOT_INODE.find_path_nofollow(path)?.readlink()
+}
+
+/// Move the file at `old_path` to `new_path`, replacing whatever is there
+/// unless `no_replace` is set.
+pub fn rename_file(old_path: &str, new_path: &str, no_replace: bool) -> Result<(), FsError> {
+    let (old_parent, old_name) = find_parent(old_path)?;
+    let (new_parent, new_name) = find_parent(new_path)?;
+    old_parent.rename(old_name, &new_parent, new_name, no_replace)
+}
+
+/// Remove the file, or the empty directory if `is_dir` is set, at `path`.
+pub fn unlink_file(path: &str, is_dir: bool) -> Result<(), FsError> {
+    let (parent, name) = find_parent(path)?;
+    if is_dir {
+        parent.rmdir(name)
+    } else {
+        parent.unlink(name)
+    }
+}
+
+/// Make `new_path` another name of the file at `old_path`.
+pub fn link_file(old_path: &str, new_path: &str) -> Result<(), FsError> {
+    let inode = ROOT_INODE.find_path_nofollow(old_path)?;
+    let (parent, name) = find_parent(new_path)?;
+    parent.link(name, &inode)
+}
+
+/// Return the value of the extended attribute `name` of the file at `path`.
+pub fn get_xattr(path: &str, name: &str) -> Result<Vec<u8>, FsError> {
+    ROOT_INODE.find_path(path)?.get_xattr(name)
+}
+
+/// Set the extended attribute `name` of the file at `path`. With
+/// `create_only` it must not be set yet, with `replace_only` it must be.
+pub fn set_xattr(
+    path: &str,
+    name: &str,
+    value: &[u8],
+    create_only: bool,
+    replace_only: bool,
+) -> Result<(), FsError> {
+    let inode = ROOT_INODE.find_path(path)?;
+    match inode.get_xattr(name) {
+        Ok(_) if create_only => return Err(FsError::Exists),
+        Err(FsError::NoAttribute) if !replace_only => {}
+        Err(err) => return Err(err),
+        Ok(_) => {}
+    }
+    inode.set_xattr(name, value)
+}
+
+/// Register `key` for encrypted files and return its identifier. The key
+/// is kept until the system is shut down.
+pub fn add_key(key: &[u8; KEY_SZ]) -> u64 {
+    EFS.lock().add_key(key)
+}
+
+/// Report the space and inodes of the filesystem holding `path`.
+pub fn fs_stat(path: &str) -> Result<FsStat, FsError> {
+    // there is only one filesystem, but the path has to exist
+    ROOT_INODE.find_path(path)?;
+    Ok(EFS.lock().stat())
+}
+
+/// Write all cached blocks back to the disk.
+pub fn sync_all() {
+    block_cache_sync_all();
+}
+
 impl File for OSInode {
     fn readable(&self) -> bool { self.readable }
     fn writable(&self) -> bool { self.writable }
-    fn read(&self, mut buf: UserBuffer) -> usize {
+    fn read(&self, mut buf: UserBuffer) -> Result<usize, FsError> {
         let mut inner = self.inner.lock();
         let mut total_read_size = 0usize;
         for slice in buf.buffers.iter_mut() {
-            let read_size = inner.inode.read_at(inner.offset, *slice);
+            let read_size = match inner.inode.read_at(inner.offset, *slice) {
+                Ok(read_size) => read_size,
+                Err(err) if total_read_size == 0 => return Err(err),
+                Err(_) => break,
+            };
             if read_size == 0 {
                 break;
             }
             inner.offset += read_size;
             total_read_size += read_size;
         }
-        total_read_size
+        Ok(total_read_size)
     }
-    fn write(&self, buf: UserBuffer) -> usize {
+    fn write(&self, buf: UserBuffer) -> Result<usize, FsError> {
         let mut inner = self.inner.lock();
         let mut total_write_size = 0usize;
         for slice in buf.buffers.iter() {
-            let write_size = inner.inode.write_at(inner.offset, *slice);
-            assert_eq!(write_size, slice.len());
+            let write_size = match inner.inode.write_at(inner.offset, *slice) {
+                Ok(write_size) => write_size,
+                Err(err) if total_write_size == 0 => return Err(err),
+                // report what was written before the disk filled up
+                Err(_) => break,
+            };
             inner.offset += write_size;
             total_write_size += write_size;
+            if write_size < slice.len() {
+                break;
+            }
+        }
+        Ok(total_write_size)
+    }
+    fn truncate(&self, len: usize) -> Result<(), FsError> {
+        if !self.writable {
+            return Err(FsError::Invalid);
+        }
+        self.inner.lock().inode.truncate(len)
+    }
+    fn stat(&self) -> Result<Stat, FsError> {
+        let stat = self.inner.lock().inode.stat()?;
+        let file_type = match stat.type_ {
+            DiskInodeType::File => S_IFREG,
+            DiskInodeType::Directory => S_IFDIR,
+            DiskInodeType::Symlink => S_IFLNK,
+        };
+        Ok(Stat {
+            ino: stat.inode_id as u64,
+            mode: file_type | stat.mode,
+            nlink: stat.nlink,
+            uid: stat.uid,
+            gid: stat.gid,
+            size: stat.size as u64,
+            atime: stat.atime as u64,
+            mtime: stat.mtime as u64,
+            ctime: stat.ctime as u64,
+        })
+    }
+    fn sync(&self) {
+        // blocks are not cached per file
+        block_cache_sync_all();
+    }
+    fn seek(&self, offset: isize, whence: usize) -> Result<usize, FsError> {
+        let mut inner = self.inner.lock();
+        let new_offset = match whence {
+            SEEK_SET => offset,
+            SEEK_CUR => inner.offset as isize + offset,
+            SEEK_END => inner.inode.stat()?.size as isize + offset,
+            SEEK_DATA | SEEK_HOLE => {
+                if offset < 0 {
+                    return Err(FsError::OutOfRange);
+                }
+                let found = if whence == SEEK_DATA {
+                    inner.inode.seek_data(offset as usize)?
+                } else {
+                    inner.inode.seek_hole(offset as usize)?
+                };
+                found as isize
+            }
+            _ => return Err(FsError::Invalid),
+        };
+        if new_offset < 0 {
+            return Err(FsError::Invalid);
         }
-        total_write_size
+        inner.offset = new_offset as usize;
+        Ok(inner.offset)
     }
 }
\ No newline at end of file
@@ -3,14 +3,85 @@ mod stdio;
 mod inode;
 
 use crate::mm::UserBuffer;
+use easy_fs::FsError;
 
 pub trait File : Send + Sync {
     fn readable(&self) -> bool;
     fn writable(&self) -> bool;
-    fn read(&self, buf: UserBuffer) -> usize;
-    fn write(&self, buf: UserBuffer) -> usize;
+    fn read(&self, buf: UserBuffer) -> Result<usize, FsError>;
+    fn write(&self, buf: UserBuffer) -> Result<usize, FsError>;
+    /// Resize the file to `len` bytes. Only files on disk can be resized.
+    fn truncate(&self, _len: usize) -> Result<(), FsError> { Err(FsError::Invalid) }
+    /// Only files on disk have metadata.
+    fn stat(&self) -> Result<Stat, FsError> { Err(FsError::Invalid) }
+    /// Write cached changes of the file back to the disk.
+    fn sync(&self) {}
+    /// Move the offset as `lseek` does and return the new one. Only files on
+    /// disk have an offset.
+    fn seek(&self, _offset: isize, _whence: usize) -> Result<usize, FsError> { Err(FsError::Invalid) }
+}
+
+/// Map a filesystem error to the negated Linux errno a syscall returns.
+pub fn errno(err: FsError) -> isize {
+    let errno = match err {
+        FsError::NotFound => 2,     // ENOENT
+        FsError::Corrupt => 5,      // EIO
+        FsError::OutOfRange => 6,   // ENXIO
+        FsError::Exists => 17,      // EEXIST
+        FsError::CrossDevice => 18, // EXDEV
+        FsError::NotDir => 20,      // ENOTDIR
+        FsError::IsDir => 21,       // EISDIR
+        FsError::Invalid => 22,     // EINVAL
+        FsError::TooLarge => 27,    // EFBIG
+        FsError::NoSpace => 28,     // ENOSPC
+        FsError::JournalFull => 28, // ENOSPC
+        FsError::NameTooLong => 36, // ENAMETOOLONG
+        FsError::NotEmpty => 39,    // ENOTEMPTY
+        FsError::Loop => 40,        // ELOOP
+        FsError::NoAttribute => 61, // ENODATA
+        FsError::QuotaExceeded => 122, // EDQUOT
+        FsError::NoKey => 126,      // ENOKEY
+    };
+    -errno
+}
+
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFLNK: u32 = 0o120000;
+
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+/// Next offset holding data, holes in sparse files are skipped.
+pub const SEEK_DATA: usize = 3;
+/// Next offset in a hole, the end of the file counts as one.
+pub const SEEK_HOLE: usize = 4;
+
+/// File status handed to user space by `fstat`.
+#[repr(C)]
+pub struct Stat {
+    pub ino: u64,
+    /// File type and permission bits.
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+}
+
+/// Filesystem usage handed to user space by `statfs`.
+#[repr(C)]
+pub struct StatFs {
+    pub bsize: u64,
+    pub blocks: u64,
+    pub bfree: u64,
+    pub files: u64,
+    pub ffree: u64,
 }
 
 pub use pipe::{Pipe, make_pipe};
 pub use stdio::{Stdin, Stdout};
-pub use inode::{OSInode, open_file, OpenFlags, list_apps};
\ No newline at end of file
+pub use inode::{OSInode, open_file, make_dir, link_file, symlink_file, read_link, rename_file, unlink_file, OpenFlags, list_apps, sync_all, fs_stat, get_xattr, set_xattr, add_key};
\ No newline at end of file
@@ -1,4 +1,5 @@
 use super::File;
+use easy_fs::FsError;
 use alloc::sync::{Arc, Weak};
 use spin::Mutex;
 use crate::mm::{
@@ -115,7 +116,7 @@ pub fn make_pipe() -> (Arc<Pipe>, Arc<Pipe>) {
 impl File for Pipe {
     fn readable(&self) -> bool { self.readable }
     fn writable(&self) -> bool { self.writable }
-    fn read(&self, buf: UserBuffer) -> usize {
+    fn read(&self, buf: UserBuffer) -> Result<usize, FsError> {
         assert_eq!(self.readable(), true);
         let mut buf_iter = buf.into_iter();
         let mut read_size = 0usize;
@@ -124,7 +125,7 @@ impl File for Pipe {
             let loop_read = ring_buffer.available_read();
             if loop_read == 0 {
                 if ring_buffer.all_write_ends_closed() {
-                    return read_size;
+                    return Ok(read_size);
                 }
                 drop(ring_buffer);
                 suspend_current_and_run_next();
@@ -136,12 +137,12 @@ impl File for Pipe {
                     unsafe { *byte_ref = ring_buffer.read_byte(); }
                     read_size += 1;
                 } else {
-                    return read_size;
+                    return Ok(read_size);
                 }
             }
         }
     }
-    fn write(&self, buf: UserBuffer) -> usize {
+    fn write(&self, buf: UserBuffer) -> Result<usize, FsError> {
         assert_eq!(self.writable(), true);
         let mut buf_iter = buf.into_iter();
         let mut write_size = 0usize;
@@ -159,7 +160,7 @@ impl File for Pipe {
                     ring_buffer.write_byte(unsafe { *byte_ref });
                     write_size += 1;
                 } else {
-                    return write_size;
+                    return Ok(write_size);
                 }
             }
         }
@@ -1,4 +1,5 @@
 use super::File;
+use easy_fs::FsError;
 use crate::mm::{UserBuffer};
 use crate::sbi::console_getchar;
 use crate::task::suspend_current_and_run_next;
@@ -10,7 +11,7 @@ pub struct Stdout;
 impl File for Stdin {
     fn readable(&self) -> bool { true }
     fn writable(&self) -> bool { false }
-    fn read(&self, mut user_buf: UserBuffer) -> usize {
+    fn read(&self, mut user_buf: UserBuffer) -> Result<usize, FsError> {
         assert_eq!(user_buf.len(), 1);
         // busy loop
         let mut c: usize;
@@ -25,9 +26,9 @@ impl File for Stdin {
         }
         let ch = c as u8;
         unsafe { user_buf.buffers[0].as_mut_ptr().write_volatile(ch); }
-        1
+        Ok(1)
     }
-    fn write(&self, _user_buf: UserBuffer) -> usize {
+    fn write(&self, _user_buf: UserBuffer) -> Result<usize, FsError> {
         panic!("Cannot write to stdin!");
     }
 }
@@ -35,13 +36,13 @@ impl File for Stdin {
 impl File for Stdout {
     fn readable(&self) -> bool { false }
     fn writable(&self) -> bool { true }
-    fn read(&self, _user_buf: UserBuffer) -> usize{
+    fn read(&self, _user_buf: UserBuffer) -> Result<usize, FsError> {
         panic!("Cannot read from stdout!");
     }
-    fn write(&self, user_buf: UserBuffer) -> usize {
+    fn write(&self, user_buf: UserBuffer) -> Result<usize, FsError> {
         for buffer in user_buf.buffers.iter() {
             print!("{}", core::str::from_utf8(*buffer).unwrap());
         }
-        user_buf.len()
+        Ok(user_buf.len())
     }
 }
\ No newline at end of file
@@ -5,8 +5,35 @@ use crate::mm::{
     translated_str,
 };
 use crate::task::{current_user_token, current_task};
-use crate::fs::{make_pipe, OpenFlags, open_file};
+use crate::fs::{
+    Stat,
+    StatFs,
+    make_pipe,
+    OpenFlags,
+    open_file,
+    make_dir,
+    link_file,
+    symlink_file,
+    read_link,
+    rename_file,
+    unlink_file,
+    sync_all,
+    fs_stat,
+    get_xattr,
+    set_xattr,
+    add_key,
+    errno,
+};
+use easy_fs::{FsError, KEY_SZ};
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+const AT_REMOVEDIR: u32 = 0x200;
+const RENAME_NOREPLACE: u32 = 1 << 0;
+const XATTR_CREATE: u32 = 1 << 0;
+const XATTR_REPLACE: u32 = 1 << 1;
+/// Result too large, for a buffer the attribute does not fit in.
+const ERANGE: isize = 34;
 
 pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
     let token = current_user_token();
@@ -22,9 +49,10 @@ pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
         let file = file.clone();
         // release Task lock manually to avoid deadlock
         drop(inner);
-        file.write(
-            UserBuffer::new(translated_byte_buffer(token, buf, len))
-        ) as isize
+        match file.write(UserBuffer::new(translated_byte_buffer(token, buf, len))) {
+            Ok(write_size) => write_size as isize,
+            Err(err) => errno(err),
+        }
     } else {
         -1
     }
@@ -44,9 +72,10 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
         }
         // release Task lock manually to avoid deadlock
         drop(inner);
-        file.read(
-            UserBuffer::new(translated_byte_buffer(token, buf, len))
-        ) as isize
+        match file.read(UserBuffer::new(translated_byte_buffer(token, buf, len))) {
+            Ok(read_size) => read_size as isize,
+            Err(err) => errno(err),
+        }
     } else {
         -1
     }
@@ -56,16 +85,17 @@ pub fn sys_open(path: *const u8, flags: u32) -> isize {
     let task = current_task().unwrap();
     let token = current_user_token();
     let path = translated_str(token, path);
-    if let Some(inode) = open_file(
+    match open_file(
         path.as_str(),
         OpenFlags::from_bits(flags).unwrap()
     ) {
-        let mut inner = task.acquire_inner_lock();
-        let fd = inner.alloc_fd();
-        inner.fd_table[fd] = Some(inode);
-        fd as isize
-    } else {
-        -1
+        Ok(inode) => {
+            let mut inner = task.acquire_inner_lock();
+            let fd = inner.alloc_fd();
+            inner.fd_table[fd] = Some(inode);
+            fd as isize
+        }
+        Err(err) => errno(err),
     }
 }
 
@@ -108,4 +138,224 @@ pub fn sys_dup(fd: usize) -> isize {
     let new_fd = inner.alloc_fd();
     inner.fd_table[new_fd] = Some(Arc::clone(inner.fd_table[fd].as_ref().unwrap()));
     new_fd as isize
+}
+
+pub fn sys_mkdir(path: *const u8) -> isize {
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    make_dir(path.as_str()).map_or_else(errno, |_| 0)
+}
+
+pub fn sys_symlinkat(target: *const u8, path: *const u8) -> isize {
+    let token = current_user_token();
+    let target = translated_str(token, target);
+    let path = translated_str(token, path);
+    symlink_file(target.as_str(), path.as_str()).map_or_else(errno, |_| 0)
+}
+
+pub fn sys_readlinkat(path: *const u8, buf: *mut u8, len: usize) -> isize {
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    match read_link(path.as_str()) {
+        Ok(target) => {
+            let len = len.min(target.len());
+            let user_buf = UserBuffer::new(translated_byte_buffer(token, buf, len));
+            for (byte_ref, byte) in user_buf.into_iter().zip(target.bytes()) {
+                unsafe { *byte_ref = byte; }
+            }
+            len as isize
+        }
+        Err(err) => errno(err),
+    }
+}
+
+pub fn sys_linkat(old_path: *const u8, new_path: *const u8) -> isize {
+    let token = current_user_token();
+    let old_path = translated_str(token, old_path);
+    let new_path = translated_str(token, new_path);
+    link_file(old_path.as_str(), new_path.as_str()).map_or_else(errno, |_| 0)
+}
+
+pub fn sys_unlinkat(path: *const u8, flags: u32) -> isize {
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    unlink_file(path.as_str(), flags & AT_REMOVEDIR != 0).map_or_else(errno, |_| 0)
+}
+
+pub fn sys_renameat2(old_path: *const u8, new_path: *const u8, flags: u32) -> isize {
+    if flags & !RENAME_NOREPLACE != 0 {
+        return errno(FsError::Invalid);
+    }
+    let token = current_user_token();
+    let old_path = translated_str(token, old_path);
+    let new_path = translated_str(token, new_path);
+    rename_file(old_path.as_str(), new_path.as_str(), flags & RENAME_NOREPLACE != 0)
+        .map_or_else(errno, |_| 0)
+}
+
+pub fn sys_ftruncate(fd: usize, len: usize) -> isize {
+    let task = current_task().unwrap();
+    let inner = task.acquire_inner_lock();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(file) = &inner.fd_table[fd] {
+        let file = file.clone();
+        // release Task lock manually to avoid deadlock
+        drop(inner);
+        file.truncate(len).map_or_else(errno, |_| 0)
+    } else {
+        -1
+    }
+}
+
+pub fn sys_lseek(fd: usize, offset: isize, whence: usize) -> isize {
+    let task = current_task().unwrap();
+    let inner = task.acquire_inner_lock();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(file) = &inner.fd_table[fd] {
+        let file = file.clone();
+        // release Task lock manually to avoid deadlock
+        drop(inner);
+        file.seek(offset, whence).map_or_else(errno, |offset| offset as isize)
+    } else {
+        -1
+    }
+}
+
+pub fn sys_statfs(path: *const u8, buf: *mut StatFs) -> isize {
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    match fs_stat(path.as_str()) {
+        Ok(stat) => {
+            let stat = StatFs {
+                bsize: stat.block_size as u64,
+                blocks: stat.blocks as u64,
+                bfree: stat.free_blocks as u64,
+                files: stat.inodes as u64,
+                ffree: stat.free_inodes as u64,
+            };
+            let len = core::mem::size_of::<StatFs>();
+            let src = unsafe {
+                core::slice::from_raw_parts(&stat as *const _ as *const u8, len)
+            };
+            let user_buf = UserBuffer::new(translated_byte_buffer(token, buf as *const u8, len));
+            for (byte_ref, byte) in user_buf.into_iter().zip(src.iter()) {
+                unsafe { *byte_ref = *byte; }
+            }
+            0
+        }
+        Err(err) => errno(err),
+    }
+}
+
+/// Copy the value of the extended attribute `name` of the file at `path`
+/// to `value` and return its length. With `size` zero only the length is
+/// returned.
+pub fn sys_getxattr(path: *const u8, name: *const u8, value: *mut u8, size: usize) -> isize {
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    let name = translated_str(token, name);
+    match get_xattr(path.as_str(), name.as_str()) {
+        Ok(data) if size == 0 => data.len() as isize,
+        Ok(data) if size < data.len() => -ERANGE,
+        Ok(data) => {
+            let user_buf = UserBuffer::new(translated_byte_buffer(token, value, data.len()));
+            for (byte_ref, byte) in user_buf.into_iter().zip(data.iter()) {
+                unsafe { *byte_ref = *byte; }
+            }
+            data.len() as isize
+        }
+        Err(err) => errno(err),
+    }
+}
+
+pub fn sys_setxattr(path: *const u8, name: *const u8, value: *const u8, size: usize, flags: u32) -> isize {
+    if flags & !(XATTR_CREATE | XATTR_REPLACE) != 0 {
+        return errno(FsError::Invalid);
+    }
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    let name = translated_str(token, name);
+    let data: Vec<u8> = UserBuffer::new(translated_byte_buffer(token, value, size))
+        .into_iter()
+        .map(|byte_ref| unsafe { *byte_ref })
+        .collect();
+    set_xattr(
+        path.as_str(),
+        name.as_str(),
+        &data,
+        flags & XATTR_CREATE != 0,
+        flags & XATTR_REPLACE != 0,
+    ).map_or_else(errno, |_| 0)
+}
+
+/// Register the `len` bytes at `key` as the key of encrypted files and
+/// store its identifier at `id`.
+pub fn sys_add_key(key: *const u8, len: usize, id: *mut u64) -> isize {
+    if len != KEY_SZ {
+        return errno(FsError::Invalid);
+    }
+    let token = current_user_token();
+    let mut key_bytes = [0u8; KEY_SZ];
+    let user_buf = UserBuffer::new(translated_byte_buffer(token, key, len));
+    for (byte, byte_ref) in key_bytes.iter_mut().zip(user_buf.into_iter()) {
+        *byte = unsafe { *byte_ref };
+    }
+    *translated_refmut(token, id) = add_key(&key_bytes);
+    0
+}
+
+pub fn sys_sync() -> isize {
+    sync_all();
+    0
+}
+
+pub fn sys_fsync(fd: usize) -> isize {
+    let task = current_task().unwrap();
+    let inner = task.acquire_inner_lock();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(file) = &inner.fd_table[fd] {
+        let file = file.clone();
+        // release Task lock manually to avoid deadlock
+        drop(inner);
+        file.sync();
+        0
+    } else {
+        -1
+    }
+}
+
+pub fn sys_fstat(fd: usize, st: *mut Stat) -> isize {
+    let token = current_user_token();
+    let task = current_task().unwrap();
+    let inner = task.acquire_inner_lock();
+    if fd >= inner.fd_table.len() {
+        return -1;
+    }
+    if let Some(file) = &inner.fd_table[fd] {
+        let file = file.clone();
+        // release Task lock manually to avoid deadlock
+        drop(inner);
+        match file.stat() {
+            Ok(stat) => {
+                let len = core::mem::size_of::<Stat>();
+                let src = unsafe {
+                    core::slice::from_raw_parts(&stat as *const _ as *const u8, len)
+                };
+                let user_buf = UserBuffer::new(translated_byte_buffer(token, st as *const u8, len));
+                for (byte_ref, byte) in user_buf.into_iter().zip(src.iter()) {
+                    unsafe { *byte_ref = *byte; }
+                }
+                0
+            }
+            Err(err) => errno(err),
+        }
+    } else {
+        -1
+    }
 }
\ No newline at end of file
@@ -1,38 +1,75 @@
+const SYSCALL_SETXATTR: usize = 5;
+const SYSCALL_GETXATTR: usize = 8;
 const SYSCALL_DUP: usize = 24;
+const SYSCALL_MKDIR: usize = 34;
+const SYSCALL_UNLINKAT: usize = 35;
+const SYSCALL_SYMLINKAT: usize = 36;
+const SYSCALL_LINKAT: usize = 37;
+const SYSCALL_STATFS: usize = 43;
+const SYSCALL_FTRUNCATE: usize = 46;
 const SYSCALL_OPEN: usize = 56;
 const SYSCALL_CLOSE: usize = 57;
 const SYSCALL_PIPE: usize = 59;
+const SYSCALL_LSEEK: usize = 62;
 const SYSCALL_READ: usize = 63;
 const SYSCALL_WRITE: usize = 64;
+const SYSCALL_READLINKAT: usize = 78;
+const SYSCALL_FSTAT: usize = 80;
+const SYSCALL_SYNC: usize = 81;
+const SYSCALL_FSYNC: usize = 82;
 const SYSCALL_EXIT: usize = 93;
 const SYSCALL_YIELD: usize = 124;
 const SYSCALL_GET_TIME: usize = 169;
 const SYSCALL_GETPID: usize = 172;
+const SYSCALL_ADD_KEY: usize = 217;
 const SYSCALL_FORK: usize = 220;
 const SYSCALL_EXEC: usize = 221;
 const SYSCALL_WAITPID: usize = 260;
+const SYSCALL_RENAMEAT2: usize = 276;
 
 mod fs;
 mod process;
 
 use fs::*;
 use process::*;
+use crate::fs::{Stat, StatFs};
 
-pub fn syscall(syscall_id: usize, args: [usize; 3]) -> isize {
+pub fn syscall(syscall_id: usize, args: [usize; 5]) -> isize {
     match syscall_id {
+        SYSCALL_SETXATTR => sys_setxattr(
+            args[0] as *const u8,
+            args[1] as *const u8,
+            args[2] as *const u8,
+            args[3],
+            args[4] as u32,
+        ),
+        SYSCALL_GETXATTR => sys_getxattr(args[0] as *const u8, args[1] as *const u8, args[2] as *mut u8, args[3]),
         SYSCALL_DUP=> sys_dup(args[0]),
+        SYSCALL_MKDIR => sys_mkdir(args[0] as *const u8),
+        SYSCALL_UNLINKAT => sys_unlinkat(args[0] as *const u8, args[1] as u32),
+        SYSCALL_SYMLINKAT => sys_symlinkat(args[0] as *const u8, args[1] as *const u8),
+        SYSCALL_LINKAT => sys_linkat(args[0] as *const u8, args[1] as *const u8),
+        SYSCALL_STATFS => sys_statfs(args[0] as *const u8, args[1] as *mut StatFs),
+        SYSCALL_FTRUNCATE => sys_ftruncate(args[0], args[1]),
         SYSCALL_OPEN => sys_open(args[0] as *const u8, args[1] as u32),
         SYSCALL_CLOSE => sys_close(args[0]),
         SYSCALL_PIPE => sys_pipe(args[0] as *mut usize),
+        SYSCALL_LSEEK => sys_lseek(args[0], args[1] as isize, args[2]),
         SYSCALL_READ => sys_read(args[0], args[1] as *const u8, args[2]),
         SYSCALL_WRITE => sys_write(args[0], args[1] as *const u8, args[2]),
+        SYSCALL_READLINKAT => sys_readlinkat(args[0] as *const u8, args[1] as *mut u8, args[2]),
+        SYSCALL_FSTAT => sys_fstat(args[0], args[1] as *mut Stat),
+        SYSCALL_SYNC => sys_sync(),
+        SYSCALL_FSYNC => sys_fsync(args[0]),
         SYSCALL_EXIT => sys_exit(args[0] as i32),
         SYSCALL_YIELD => sys_yield(),
         SYSCALL_GET_TIME => sys_get_time(),
         SYSCALL_GETPID => sys_getpid(),
+        SYSCALL_ADD_KEY => sys_add_key(args[0] as *const u8, args[1], args[2] as *mut u64),
         SYSCALL_FORK => sys_fork(),
         SYSCALL_EXEC => sys_exec(args[0] as *const u8, args[1] as *const usize),
         SYSCALL_WAITPID => sys_waitpid(args[0] as isize, args[1] as *mut i32),
+        SYSCALL_RENAMEAT2 => sys_renameat2(args[0] as *const u8, args[1] as *const u8, args[2] as u32),
         _ => panic!("Unsupported syscall_id: {}", syscall_id),
     }
 }
@@ -14,6 +14,7 @@ use crate::mm::{
 use crate::fs::{
     open_file,
     OpenFlags,
+    errno,
 };
 use alloc::sync::Arc;
 use alloc::vec::Vec;
@@ -63,15 +64,19 @@ pub fn sys_exec(path: *const u8, mut args: *const usize) -> isize {
         args_vec.push(translated_str(token, arg_str_ptr as *const u8));
         unsafe { args = args.add(1); }
     }
-    if let Some(app_inode) = open_file(path.as_str(), OpenFlags::RDONLY) {
-        let all_data = app_inode.read_all();
-        let task = current_task().unwrap();
-        let argc = args_vec.len();
-        task.exec(all_data.as_slice(), args_vec);
-        // return argc because cx.x[10] will be covered with it later
-        argc as isize
-    } else {
-        -1
+    match open_file(path.as_str(), OpenFlags::RDONLY) {
+        Ok(app_inode) => {
+            let all_data = match app_inode.read_all() {
+                Ok(all_data) => all_data,
+                Err(err) => return errno(err),
+            };
+            let task = current_task().unwrap();
+            let argc = args_vec.len();
+            task.exec(all_data.as_slice(), args_vec);
+            // return argc because cx.x[10] will be covered with it later
+            argc as isize
+        }
+        Err(err) => errno(err),
     }
 }
 
@@ -78,7 +78,7 @@ pub fn exit_current_and_run_next(exit_code: i32) {
 lazy_static! {
     pub static ref INITPROC: Arc<TaskControlBlock> = Arc::new({
         let inode = open_file("initproc", OpenFlags::RDONLY).unwrap();
-        let v = inode.read_all();
+        let v = inode.read_all().unwrap();
         TaskControlBlock::new(v.as_slice())
     });
 }
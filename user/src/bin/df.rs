@@ -0,0 +1,38 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    statfs,
+    StatFs,
+};
+
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    let path = if argc > 1 { argv[1] } else { "/\0" };
+    let mut st = StatFs::default();
+    if statfs(path, &mut st) < 0 {
+        println!("df: cannot stat {}", path.trim_end_matches('\0'));
+        return -1;
+    }
+    let kib = |blocks: u64| blocks * st.bsize / 1024;
+    let used = st.blocks - st.bfree;
+    // rounded up like df does
+    let percent = |used: u64, total: u64| {
+        if total == 0 { 0 } else { (used * 100 + total - 1) / total }
+    };
+    println!("{:>10} {:>10} {:>10} {:>5}", "1K-blocks", "Used", "Available", "Use%");
+    println!(
+        "{:>10} {:>10} {:>10} {:>4}%",
+        kib(st.blocks), kib(used), kib(st.bfree), percent(used, st.blocks),
+    );
+    let iused = st.files - st.ffree;
+    println!("{:>10} {:>10} {:>10} {:>5}", "Inodes", "IUsed", "IFree", "IUse%");
+    println!(
+        "{:>10} {:>10} {:>10} {:>4}%",
+        st.files, iused, st.ffree, percent(iused, st.files),
+    );
+    0
+}
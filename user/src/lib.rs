@@ -69,9 +69,68 @@ bitflags! {
     }
 }
 
+const AT_REMOVEDIR: u32 = 0x200;
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFLNK: u32 = 0o120000;
+
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+pub const SEEK_DATA: usize = 3;
+pub const SEEK_HOLE: usize = 4;
+
+/// `setxattr` fails if the attribute is set already.
+pub const XATTR_CREATE: u32 = 1 << 0;
+/// `setxattr` fails unless the attribute is set already.
+pub const XATTR_REPLACE: u32 = 1 << 1;
+
+#[repr(C)]
+#[derive(Default)]
+pub struct Stat {
+    pub ino: u64,
+    /// File type and permission bits.
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+pub struct StatFs {
+    pub bsize: u64,
+    pub blocks: u64,
+    pub bfree: u64,
+    pub files: u64,
+    pub ffree: u64,
+}
+
 pub fn dup(fd: usize) -> isize { sys_dup(fd) }
+pub fn mkdir(path: &str) -> isize { sys_mkdir(path) }
+pub fn symlink(target: &str, path: &str) -> isize { sys_symlinkat(target, path) }
+pub fn readlink(path: &str, buf: &mut [u8]) -> isize { sys_readlinkat(path, buf) }
+pub fn link(old_path: &str, new_path: &str) -> isize { sys_linkat(old_path, new_path) }
+pub fn unlink(path: &str) -> isize { sys_unlinkat(path, 0) }
+pub fn rmdir(path: &str) -> isize { sys_unlinkat(path, AT_REMOVEDIR) }
+pub fn rename(old_path: &str, new_path: &str) -> isize { sys_renameat2(old_path, new_path, 0) }
 pub fn open(path: &str, flags: OpenFlags) -> isize { sys_open(path, flags.bits) }
 pub fn close(fd: usize) -> isize { sys_close(fd) }
+pub fn ftruncate(fd: usize, len: usize) -> isize { sys_ftruncate(fd, len) }
+pub fn fstat(fd: usize, st: &mut Stat) -> isize { sys_fstat(fd, st) }
+pub fn lseek(fd: usize, offset: isize, whence: usize) -> isize { sys_lseek(fd, offset, whence) }
+pub fn statfs(path: &str, buf: &mut StatFs) -> isize { sys_statfs(path, buf) }
+pub fn getxattr(path: &str, name: &str, value: &mut [u8]) -> isize { sys_getxattr(path, name, value) }
+pub fn setxattr(path: &str, name: &str, value: &[u8], flags: u32) -> isize { sys_setxattr(path, name, value, flags) }
+pub fn add_key(key: &[u8], id: &mut u64) -> isize { sys_add_key(key, id) }
+pub fn sync() -> isize { sys_sync() }
+pub fn fsync(fd: usize) -> isize { sys_fsync(fd) }
 pub fn pipe(pipe_fd: &mut [usize]) -> isize { sys_pipe(pipe_fd) }
 pub fn read(fd: usize, buf: &mut [u8]) -> isize { sys_read(fd, buf) }
 pub fn write(fd: usize, buf: &[u8]) -> isize { sys_write(fd, buf) }
@@ -1,16 +1,33 @@
+use super::{Stat, StatFs};
+
+const SYSCALL_SETXATTR: usize = 5;
+const SYSCALL_GETXATTR: usize = 8;
 const SYSCALL_DUP: usize = 24;
+const SYSCALL_MKDIR: usize = 34;
+const SYSCALL_UNLINKAT: usize = 35;
+const SYSCALL_SYMLINKAT: usize = 36;
+const SYSCALL_LINKAT: usize = 37;
+const SYSCALL_STATFS: usize = 43;
+const SYSCALL_FTRUNCATE: usize = 46;
 const SYSCALL_OPEN: usize = 56;
 const SYSCALL_CLOSE: usize = 57;
 const SYSCALL_PIPE: usize = 59;
+const SYSCALL_LSEEK: usize = 62;
 const SYSCALL_READ: usize = 63;
 const SYSCALL_WRITE: usize = 64;
+const SYSCALL_READLINKAT: usize = 78;
+const SYSCALL_FSTAT: usize = 80;
+const SYSCALL_SYNC: usize = 81;
+const SYSCALL_FSYNC: usize = 82;
 const SYSCALL_EXIT: usize = 93;
 const SYSCALL_YIELD: usize = 124;
 const SYSCALL_GET_TIME: usize = 169;
 const SYSCALL_GETPID: usize = 172;
+const SYSCALL_ADD_KEY: usize = 217;
 const SYSCALL_FORK: usize = 220;
 const SYSCALL_EXEC: usize = 221;
 const SYSCALL_WAITPID: usize = 260;
+const SYSCALL_RENAMEAT2: usize = 276;
 
 fn syscall(id: usize, args: [usize; 3]) -> isize {
     let mut ret: isize;
@@ -25,10 +42,76 @@ fn syscall(id: usize, args: [usize; 3]) -> isize {
     ret
 }
 
+/// A system call with more than three arguments.
+fn syscall5(id: usize, args: [usize; 5]) -> isize {
+    let mut ret: isize;
+    unsafe {
+        llvm_asm!("ecall"
+            : "={x10}" (ret)
+            : "{x10}" (args[0]), "{x11}" (args[1]), "{x12}" (args[2]), "{x13}" (args[3]), "{x14}" (args[4]), "{x17}" (id)
+            : "memory"
+            : "volatile"
+        );
+    }
+    ret
+}
+
+pub fn sys_setxattr(path: &str, name: &str, value: &[u8], flags: u32) -> isize {
+    syscall5(SYSCALL_SETXATTR, [
+        path.as_ptr() as usize,
+        name.as_ptr() as usize,
+        value.as_ptr() as usize,
+        value.len(),
+        flags as usize,
+    ])
+}
+
+pub fn sys_getxattr(path: &str, name: &str, value: &mut [u8]) -> isize {
+    syscall5(SYSCALL_GETXATTR, [
+        path.as_ptr() as usize,
+        name.as_ptr() as usize,
+        value.as_mut_ptr() as usize,
+        value.len(),
+        0,
+    ])
+}
+
+pub fn sys_add_key(key: &[u8], id: &mut u64) -> isize {
+    syscall(SYSCALL_ADD_KEY, [key.as_ptr() as usize, key.len(), id as *mut u64 as usize])
+}
+
 pub fn sys_dup(fd: usize) -> isize {
     syscall(SYSCALL_DUP, [fd, 0, 0])
 }
 
+pub fn sys_mkdir(path: &str) -> isize {
+    syscall(SYSCALL_MKDIR, [path.as_ptr() as usize, 0, 0])
+}
+
+pub fn sys_unlinkat(path: &str, flags: u32) -> isize {
+    syscall(SYSCALL_UNLINKAT, [path.as_ptr() as usize, flags as usize, 0])
+}
+
+pub fn sys_symlinkat(target: &str, path: &str) -> isize {
+    syscall(SYSCALL_SYMLINKAT, [target.as_ptr() as usize, path.as_ptr() as usize, 0])
+}
+
+pub fn sys_linkat(old_path: &str, new_path: &str) -> isize {
+    syscall(SYSCALL_LINKAT, [old_path.as_ptr() as usize, new_path.as_ptr() as usize, 0])
+}
+
+pub fn sys_statfs(path: &str, buf: &mut StatFs) -> isize {
+    syscall(SYSCALL_STATFS, [path.as_ptr() as usize, buf as *mut _ as usize, 0])
+}
+
+pub fn sys_ftruncate(fd: usize, len: usize) -> isize {
+    syscall(SYSCALL_FTRUNCATE, [fd, len, 0])
+}
+
+pub fn sys_lseek(fd: usize, offset: isize, whence: usize) -> isize {
+    syscall(SYSCALL_LSEEK, [fd, offset as usize, whence])
+}
+
 pub fn sys_open(path: &str, flags: u32) -> isize {
     syscall(SYSCALL_OPEN, [path.as_ptr() as usize, flags as usize, 0])
 }
@@ -49,6 +132,22 @@ pub fn sys_write(fd: usize, buffer: &[u8]) -> isize {
     syscall(SYSCALL_WRITE, [fd, buffer.as_ptr() as usize, buffer.len()])
 }
 
+pub fn sys_readlinkat(path: &str, buffer: &mut [u8]) -> isize {
+    syscall(SYSCALL_READLINKAT, [path.as_ptr() as usize, buffer.as_mut_ptr() as usize, buffer.len()])
+}
+
+pub fn sys_fstat(fd: usize, st: &mut Stat) -> isize {
+    syscall(SYSCALL_FSTAT, [fd, st as *mut _ as usize, 0])
+}
+
+pub fn sys_sync() -> isize {
+    syscall(SYSCALL_SYNC, [0, 0, 0])
+}
+
+pub fn sys_fsync(fd: usize) -> isize {
+    syscall(SYSCALL_FSYNC, [fd, 0, 0])
+}
+
 pub fn sys_exit(exit_code: i32) -> ! {
     syscall(SYSCALL_EXIT, [exit_code as usize, 0, 0]);
     panic!("sys_exit never returns!");
@@ -76,4 +175,8 @@ pub fn sys_exec(path: &str, args: &[*const u8]) -> isize {
 
 pub fn sys_waitpid(pid: isize, exit_code: *mut i32) -> isize {
     syscall(SYSCALL_WAITPID, [pid as usize, exit_code as usize, 0])
+}
+
+pub fn sys_renameat2(old_path: &str, new_path: &str, flags: u32) -> isize {
+    syscall(SYSCALL_RENAMEAT2, [old_path.as_ptr() as usize, new_path.as_ptr() as usize, flags as usize])
 }
\ No newline at end of file